@@ -1,137 +1,534 @@
 use Register;
 use bytecode::{Instruction, Opcode};
-use tokenizer::Token;
+use error::{AssembleError, Span};
+use preprocess::preprocess;
+use tokenizer::{Directive, Pseudo, Token, Tokenizer};
+use vm::HEAP_SIZE;
 
 use std::collections::HashMap;
+use std::iter::Peekable;
+use std::path::Path;
 use std::slice::Iter;
 
-pub fn assemble(tokens: Vec<Token>) -> (Vec<Opcode>, Vec<usize>) {
+/// The label that execution starts at, if it is defined.
+pub const ENTRY_LABEL: &str = "_start";
+
+#[derive(Debug, Default)]
+pub struct Program {
+    pub code: Vec<Opcode>,
+    /// The instruction index where execution starts.
+    pub entry: usize,
+    pub line_map: Vec<usize>,
+    /// The instruction index of each label in the text section.
+    pub labels: HashMap<String, usize>,
+    /// The initial contents of memory starting at address 0.
+    pub data: Vec<u8>,
+    /// The address of each label in the data section.
+    pub data_labels: HashMap<String, u64>,
+    /// The labels exported with `.global`.
+    pub globals: Vec<String>,
+    /// The places that refer to the address of a label, which must be updated when the program
+    /// is linked with others.
+    pub relocations: Vec<Relocation>,
+    /// The labels declared with `.extern`, which can only be resolved by linking.
+    pub externs: Vec<(String, Span)>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Relocation {
+    /// The instruction index for `Branch` and `Imm` or the data offset for `Data`.
+    pub offset: usize,
+    pub fixup: Fixup,
+    pub symbol: String,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Section {
+    Text,
+    Data,
+}
+
+/// How a reference to a label gets patched once its value is known.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Fixup {
+    /// The offset field of a branch instruction.
+    Branch,
+    /// The immediate field of an I-format instruction.
+    Imm,
+    /// A value of the given size in bytes in the data section.
+    Data(usize),
+}
+
+/// The labels defined so far and the references that still need to be resolved.
+struct Symbols<'a> {
+    labels: HashMap<&'a String, usize>,
+    data_labels: HashMap<&'a String, u64>,
+    externs: HashMap<&'a String, Span>,
+    references: Vec<(String, usize, Span, Fixup)>,
+}
+
+impl<'a> Symbols<'a> {
+    fn contains(&self, label: &String) -> bool {
+        self.labels.contains_key(label) || self.data_labels.contains_key(label)
+    }
+
+    /// Returns the value of `label` when used as an immediate: the instruction index of a code
+    /// label or the address of a data label.
+    fn value(&self, label: &String) -> Option<u64> {
+        self.labels.get(label).map(|&i| i as u64).or_else(|| self.data_labels.get(label).cloned())
+    }
+}
+
+/// Assembles `tokens` into machine code, the line map, the label addresses and the initial
+/// contents of the data section. Every error in the program is reported rather than stopping at
+/// the first one.
+pub fn assemble(tokens: Vec<Token>) -> Result<Program, Vec<AssembleError>> {
     let mut code = Vec::new();
     let mut line_map = Vec::new();
-    let mut labels = HashMap::new();
-    let mut jumps = Vec::new();
+    let mut data = Vec::new();
+    let mut symbols = Symbols {
+        labels: HashMap::new(),
+        data_labels: HashMap::new(),
+        externs: HashMap::new(),
+        references: Vec::new(),
+    };
+    let mut globals = Vec::new();
+    let mut errors = Vec::new();
+    let mut section = Section::Text;
 
-    let mut i =0;
+    let mut i = 0;
     let mut line_number = 1;
-    let mut tokens = tokens.iter();
+    let mut tokens = tokens.iter().peekable();
     while let Some(t) = tokens.next() {
         match t {
-            Token::Label(line, s) => {
-                handle_line_map(&mut line_map, i, &mut line_number, *line);
-                if labels.contains_key(s) {
-                    panic!("label {} define more than once on line {}", s, line);
+            Token::Label(span, s) => {
+                if symbols.contains(s) {
+                    errors.push(AssembleError::new(*span, format!("Label `{}` defined more than once", s)));
+                } else if section == Section::Data {
+                    symbols.data_labels.insert(s, data.len() as u64);
+                } else {
+                    handle_line_map(&mut line_map, i, &mut line_number, *span);
+                    symbols.labels.insert(s, i);
+                }
+            }
+            Token::Directive(span, Directive::Include) => {
+                // The tokens of the included file follow.
+                handle_line_map(&mut line_map, i, &mut line_number, *span);
+                skip_line(&mut tokens, *span);
+            }
+            Token::Directive(_, Directive::Text) => section = Section::Text,
+            Token::Directive(_, Directive::Data) => section = Section::Data,
+            Token::Directive(span, d @ Directive::Global) | Token::Directive(span, d @ Directive::Extern) => {
+                let mut ops = Operands { tokens: &mut tokens, last: *span };
+                loop {
+                    match ops.read_label() {
+                        Ok((l, span)) if *d == Directive::Global => globals.push((l, span)),
+                        Ok((l, span)) => {
+                            symbols.externs.insert(l, span);
+                        }
+                        Err(e) => {
+                            errors.push(e);
+                            break;
+                        }
+                    }
+                    if !matches!(ops.peek(), Some(Token::Comma(_))) {
+                        break;
+                    }
+                    ops.next();
+                }
+                skip_line(&mut tokens, *span);
+            }
+            Token::Directive(span, Directive::Word) if section == Section::Text => {
+                // Raw instruction words, for encodings the assembler has no syntax for.
+                let mut ops = Operands { tokens: &mut tokens, last: *span };
+                loop {
+                    match ops.read_imm_in(i32::MIN as i64, u32::MAX as i64, "Word") {
+                        Ok(v) => {
+                            code.push(Opcode(v as u32));
+                            handle_line_map(&mut line_map, i, &mut line_number, *span);
+                            i += 1;
+                        }
+                        Err(e) => {
+                            errors.push(e);
+                            skip_line(&mut tokens, *span);
+                            break;
+                        }
+                    }
+                    if !matches!(ops.peek(), Some(Token::Comma(_))) {
+                        break;
+                    }
+                    ops.next();
+                }
+            }
+            Token::Directive(span, d) => {
+                let mut ops = Operands { tokens: &mut tokens, last: *span };
+                let r = if section == Section::Data {
+                    handle_directive(*d, &mut ops, &mut symbols, &mut data)
+                } else {
+                    Err(AssembleError::new(*span, "Data directives must be in the .data section"))
+                };
+                if let Err(e) = r {
+                    errors.push(e);
+                    skip_line(&mut tokens, *span);
                 }
-                labels.insert(s, i);
             }
-            Token::Instruction(line, instr) => {
+            Token::Instruction(span, _) | Token::Pseudo(span, _) if section == Section::Data => {
+                errors.push(AssembleError::new(*span, "Instructions must be in the .text section"));
+                skip_line(&mut tokens, *span);
+            }
+            Token::Instruction(span, instr) => {
                 use bytecode::Instruction::*;
-                code.push(match instr {
-                    Prnt => if let Some(Token::Register(_, r)) = tokens.next() {
-                        Opcode::Prnt(*r)
-                    } else {
-                        panic!("Expected register on line {}", line);
-                    },
-                    Prnl => Opcode::Prnl(),
-                    Dump => Opcode::Dump(),
-                    Halt => Opcode::Halt(),
-                    Stur | Ldur => handle_d(*instr, &mut tokens, *line),
-                    Cbz | Cbnz => handle_cb(*instr, &mut tokens, &labels, &mut jumps, i, *line),
+                let mut ops = Operands { tokens: &mut tokens, last: *span };
+                let op = match instr {
+                    Prnt => ops.read_register(false).map(Opcode::Prnt),
+                    Prnl => Ok(Opcode::Prnl()),
+                    Dump => Ok(Opcode::Dump()),
+                    Halt => Ok(Opcode::Halt()),
+                    Stur | Ldur => handle_d(*instr, &mut ops),
+                    Cbz | Cbnz => handle_cb(*instr, &mut ops, &mut symbols, i),
                     // NOTE B.cond instructions are encoded differently but they are written the
                     // same as B-form instructions.
                     B | Bl | Beq | Bne | Bhs | Blo | Bmi | Bpl | Bvs | Bvc | Bhi |
-                    Bls | Bgt | Bge | Blt | Ble => handle_b(*instr, &mut tokens, &labels, &mut jumps, i, *line),
-                    Addi | Addis | Andi | Andis | Eori | Orri | Subi | Subis => handle_i(*instr, &mut tokens, *line),
-                    Add | Adds | And | Ands | Eor | Orr | Sub | Subs | Mul | Sdiv => handle_r(*instr, &mut tokens, *line),
-                    Lsl | Lsr => handle_shift(*instr, &mut tokens, *line),
-                    Br => if let Some(Token::Register(_, r)) = tokens.next() {
-                        Opcode::Br(*r)
-                    } else {
-                        panic!("Invalid instruction on line {}", line);
-                    },
-                    _ => panic!("This instruction is unimplemented: {:?}", instr),
-                });
-                handle_line_map(&mut line_map, i, &mut line_number, *line);
+                    Bls | Bgt | Bge | Blt | Ble => handle_b(*instr, &mut ops, &mut symbols, i),
+                    Addi | Addis | Andi | Andis | Eori | Orri | Subi | Subis => handle_i(*instr, &mut ops, &mut symbols, i),
+                    Add | Adds | And | Ands | Eor | Orr | Sub | Subs | Mul | Sdiv => handle_r(*instr, &mut ops),
+                    Lsl | Lsr => handle_shift(*instr, &mut ops),
+                    Movz | Movk => handle_im(*instr, &mut ops),
+                    Br => ops.read_register(false).map(Opcode::Br),
+                    _ => {
+                        debug_assert!(!instr.is_supported());
+                        Err(AssembleError::new(*span, format!("This instruction is unimplemented: {:?}", instr)))
+                    }
+                };
+                match op {
+                    Ok(op) => code.push(op),
+                    Err(e) => {
+                        errors.push(e);
+                        // Keep the addresses of the following instructions correct.
+                        code.push(Opcode::Halt());
+                        skip_line(&mut tokens, *span);
+                    }
+                }
+                handle_line_map(&mut line_map, i, &mut line_number, *span);
                 i += 1;
             }
-            _ => panic!("Expected label or instruction on line {}", t.line()),
-        }
-    }
-
-    for (l, pos) in jumps {
-        // NOTE that these must be forward jumps as they would otherwise already have been handled.
-        if let Some(i) = labels.get(&l) {
-            let addr = (*i - pos) as u32;
-            match code[pos].instruction() {
-                Instruction::B => code[pos] = code[pos].b_set_addr(addr),
-                Instruction::Bl => code[pos] = code[pos].bl_set_addr(addr),
-                Instruction::Cbz => code[pos] = code[pos].cbz_set_addr(addr),
-                Instruction::Cbnz => code[pos] = code[pos].cbnz_set_addr(addr),
-                Instruction::Beq => code[pos] = code[pos].beq_set_addr(addr),
-                Instruction::Bne => code[pos] = code[pos].bne_set_addr(addr),
-                Instruction::Bhs => code[pos] = code[pos].bhs_set_addr(addr),
-                Instruction::Blo => code[pos] = code[pos].blo_set_addr(addr),
-                Instruction::Bmi => code[pos] = code[pos].bmi_set_addr(addr),
-                Instruction::Bpl => code[pos] = code[pos].bpl_set_addr(addr),
-                Instruction::Bvs => code[pos] = code[pos].bvs_set_addr(addr),
-                Instruction::Bvc => code[pos] = code[pos].bvc_set_addr(addr),
-                Instruction::Bhi => code[pos] = code[pos].bhi_set_addr(addr),
-                Instruction::Bls => code[pos] = code[pos].bls_set_addr(addr),
-                Instruction::Bgt => code[pos] = code[pos].bgt_set_addr(addr),
-                Instruction::Bge => code[pos] = code[pos].bge_set_addr(addr),
-                Instruction::Blt => code[pos] = code[pos].blt_set_addr(addr),
-                Instruction::Ble => code[pos] = code[pos].ble_set_addr(addr),
-                _ => unreachable!(),
+            Token::Pseudo(span, pseudo) => {
+                let mut ops = Operands { tokens: &mut tokens, last: *span };
+                let expansion = match handle_pseudo(*pseudo, &mut ops, &mut symbols, i) {
+                    Ok(ops) => ops,
+                    Err(e) => {
+                        errors.push(e);
+                        skip_line(&mut tokens, *span);
+                        vec![Opcode::Halt()]
+                    }
+                };
+                // Every instruction of the expansion belongs to the line of the pseudo-instruction.
+                for op in expansion {
+                    code.push(op);
+                    handle_line_map(&mut line_map, i, &mut line_number, *span);
+                    i += 1;
+                }
+            }
+            _ => {
+                errors.push(AssembleError::new(t.span(), "Expected label, instruction or directive"));
+                skip_line(&mut tokens, t.span());
             }
-        } else {
-            panic!("Label {} not found", l);
         }
     }
 
-    (code, line_map)
+    if data.len() > HEAP_SIZE {
+        errors.push(AssembleError::new(Span::new(line_number, 1, 1),
+                                       format!("The data section is {} bytes but memory is only {} bytes",
+                                               data.len(), HEAP_SIZE)));
+    }
+
+    for (l, span) in &globals {
+        if symbols.externs.contains_key(l) {
+            errors.push(AssembleError::new(*span, format!("Label `{}` cannot be both global and external", l)));
+        } else if !symbols.contains(l) {
+            errors.push(AssembleError::new(*span, format!("Global label `{}` is not defined", l)));
+        }
+    }
+
+    let mut relocations = Vec::new();
+    let references = std::mem::take(&mut symbols.references);
+    for (l, pos, span, fixup) in references {
+        let extern_ = !symbols.contains(&l) && symbols.externs.contains_key(&l);
+        // Addresses of labels depend on where the program is placed in memory, so they are
+        // relocated when linking even if they are already resolved.
+        if extern_ || fixup != Fixup::Branch {
+            relocations.push(Relocation { offset: pos, fixup, symbol: l.clone() });
+        }
+        if extern_ {
+            continue;
+        }
+
+        match fixup {
+            // NOTE that these must be forward jumps as they would otherwise already have been handled.
+            Fixup::Branch => if let Some(i) = symbols.labels.get(&l) {
+                code[pos] = code[pos].set_branch_addr((*i - pos) as u32);
+            } else if symbols.data_labels.contains_key(&l) {
+                errors.push(AssembleError::new(span, format!("Cannot branch to data label `{}`", l)));
+            } else {
+                errors.push(AssembleError::new(span, format!("Label `{}` not found", l)));
+            },
+            Fixup::Imm => match symbols.value(&l) {
+                Some(v) if v < 4096 => code[pos] = code[pos].set_i_imm(v as u16),
+                Some(v) => errors.push(AssembleError::new(span, format!(
+                    "The address of `{}` is {} which does not fit in a 12-bit immediate", l, v))),
+                None => errors.push(AssembleError::new(span, format!("Label `{}` not found", l))),
+            },
+            Fixup::Data(size) => match symbols.value(&l) {
+                Some(v) => data[pos..pos+size].copy_from_slice(&v.to_le_bytes()[..size]),
+                None => errors.push(AssembleError::new(span, format!("Label `{}` not found", l))),
+            },
+        }
+    }
+
+    if errors.is_empty() {
+        let entry = symbols.labels.get(&ENTRY_LABEL.to_string()).cloned().unwrap_or(0);
+        Ok(Program {
+            code,
+            entry,
+            line_map,
+            labels: symbols.labels.into_iter().map(|(l, i)| (l.clone(), i)).collect(),
+            data,
+            data_labels: symbols.data_labels.into_iter().map(|(l, a)| (l.clone(), a)).collect(),
+            globals: globals.into_iter().map(|(l, _)| l.clone()).collect(),
+            relocations,
+            externs: symbols.externs.into_iter().map(|(l, s)| (l.clone(), s)).collect(),
+        })
+    } else {
+        Err(errors)
+    }
+}
+
+/// Tokenizes, preprocesses and assembles `source`, with `.include` paths relative to `dir`. The
+/// errors from every stage are returned together, with errors in included files naming the file.
+pub fn assemble_source(source: &str, dir: &Path) -> Result<Program, Vec<AssembleError>> {
+    assemble_source_tokens(source, dir).map(|(program, _)| program)
 }
 
-fn handle_line_map(map: &mut Vec<usize>, i: usize, line_number: &mut usize, l: usize) {
-    for _ in *line_number..=l {
+/// Like `assemble_source`, but also returns the preprocessed tokens the program was assembled
+/// from.
+pub fn assemble_source_tokens(source: &str, dir: &Path) -> Result<(Program, Vec<Token>), Vec<AssembleError>> {
+    let (tokens, mut errors) = Tokenizer::tokenize(source);
+    let (tokens, files, e) = preprocess(tokens, dir);
+    errors.extend(e);
+    let result = match assemble(tokens.clone()) {
+        Ok(program) if errors.is_empty() => return Ok((program, tokens)),
+        Ok(_) => errors,
+        Err(e) => {
+            errors.extend(e);
+            errors
+        }
+    };
+    Err(result.into_iter()
+        .map(|mut e| {
+            if e.span.file != 0 {
+                e.file = files[e.span.file - 1].display().to_string();
+            }
+            e
+        })
+        .collect())
+}
+
+/// Returns the source line containing the instruction at `pc`. Instructions after the first in the
+/// expansion of a pseudo-instruction belong to the line of the pseudo-instruction.
+pub fn pc_to_line(line_map: &[usize], pc: usize) -> Option<usize> {
+    line_map.iter().rposition(|&i| i <= pc).map(|l| l + 1)
+}
+
+/// Maps the line of `span` to the instruction index `i`. The code of an included file belongs to
+/// the line of its `.include`, so spans in included files are left out.
+fn handle_line_map(map: &mut Vec<usize>, i: usize, line_number: &mut usize, span: Span) {
+    if span.file != 0 {
+        return;
+    }
+    for _ in *line_number..=span.line {
         map.push(i);
     }
-    *line_number = l + 1;
+    *line_number = span.line + 1;
 }
 
-fn read_imm(tokens: &mut Iter<Token>, line_number: usize) -> u16 {
-    if let Some(Token::Immediate(_, imm)) = tokens.next() {
-        *imm
-    } else {
-        panic!("Expected immediate on line {}", line_number);
+fn skip_line(tokens: &mut Peekable<Iter<Token>>, span: Span) {
+    while tokens.peek().is_some_and(|t| t.span().same_line(span)) {
+        tokens.next();
     }
 }
 
-fn handle_d(instr: Instruction, tokens: &mut Iter<Token>, line_number: usize) -> Opcode {
-    let rt = read_register(tokens, true, line_number);
-    if let Some(Token::LBrace(_)) = tokens.next() {
-    } else {
-        panic!("Expected `[` on line {}", line_number);
-    };
+/// The operands following an instruction. Operands must be on the same line as the instruction.
+struct Operands<'a, 'b> {
+    tokens: &'b mut Peekable<Iter<'a, Token>>,
+    /// The span of the last token consumed, used to point at the end of the line when an
+    /// operand is missing.
+    last: Span,
+}
 
-    let rn = read_register(tokens, true, line_number);
-    let addr = read_imm(tokens, line_number);
-    if let Some(Token::RBrace(_)) = tokens.next() {
-    } else {
-        panic!("Expected `]` on line {}", line_number);
-    };
+impl<'a, 'b> Operands<'a, 'b> {
+    fn peek(&mut self) -> Option<&'a Token> {
+        match self.tokens.peek() {
+            Some(t) if t.span().same_line(self.last) => Some(*t),
+            _ => None,
+        }
+    }
 
-    match instr {
+    fn next(&mut self) -> Option<&'a Token> {
+        let last = self.last;
+        if self.tokens.peek().is_some_and(|t| t.span().same_line(last)) {
+            let t = self.tokens.next().unwrap();
+            self.last = t.span();
+            Some(t)
+        } else {
+            None
+        }
+    }
+
+    fn error(&self, t: Option<&Token>, message: &str) -> AssembleError {
+        match t {
+            Some(t) => AssembleError::new(t.span(), message),
+            None => AssembleError::new(self.last.after(), message),
+        }
+    }
+
+    fn read_register(&mut self, trailing_comma: bool) -> Result<Register, AssembleError> {
+        let r = match self.next() {
+            Some(Token::Register(_, r)) => *r,
+            t => return Err(self.error(t, "Expected register")),
+        };
+
+        if trailing_comma {
+            self.read_comma()?;
+        }
+        Ok(r)
+    }
+
+    fn read_comma(&mut self) -> Result<(), AssembleError> {
+        match self.next() {
+            Some(Token::Comma(_)) => Ok(()),
+            t => Err(self.error(t, "Expected comma")),
+        }
+    }
+
+    fn read_imm(&mut self) -> Result<(i64, Span), AssembleError> {
+        match self.next() {
+            Some(Token::Immediate(span, imm)) => Ok((*imm, *span)),
+            t => Err(self.error(t, "Expected immediate")),
+        }
+    }
+
+    /// Reads an immediate and checks that it is between `min` and `max` inclusive.
+    fn read_imm_in(&mut self, min: i64, max: i64, what: &str) -> Result<i64, AssembleError> {
+        let (imm, span) = self.read_imm()?;
+        if imm < min || imm > max {
+            return Err(AssembleError::new(span, format!("{} must be between {} and {}, found {}",
+                                                        what, min, max, imm)));
+        }
+        Ok(imm)
+    }
+
+    fn read_label(&mut self) -> Result<(&'a String, Span), AssembleError> {
+        match self.next() {
+            Some(Token::Label(span, l)) => Ok((l, *span)),
+            t => Err(self.error(t, "Expected label")),
+        }
+    }
+}
+
+fn handle_directive<'a>(directive: Directive, ops: &mut Operands<'a, '_>, symbols: &mut Symbols<'a>,
+                        data: &mut Vec<u8>)
+    -> Result<(), AssembleError>
+{
+    match directive {
+        Directive::Dword | Directive::Word | Directive::Byte => {
+            let (size, min, max) = match directive {
+                Directive::Dword => (8, i64::MIN, i64::MAX),
+                Directive::Word => (4, i32::MIN as i64, u32::MAX as i64),
+                _ => (1, i8::MIN as i64, u8::MAX as i64),
+            };
+            loop {
+                match ops.next() {
+                    Some(Token::Immediate(span, v)) => {
+                        if *v < min || *v > max {
+                            return Err(AssembleError::new(*span, format!(
+                                "Value must be between {} and {}, found {}", min, max, v)));
+                        }
+                        data.extend_from_slice(&v.to_le_bytes()[..size]);
+                    }
+                    Some(Token::Label(span, l)) => {
+                        symbols.references.push((l.clone(), data.len(), *span, Fixup::Data(size)));
+                        data.extend_from_slice(&[0; 8][..size]);
+                    }
+                    t => return Err(ops.error(t, "Expected value")),
+                }
+                if !matches!(ops.peek(), Some(Token::Comma(_))) {
+                    return Ok(());
+                }
+                ops.read_comma()?;
+            }
+        }
+        Directive::Asciz => loop {
+            match ops.next() {
+                Some(Token::Str(_, s)) => {
+                    data.extend_from_slice(s.as_bytes());
+                    data.push(0);
+                }
+                t => return Err(ops.error(t, "Expected string")),
+            }
+            if !matches!(ops.peek(), Some(Token::Comma(_))) {
+                return Ok(());
+            }
+            ops.read_comma()?;
+        },
+        Directive::Space => {
+            let n = ops.read_imm_in(0, HEAP_SIZE as i64, "Size")?;
+            data.resize(data.len() + n as usize, 0);
+            Ok(())
+        }
+        Directive::Align => {
+            let n = ops.read_imm_in(0, 12, "Alignment")?;
+            let align = 1 << n;
+            while !data.len().is_multiple_of(align) {
+                data.push(0);
+            }
+            Ok(())
+        }
+        Directive::Data | Directive::Text | Directive::Global | Directive::Extern | Directive::Include => {
+            unreachable!()
+        }
+        // These are handled by `preprocess::preprocess` before assembling.
+        Directive::Equ | Directive::Macro | Directive::Endm => {
+            Err(AssembleError::new(ops.last, "Unexpected directive"))
+        }
+    }
+}
+
+fn handle_d(instr: Instruction, ops: &mut Operands) -> Result<Opcode, AssembleError> {
+    let rt = ops.read_register(true)?;
+    match ops.next() {
+        Some(Token::LBrace(_)) => (),
+        t => return Err(ops.error(t, "Expected `[`")),
+    }
+
+    let rn = ops.read_register(true)?;
+    let addr = ops.read_imm_in(-256, 255, "Offset")? as i16;
+    match ops.next() {
+        Some(Token::RBrace(_)) => (),
+        t => return Err(ops.error(t, "Expected `]`")),
+    }
+
+    Ok(match instr {
         Instruction::Stur => Opcode::Stur(rn, rt, addr),
         Instruction::Ldur => Opcode::Ldur(rn, rt, addr),
         _ => unreachable!(),
-    }
+    })
 }
 
-fn handle_b(instr: Instruction, tokens: &mut Iter<Token>, labels: &HashMap<&String, usize>,
-            jumps: &mut Vec<(String, usize)>, code_pos: usize, line_number: usize)
-    -> Opcode
+fn handle_b<'a>(instr: Instruction, ops: &mut Operands<'a, '_>, symbols: &mut Symbols<'a>, code_pos: usize)
+    -> Result<Opcode, AssembleError>
 {
-    let addr = handle_label(tokens, labels, jumps, code_pos, line_number);
-    match instr {
+    let addr = handle_label(ops, symbols, code_pos)?;
+    Ok(match instr {
         Instruction::B => Opcode::B(addr),
         Instruction::Bl => Opcode::Bl(addr),
         Instruction::Beq => Opcode::Beq(addr),
@@ -149,96 +546,199 @@ fn handle_b(instr: Instruction, tokens: &mut Iter<Token>, labels: &HashMap<&Stri
         Instruction::Blt => Opcode::Blt(addr),
         Instruction::Ble => Opcode::Ble(addr),
         _ => unreachable!(),
-    }
+    })
 }
 
-fn read_register(tokens: &mut Iter<Token>, trailing_comma: bool, line_number: usize) -> Register {
-    let r = if let Some(Token::Register(_, r)) = tokens.next() {
-        r
-    } else {
-        panic!("Expected register on line {}", line_number);
-    };
-
-    if trailing_comma {
-        if let Some(Token::Comma(_)) = tokens.next() {
-        } else {
-            panic!("Expected comma on line {}", line_number);
-        }
-    }
-
-    *r
-}
-
-fn handle_label(tokens: &mut Iter<Token>, labels: &HashMap<&String, usize>,
-                jumps: &mut Vec<(String, usize)>, code_pos: usize, line_number: usize)
-    -> u32
+fn handle_label<'a>(ops: &mut Operands<'a, '_>, symbols: &mut Symbols<'a>, code_pos: usize)
+    -> Result<u32, AssembleError>
 {
-    let label = if let Some(Token::Label(_, l)) = tokens.next() {
-        l
-    } else {
-        panic!("Expected label on line {}", line_number);
-    };
+    let (label, span) = ops.read_label()?;
 
     // NOTE if the label is found it must be behind this point.
-    if let Some(i) = labels.get(label) {
-        -((code_pos as i32) - (*i as i32)) as u32
+    if let Some(i) = symbols.labels.get(label) {
+        Ok(-((code_pos as i32) - (*i as i32)) as u32)
     } else {
-        jumps.push((label.to_string(), code_pos));
-        0
+        symbols.references.push((label.to_string(), code_pos, span, Fixup::Branch));
+        Ok(0)
     }
 }
 
-fn handle_cb(instr: Instruction, tokens: &mut Iter<Token>, labels: &HashMap<&String, usize>,
-            jumps: &mut Vec<(String, usize)>, code_pos: usize, line_number: usize)
-    -> Opcode
+fn handle_cb<'a>(instr: Instruction, ops: &mut Operands<'a, '_>, symbols: &mut Symbols<'a>, code_pos: usize)
+    -> Result<Opcode, AssembleError>
 {
-    let rt = read_register(tokens, true, line_number);
-    let addr = handle_label(tokens, labels, jumps, code_pos, line_number);
-    match instr {
+    let rt = ops.read_register(true)?;
+    let addr = handle_label(ops, symbols, code_pos)?;
+    Ok(match instr {
         Instruction::Cbz => Opcode::Cbz(rt, addr),
         Instruction::Cbnz => Opcode::Cbnz(rt, addr),
         _ => unreachable!(),
-    }
+    })
+}
+
+fn handle_i<'a>(instr: Instruction, ops: &mut Operands<'a, '_>, symbols: &mut Symbols<'a>, code_pos: usize)
+    -> Result<Opcode, AssembleError>
+{
+    let rd = ops.read_register(true)?;
+    let rn = ops.read_register(true)?;
+    let (instr, imm) = read_i_imm(instr, ops, symbols, code_pos)?;
+    Ok(i_opcode(instr, rn, rd, imm))
 }
 
-fn handle_i(instr: Instruction, tokens: &mut Iter<Token>, line_number: usize) -> Opcode {
-    let rd = read_register(tokens, true, line_number);
-    let rn = read_register(tokens, true, line_number);
-    let imm = read_imm(tokens, line_number);
+/// Reads the immediate of an I-format instruction, returning the instruction to encode it with.
+fn read_i_imm<'a>(instr: Instruction, ops: &mut Operands<'a, '_>, symbols: &mut Symbols<'a>, code_pos: usize)
+    -> Result<(Instruction, u16), AssembleError>
+{
+    use bytecode::Instruction::*;
+    // A label can be used in place of the immediate to get its address.
+    let (instr, imm) = if let Some(Token::Label(span, l)) = ops.peek() {
+        ops.next();
+        // The address is filled in once all labels are known.
+        symbols.references.push((l.clone(), code_pos, *span, Fixup::Imm));
+        (instr, 0)
+    } else {
+        // Negative immediates are accepted for arithmetic instructions by switching to the
+        // opposite operation, as the immediate field itself is unsigned.
+        match instr {
+            Addi | Addis | Subi | Subis => {
+                let imm = ops.read_imm_in(-4095, 4095, "Immediate")?;
+                match instr {
+                    Addi if imm < 0 => (Subi, -imm),
+                    Subi if imm < 0 => (Addi, -imm),
+                    Addis if imm < 0 => (Subis, -imm),
+                    Subis if imm < 0 => (Addis, -imm),
+                    _ => (instr, imm),
+                }
+            }
+            _ => (instr, ops.read_imm_in(0, 4095, "Immediate")?),
+        }
+    };
+    Ok((instr, imm as u16))
+}
 
+fn i_opcode(instr: Instruction, rn: Register, rd: Register, imm: u16) -> Opcode {
+    use bytecode::Instruction::*;
     match instr {
-        Instruction::Addi => Opcode::Addi(rn, rd, imm),
-        Instruction::Addis => Opcode::Addis(rn, rd, imm),
-        Instruction::Andi => Opcode::Andi(rn, rd, imm),
-        Instruction::Andis => Opcode::Andis(rn, rd, imm),
-        Instruction::Eori => Opcode::Eori(rn, rd, imm),
-        Instruction::Orri => Opcode::Orri(rn, rd, imm),
-        Instruction::Subi => Opcode::Subi(rn, rd, imm),
-        Instruction::Subis => Opcode::Subis(rn, rd, imm),
+        Addi => Opcode::Addi(rn, rd, imm),
+        Addis => Opcode::Addis(rn, rd, imm),
+        Andi => Opcode::Andi(rn, rd, imm),
+        Andis => Opcode::Andis(rn, rd, imm),
+        Eori => Opcode::Eori(rn, rd, imm),
+        Orri => Opcode::Orri(rn, rd, imm),
+        Subi => Opcode::Subi(rn, rd, imm),
+        Subis => Opcode::Subis(rn, rd, imm),
         _ => unreachable!(),
     }
 }
 
-fn handle_shift(instr: Instruction, tokens: &mut Iter<Token>, line_number: usize) -> Opcode {
-    let rd = read_register(tokens, true, line_number);
-    let rn = read_register(tokens, true, line_number);
-    let imm = read_imm(tokens, line_number);
+/// Expands a pseudo-instruction into the real instructions that implement it.
+fn handle_pseudo<'a>(pseudo: Pseudo, ops: &mut Operands<'a, '_>, symbols: &mut Symbols<'a>, code_pos: usize)
+    -> Result<Vec<Opcode>, AssembleError>
+{
+    let xzr = Register(31);
+    Ok(match pseudo {
+        // MOV Xd, Xm => ORR Xd, XZR, Xm
+        Pseudo::Mov => {
+            let rd = ops.read_register(true)?;
+            let rm = ops.read_register(false)?;
+            vec![Opcode::Orr(rm, xzr, rd)]
+        }
+        // CMP Xn, Xm => SUBS XZR, Xn, Xm
+        Pseudo::Cmp => {
+            let rn = ops.read_register(true)?;
+            let rm = ops.read_register(false)?;
+            vec![Opcode::Subs(rm, rn, xzr)]
+        }
+        // CMPI Xn, #imm => SUBIS XZR, Xn, #imm
+        Pseudo::Cmpi => {
+            let rn = ops.read_register(true)?;
+            let (instr, imm) = read_i_imm(Instruction::Subis, ops, symbols, code_pos)?;
+            vec![i_opcode(instr, rn, xzr, imm)]
+        }
+        // LDA Xd, label => ADDI Xd, XZR, label
+        Pseudo::Lda => {
+            let rd = ops.read_register(true)?;
+            let (instr, imm) = read_i_imm(Instruction::Addi, ops, symbols, code_pos)?;
+            vec![i_opcode(instr, xzr, rd, imm)]
+        }
+        // MOVI Xd, #imm => MOVZ Xd, #imm[15:0] followed by a MOVK for each other non-zero
+        // 16 bits of the immediate.
+        Pseudo::Movi => {
+            let rd = ops.read_register(true)?;
+            let (imm, _) = ops.read_imm()?;
+            let imm = imm as u64;
+            let mut code = Vec::new();
+            for shift in (0..64).step_by(16) {
+                let part = (imm >> shift) as u16;
+                if code.is_empty() && (part != 0 || imm >> shift == 0) {
+                    code.push(Opcode::Movz(rd, part, shift));
+                } else if !code.is_empty() && part != 0 {
+                    code.push(Opcode::Movk(rd, part, shift));
+                }
+            }
+            code
+        }
+        // NEG Xd, Xm => SUB Xd, XZR, Xm
+        Pseudo::Neg => {
+            let rd = ops.read_register(true)?;
+            let rm = ops.read_register(false)?;
+            vec![Opcode::Sub(rm, xzr, rd)]
+        }
+        // NOP => ORR XZR, XZR, XZR
+        Pseudo::Nop => vec![Opcode::Orr(xzr, xzr, xzr)],
+        // RET => BR LR
+        Pseudo::Ret => vec![Opcode::Br(Register(30))],
+    })
+}
 
-    match instr {
-        Instruction::Lsl => Opcode::Lsl(rn, rd, imm as u32),
-        Instruction::Lsr => Opcode::Lsr(rn, rd, imm as u32),
+fn handle_shift(instr: Instruction, ops: &mut Operands) -> Result<Opcode, AssembleError> {
+    let rd = ops.read_register(true)?;
+    let rn = ops.read_register(true)?;
+    let imm = ops.read_imm_in(0, 63, "Shift amount")? as u32;
+
+    Ok(match instr {
+        Instruction::Lsl => Opcode::Lsl(rn, rd, imm),
+        Instruction::Lsr => Opcode::Lsr(rn, rd, imm),
         _ => unreachable!(),
-    }
+    })
 }
 
-fn handle_r(instr: Instruction, tokens: &mut Iter<Token>, line_number: usize) -> Opcode {
-    let rd = read_register(tokens, true, line_number);
-    let rn = read_register(tokens, true, line_number);
-    let rm = read_register(tokens, false, line_number);
+fn handle_im(instr: Instruction, ops: &mut Operands) -> Result<Opcode, AssembleError> {
+    let rd = ops.read_register(true)?;
+    let imm = ops.read_imm_in(0, 0xffff, "Immediate")? as u16;
 
-    match instr {
+    // The shift is optional, e.g. `MOVZ X9, #255, LSL #16`.
+    let shift = if let Some(Token::Comma(_)) = ops.peek() {
+        ops.read_comma()?;
+        match ops.next() {
+            Some(Token::Instruction(_, Instruction::Lsl)) => (),
+            t => return Err(ops.error(t, "Expected `LSL`")),
+        }
+        let (shift, span) = ops.read_imm()?;
+        if shift != 0 && shift != 16 && shift != 32 && shift != 48 {
+            return Err(AssembleError::new(span, format!("Shift must be 0, 16, 32 or 48, found {}", shift)));
+        }
+        shift as u32
+    } else {
+        0
+    };
+
+    Ok(match instr {
+        Instruction::Movz => Opcode::Movz(rd, imm, shift),
+        Instruction::Movk => Opcode::Movk(rd, imm, shift),
+        _ => unreachable!(),
+    })
+}
+
+fn handle_r(instr: Instruction, ops: &mut Operands) -> Result<Opcode, AssembleError> {
+    let rd = ops.read_register(true)?;
+    let rn = ops.read_register(true)?;
+    let rm = ops.read_register(false)?;
+
+    Ok(match instr {
         Instruction::Add => Opcode::Add(rm, rn, rd),
+        Instruction::Adds => Opcode::Adds(rm, rn, rd),
         Instruction::And => Opcode::And(rm, rn, rd),
+        Instruction::Ands => Opcode::Ands(rm, rn, rd),
         Instruction::Eor => Opcode::Eor(rm, rn, rd),
         Instruction::Orr => Opcode::Orr(rm, rn, rd),
         Instruction::Sub => Opcode::Sub(rm, rn, rd),
@@ -246,5 +746,67 @@ fn handle_r(instr: Instruction, tokens: &mut Iter<Token>, line_number: usize) ->
         Instruction::Mul => Opcode::Mul(rm, rn, rd),
         Instruction::Sdiv => Opcode::Sdiv(rm, rn, rd),
         _ => unreachable!(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokenizer::Tokenizer;
+
+    #[test]
+    fn reports_every_error() {
+        let src = "ADD X1 X2, X3\nB nowhere\nADDI X0, X0, #1\nCBZ X1\n";
+        let (tokens, errors) = Tokenizer::tokenize(src);
+        assert!(errors.is_empty());
+        let errors = assemble(tokens).unwrap_err();
+        let spans: Vec<_> = errors.iter().map(|e| (e.span.line, e.span.column)).collect();
+        assert_eq!(spans, vec![(1, 8), (4, 7), (2, 3)]);
+    }
+
+    #[test]
+    fn tokenizer_drops_bad_lines() {
+        let (tokens, errors) = Tokenizer::tokenize("ADDI X0, X0, #1x\nADD X1, X1, X1\n");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].span, Span::new(1, 14, 3));
+        assert!(tokens.iter().all(|t| t.line() == 2));
+    }
+
+    #[test]
+    fn immediate_formats() {
+        let src = "ADDI X0, X0, #0x10\nADDI X0, X0, #-8\nORRI X0, X0, #0b1010\nADDI X0, X0, #'A'\n";
+        let (tokens, _) = Tokenizer::tokenize(src);
+        let code = assemble(tokens).unwrap().code;
+        assert_eq!(code, vec![Opcode::Addi(Register(0), Register(0), 16),
+                              Opcode::Subi(Register(0), Register(0), 8),
+                              Opcode::Orri(Register(0), Register(0), 10),
+                              Opcode::Addi(Register(0), Register(0), 65)]);
+    }
+
+    #[test]
+    fn data_layout() {
+        let src = ".data\na: .byte 1\n.align 3\nb: .dword a, b, c\ns: .asciz \"ok\"\n\
+                   .text\nc: ADDI X0, XZR, s\n";
+        let (tokens, _) = Tokenizer::tokenize(src);
+        let program = assemble(tokens).unwrap();
+        assert_eq!(program.data_labels["b"], 8);
+        assert_eq!(program.data_labels["s"], 32);
+        assert_eq!(&program.data[8..24], &[0, 0, 0, 0, 0, 0, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&program.data[32..], b"ok\0");
+        assert_eq!(program.code, vec![Opcode::Addi(Register(31), Register(0), 32)]);
+    }
+
+    #[test]
+    fn pseudo_instructions() {
+        let src = "MOV X0, X1\nMOVI X2, #0x10000ffff\nCMPI X0, #-3\nRET\n";
+        let (tokens, _) = Tokenizer::tokenize(src);
+        let program = assemble(tokens).unwrap();
+        assert_eq!(program.code, vec![Opcode::Orr(Register(1), Register(31), Register(0)),
+                                      Opcode::Movz(Register(2), 0xffff, 0),
+                                      Opcode::Movk(Register(2), 1, 32),
+                                      Opcode::Addis(Register(0), Register(31), 3),
+                                      Opcode::Br(Register(30))]);
+        let lines: Vec<_> = (0..5).map(|pc| pc_to_line(&program.line_map, pc)).collect();
+        assert_eq!(lines, vec![Some(1), Some(2), Some(2), Some(3), Some(4)]);
     }
 }
@@ -10,7 +10,13 @@ pub struct Opcode(pub u32);
 impl fmt::Display for Opcode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         use self::Instruction::*;
-        match self.instruction() {
+        // Instructions the assembler doesn't support are shown as words, as the operands of some
+        // of them aren't laid out the way they would be printed.
+        let instruction = match self.decode() {
+            Some(i) if i.is_supported() => i,
+            _ => return write!(f, ".word {:#010x}", self.0),
+        };
+        match instruction {
             Prnt | Prnl | Dump | Halt => self.print_special(f),
             B | Bl => self.print_b(f),
             Cbz | Cbnz | Beq | Bne | Bhs | Blo | Bmi | Bpl | Bvs | Bvc | Bhi | Bls | Bgt | Bge | Blt | Ble => self.print_cb(f),
@@ -45,9 +51,11 @@ macro_rules! r {
 }
 
 macro_rules! im {
-    ($instruction:ident, $rd:ident, $imm:ident) => {
-        pub fn $instruction(rd: Register, imm: u16) -> Self {
-            Opcode(Instruction::$instruction.as_u32() | ((imm as u32) << 5) | rd.as_u32())
+    ($instruction:ident, $rd:ident, $imm:ident, $shift:ident) => {
+        /// `shift` is the number of bits to shift `imm` left by and must be 0, 16, 32 or 48.
+        pub fn $instruction(rd: Register, imm: u16, shift: u32) -> Self {
+            assert!(shift % 16 == 0 && shift < 64);
+            Opcode(Instruction::$instruction.as_u32() | ((shift / 16) << 21) | ((imm as u32) << 5) | rd.as_u32())
         }
 
         pub fn $rd(self) -> Register {
@@ -57,6 +65,10 @@ macro_rules! im {
         pub fn $imm(self) -> u16 {
             ((self.0 >> 5) & 0xff_ff) as u16
         }
+
+        pub fn $shift(self) -> u32 {
+            ((self.0 >> 21) & 0b11) * 16
+        }
     };
 }
 
@@ -145,22 +157,18 @@ macro_rules! i {
             Register((self.0 & 0b11111) as u8)
         }
 
-        pub fn $imm(self) -> i16 {
-            let i = ((self.0 >> 10) & 0b1111_11111111) as u16;
-            if (i >> 11) == 1 {
-                (i | (0b1111 << 12)) as i16
-            } else {
-                i as i16
-            }
+        pub fn $imm(self) -> u16 {
+            ((self.0 >> 10) & 0b1111_11111111) as u16
         }
     };
 }
 
 macro_rules! d {
     ($instruction:ident, $rn:ident, $rt:ident, $addr:ident) => {
-        pub fn $instruction(rn: Register, rt: Register, addr: u16) -> Self {
-            assert!(addr < 0b10_00000000);
-            Opcode(Instruction::$instruction.as_u32() | ((addr as u32) << 11) | (rn.as_u32() << 5) | rt.as_u32())
+        /// `addr` is a signed 9-bit byte offset.
+        pub fn $instruction(rn: Register, rt: Register, addr: i16) -> Self {
+            assert!((-256..256).contains(&addr));
+            Opcode(Instruction::$instruction.as_u32() | (((addr as u32) & 0b1_11111111) << 12) | (rn.as_u32() << 5) | rt.as_u32())
         }
 
         pub fn $rn(self) -> Register {
@@ -171,15 +179,90 @@ macro_rules! d {
             Register((self.0 & 0b11111) as u8)
         }
 
-        pub fn $addr(self) -> u16 {
-            ((self.0 >> 11) & 0b1_11111111) as u16
+        pub fn $addr(self) -> i16 {
+            let i = ((self.0 >> 12) & 0b1_11111111) as u16;
+            if (i >> 8) == 1 {
+                (i | (0b1111111 << 9)) as i16
+            } else {
+                i as i16
+            }
         }
     };
 }
 
 impl Opcode {
+    /// Returns the instruction this word encodes, or `None` if it is not a valid encoding.
+    pub fn decode(&self) -> Option<Instruction> {
+        Instruction::decode(self.0)
+    }
+
+    /// Returns true if this word is the only encoding of its instruction and operands, so that
+    /// assembling its disassembly gives the same word.
+    pub fn is_canonical(&self) -> bool {
+        self.decode().is_some_and(|i| i.as_u32() | (self.0 & i.operand_mask()) == self.0)
+    }
+
+    /// Returns the instruction this word encodes. Words that may not be valid, such as those read
+    /// from a file, must be checked with `decode` first.
     pub fn instruction(&self) -> Instruction {
-        Instruction::from(self.0)
+        self.decode().expect("invalid instruction encoding")
+    }
+
+    /// Returns the offset in instructions of a branch instruction, or `None` for other instructions.
+    pub fn branch_addr(self) -> Option<u32> {
+        use self::Instruction::*;
+        Some(match self.decode()? {
+            B => self.b_addr(),
+            Bl => self.bl_addr(),
+            Cbz => self.cbz_addr(),
+            Cbnz => self.cbnz_addr(),
+            Beq => self.beq_addr(),
+            Bne => self.bne_addr(),
+            Bhs => self.bhs_addr(),
+            Blo => self.blo_addr(),
+            Bmi => self.bmi_addr(),
+            Bpl => self.bpl_addr(),
+            Bvs => self.bvs_addr(),
+            Bvc => self.bvc_addr(),
+            Bhi => self.bhi_addr(),
+            Bls => self.bls_addr(),
+            Bgt => self.bgt_addr(),
+            Bge => self.bge_addr(),
+            Blt => self.blt_addr(),
+            Ble => self.ble_addr(),
+            _ => return None,
+        })
+    }
+
+    /// Sets the offset of a branch instruction whose offset is currently 0.
+    pub fn set_branch_addr(self, addr: u32) -> Self {
+        use self::Instruction::*;
+        match self.instruction() {
+            B => self.b_set_addr(addr),
+            Bl => self.bl_set_addr(addr),
+            Cbz => self.cbz_set_addr(addr),
+            Cbnz => self.cbnz_set_addr(addr),
+            Beq => self.beq_set_addr(addr),
+            Bne => self.bne_set_addr(addr),
+            Bhs => self.bhs_set_addr(addr),
+            Blo => self.blo_set_addr(addr),
+            Bmi => self.bmi_set_addr(addr),
+            Bpl => self.bpl_set_addr(addr),
+            Bvs => self.bvs_set_addr(addr),
+            Bvc => self.bvc_set_addr(addr),
+            Bhi => self.bhi_set_addr(addr),
+            Bls => self.bls_set_addr(addr),
+            Bgt => self.bgt_set_addr(addr),
+            Bge => self.bge_set_addr(addr),
+            Blt => self.blt_set_addr(addr),
+            Ble => self.ble_set_addr(addr),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Replaces the immediate of an I-format instruction.
+    pub fn set_i_imm(self, imm: u16) -> Self {
+        Opcode((self.0 & !(0xfff << 10)) | ((imm as u32 & 0xfff) << 10))
     }
 
     fn print_special(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -254,9 +337,8 @@ impl Opcode {
     fn print_im(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         use self::Instruction::*;
         match self.instruction() {
-            // TODO: handle shift amount
-            Movk => write!(f, "MOVK {}, {}", self.movk_rd(), self.movk_imm()),
-            Movz => write!(f, "MOVZ {}, {}", self.movz_rd(), self.movz_imm()),
+            Movk => write!(f, "MOVK {}, #{}, LSL #{}", self.movk_rd(), self.movk_imm(), self.movk_shift()),
+            Movz => write!(f, "MOVZ {}, #{}, LSL #{}", self.movz_rd(), self.movz_imm(), self.movz_shift()),
             _ => unreachable!(),
         }
     }
@@ -309,7 +391,6 @@ impl Opcode {
             Lsl => write!(f, "LSL {}, {}, #{}", self.lsl_rd(), self.lsl_rn(), self.lsl_shamt()),
             Lsr => write!(f, "LSR {}, {}, #{}", self.lsr_rd(), self.lsr_rn(), self.lsr_shamt()),
             Br => write!(f, "BR {}", self.br_rt()),
-            // TODO: arithmetic instructions
             _ => unreachable!(),
         }
     }
@@ -354,8 +435,8 @@ impl Opcode {
     bcond!(Blt, blt_addr, blt_set_addr);
     bcond!(Ble, ble_addr, ble_set_addr);
 
-    im!(Movk, movk_rd, movk_imm);
-    im!(Movz, movz_rd, movz_imm);
+    im!(Movk, movk_rd, movk_imm, movk_shift);
+    im!(Movz, movz_rd, movz_imm, movz_shift);
 
     i!(Addi, addi_rn, addi_rd, addi_imm);
     i!(Addis, addis_rn, addis_rd, addis_imm);
@@ -387,7 +468,7 @@ impl Opcode {
     }
 
     pub fn br_rt(self) -> Register {
-        Register(((self.0 >> 5) & 0b111110) as u8)
+        Register(((self.0 >> 5) & 0b11111) as u8)
     }
 
     r!(Eor, eor_rm, eor_rn, eor_rd);
@@ -450,6 +531,31 @@ impl Opcode {
     r!(Umulh, umulh_rm, umulh_rn, umulh_rd);
 }
 
+/// The encoding formats of the LEGv8 reference card.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Format {
+    R,
+    I,
+    D,
+    B,
+    CB,
+    IM,
+}
+
+impl Format {
+    /// Returns the name and width in bits of each field, from the most significant bit.
+    pub fn fields(self) -> &'static [(&'static str, u32)] {
+        match self {
+            Format::R => &[("opcode", 11), ("Rm", 5), ("shamt", 6), ("Rn", 5), ("Rd", 5)],
+            Format::I => &[("opcode", 10), ("ALU_immediate", 12), ("Rn", 5), ("Rd", 5)],
+            Format::D => &[("opcode", 11), ("DT_address", 9), ("op", 2), ("Rn", 5), ("Rt", 5)],
+            Format::B => &[("opcode", 6), ("BR_address", 26)],
+            Format::CB => &[("opcode", 8), ("COND_BR_address", 19), ("Rt", 5)],
+            Format::IM => &[("opcode", 9), ("LSL", 2), ("MOV_immediate", 16), ("Rd", 5)],
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Instruction {
     B,
@@ -527,6 +633,60 @@ pub enum Instruction {
 }
 
 impl Instruction {
+    /// Returns true if the assembler and emulator support this instruction.
+    pub fn is_supported(self) -> bool {
+        use self::Instruction::*;
+        matches!(self, Prnt | Prnl | Dump | Halt | Stur | Ldur | Cbz | Cbnz | B | Bl | Beq | Bne | Bhs | Blo | Bmi |
+                 Bpl | Bvs | Bvc | Bhi | Bls | Bgt | Bge | Blt | Ble | Addi | Addis | Andi | Andis | Eori | Orri |
+                 Subi | Subis | Add | Adds | And | Ands | Eor | Orr | Sub | Subs | Mul | Sdiv | Lsl | Lsr | Movz |
+                 Movk | Br)
+    }
+
+    pub fn format(self) -> Format {
+        use self::Instruction::*;
+        match self {
+            B | Bl => Format::B,
+            Cbz | Cbnz | Beq | Bne | Bhs | Blo | Bmi | Bpl | Bvs | Bvc | Bhi | Bls | Bgt | Bge | Blt | Ble => Format::CB,
+            Movz | Movk => Format::IM,
+            Addi | Addis | Andi | Andis | Eori | Orri | Subi | Subis => Format::I,
+            Ldur | Ldurb | Ldurh | Ldursw | Ldxr | Stur | Sturb | Sturh | Sturw | Stxr => Format::D,
+            _ => Format::R,
+        }
+    }
+
+    /// Returns the bits of the encoding that hold operands. All other bits are fixed by the
+    /// instruction.
+    pub fn operand_mask(self) -> u32 {
+        use self::Instruction::*;
+        match self {
+            Prnl | Dump | Halt => 0,
+            Prnt => 0b11111,
+            Br => 0b11111 << 5,
+            Lsl | Lsr => 0xffff,
+            // The condition of B.cond is part of the instruction.
+            Beq | Bne | Bhs | Blo | Bmi | Bpl | Bvs | Bvc | Bhi | Bls | Bgt | Bge | Blt | Ble => 0x7ffff << 5,
+            _ => match self.format() {
+                Format::R => (0b11111 << 16) | 0x3ff,
+                Format::I => 0x3fffff,
+                Format::D => (0x1ff << 12) | 0x3ff,
+                Format::B => 0x3ffffff,
+                Format::CB => 0xffffff,
+                Format::IM => 0x7fffff,
+            },
+        }
+    }
+
+    /// Every mnemonic that `from_str` accepts.
+    pub const MNEMONICS: &[&str] = &[
+        "B", "FMULS", "FDIVS", "FCMPS", "FADDS", "FSUBS", "FMULD", "FDIVD", "FCMPD", "FADDD", "FSUBD",
+        "STURB", "LDURB", "B.EQ", "B.NE", "B.HS", "B.LO", "B.MI", "B.PL", "B.VS", "B.VC", "B.HI",
+        "B.LS", "B.GT", "B.LT", "B.GE", "B.LE", "STURH", "LDURH", "AND", "ADD", "ADDI", "ANDI", "BL",
+        "SDIV", "UDIV", "MUL", "SMULH", "UMULH", "ORR", "ADDS", "ADDIS", "ORRI", "CBZ", "CBNZ", "STURW",
+        "LDURSW", "STURS", "LDURS", "STXR", "LDXR", "EOR", "SUB", "SUBI", "EORI", "MOVZ", "LSR", "LSL",
+        "BR", "ANDS", "SUBS", "SUBIS", "ANDIS", "MOVK", "STUR", "LDUR", "STURD", "LDURD", "PRNT",
+        "PRNL", "DUMP", "HALT",
+    ];
+
     pub fn from_str(s: &str) -> Option<Self> {
         use self::Instruction::*;
         Some(match s {
@@ -606,7 +766,8 @@ impl Instruction {
         })
     }
 
-    fn as_u32(&self) -> u32 {
+    /// Returns the encoding of the instruction with every operand 0.
+    pub fn as_u32(&self) -> u32 {
         use self::Instruction::*;
         match self {
             // B instructions
@@ -701,143 +862,456 @@ impl Instruction {
     }
 }
 
-impl From<u32> for Instruction {
-    fn from(r: u32) -> Self {
+impl Instruction {
+    /// Decodes the instruction that `r` encodes, or returns `None` if it is not a valid encoding.
+    pub fn decode(r: u32) -> Option<Self> {
         use self::Instruction::*;
         // check if branch instruction
         match r >> 26 {
-            0b000101 => return B,
-            0b100101 => return Bl,
+            0b000101 => return Some(B),
+            0b100101 => return Some(Bl),
             _ => (),
         }
 
         // check if conditional branch instruction
         match r >> 24 {
-            0b10110100 => return Cbz,
-            0b10110101 => return Cbnz,
+            0b10110100 => return Some(Cbz),
+            0b10110101 => return Some(Cbnz),
             0b01010100 => match r & 0b11111 {
-                0x00 => return Beq,
-                0x01 => return Bne,
-                0x02 => return Bhs,
-                0x03 => return Blo,
-                0x04 => return Bmi,
-                0x05 => return Bpl,
-                0x06 => return Bvs,
-                0x07 => return Bvc,
-                0x08 => return Bhi,
-                0x09 => return Bls,
-                0x0c => return Bgt,
-                0x0a => return Bge,
-                0x0b => return Blt,
-                0x0d => return Ble,
-                _ => unreachable!(),
+                0x00 => return Some(Beq),
+                0x01 => return Some(Bne),
+                0x02 => return Some(Bhs),
+                0x03 => return Some(Blo),
+                0x04 => return Some(Bmi),
+                0x05 => return Some(Bpl),
+                0x06 => return Some(Bvs),
+                0x07 => return Some(Bvc),
+                0x08 => return Some(Bhi),
+                0x09 => return Some(Bls),
+                0x0c => return Some(Bgt),
+                0x0a => return Some(Bge),
+                0x0b => return Some(Blt),
+                0x0d => return Some(Ble),
+                _ => return None,
             }
             _ => (),
         }
 
         // check if IM instruction
         match r >> 23 {
-            0b110100101 => return Movz,
-            0b111100101 => return Movk,
+            0b110100101 => return Some(Movz),
+            0b111100101 => return Some(Movk),
             _ => (),
         }
 
         // check if I instruction
         match r >> 22 {
-            0b1001000100 => return Addi,
-            0b1001001000 => return Andi,
-            0b1011000100 => return Addis,
-            0b1011001000 => return Orri,
-            0b1101000100 => return Subi,
-            0b1101001000 => return Eori,
-            0b1111000100 => return Subis,
-            0b1111001000 => return Andis,
+            0b1001000100 => return Some(Addi),
+            0b1001001000 => return Some(Andi),
+            0b1011000100 => return Some(Addis),
+            0b1011001000 => return Some(Orri),
+            0b1101000100 => return Some(Subi),
+            0b1101001000 => return Some(Eori),
+            0b1111000100 => return Some(Subis),
+            0b1111001000 => return Some(Andis),
             _ => (),
         }
 
         // check if D instruction
         match r >> 21 {
-            0b00111000000 => return Sturb,
-            0b00111000010 => return Ldurb,
-            0b01111000000 => return Sturh,
-            0b01111000010 => return Ldurh,
-            0b10111000000 => return Sturw,
-            0b10111000100 => return Ldursw,
-            0b11001000000 => return Stxr,
-            0b11001000010 => return Ldxr,
-            0b11111000000 => return Stur,
-            0b11111000010 => return Ldur,
+            0b00111000000 => return Some(Sturb),
+            0b00111000010 => return Some(Ldurb),
+            0b01111000000 => return Some(Sturh),
+            0b01111000010 => return Some(Ldurh),
+            0b10111000000 => return Some(Sturw),
+            0b10111000100 => return Some(Ldursw),
+            0b11001000000 => return Some(Stxr),
+            0b11001000010 => return Some(Ldxr),
+            0b11111000000 => return Some(Stur),
+            0b11111000010 => return Some(Ldur),
             _ => (),
         }
 
         // check if R instruction
         match r >> 21 {
-            0b10001010000 => return And,
-            0b10001011000 => return Add,
-            0b10011011000 => return Mul,
+            0b10001010000 => return Some(And),
+            0b10001011000 => return Some(Add),
+            0b10011011000 => return Some(Mul),
             // sdiv/udiv
             0b10011010110 => match (r >> 10) & 0b111111 {
-                0b000010 => return Sdiv,
-                0b000011 => return Udiv,
+                0b000010 => return Some(Sdiv),
+                0b000011 => return Some(Udiv),
                 _ => (),
             },
-            0b10011011010 => return Smulh,
-            0b10011011110 => return Umulh,
-            0b10101010000 => return Orr,
-            0b10101011000 => return Adds,
-            0b10111100000 => return Sturs,
-            0b10111100010 => return Ldurs,
-            0b11001010000 => return Eor,
-            0b11001011000 => return Sub,
-            0b11010011010 => return Lsr,
-            0b11010011011 => return Lsl,
-            0b11010110000 => return Br,
-            0b11101010000 => return Ands,
-            0b11101011000 => return Subs,
-            0b11111100000 => return Sturd,
-            0b11111100010 => return Ldurd,
+            0b10011011010 => return Some(Smulh),
+            0b10011011110 => return Some(Umulh),
+            0b10101010000 => return Some(Orr),
+            0b10101011000 => return Some(Adds),
+            0b10111100000 => return Some(Sturs),
+            0b10111100010 => return Some(Ldurs),
+            0b11001010000 => return Some(Eor),
+            0b11001011000 => return Some(Sub),
+            0b11010011010 => return Some(Lsr),
+            0b11010011011 => return Some(Lsl),
+            0b11010110000 => return Some(Br),
+            0b11101010000 => return Some(Ands),
+            0b11101011000 => return Some(Subs),
+            0b11111100000 => return Some(Sturd),
+            0b11111100010 => return Some(Ldurd),
             // floating point instructions
             0b00011110001 => match (r >> 10) & 0b111111 {
-                0b000010 => return Fmuls,
-                0b000110 => return Fdivs,
-                0b001000 => return Fcmps,
-                0b001010 => return Fadds,
-                0b001110 => return Fsubs,
+                0b000010 => return Some(Fmuls),
+                0b000110 => return Some(Fdivs),
+                0b001000 => return Some(Fcmps),
+                0b001010 => return Some(Fadds),
+                0b001110 => return Some(Fsubs),
                 _ => (),
             },
             0b00011110011 => match (r >> 10) & 0b111111 {
-                0b000010 => return Fmuld,
-                0b000110 => return Fdivd,
-                0b001000 => return Fcmpd,
-                0b001010 => return Faddd,
-                0b001110 => return Fsubd,
+                0b000010 => return Some(Fmuld),
+                0b000110 => return Some(Fdivd),
+                0b001000 => return Some(Fcmpd),
+                0b001010 => return Some(Faddd),
+                0b001110 => return Some(Fsubd),
                 _ => (),
             },
-            0b11111111101 => return Prnt,
-            0b11111111100 => return Prnl,
-            0b11111111110 => return Dump,
-            0b11111111111=> return Halt,
+            0b11111111101 => return Some(Prnt),
+            0b11111111100 => return Some(Prnl),
+            0b11111111110 => return Some(Dump),
+            0b11111111111=> return Some(Halt),
             _ => (),
         }
 
-        unreachable!();
+        None
     }
 }
 
 #[cfg(test)]
-mod test {
+pub mod test {
     use super::*;
+    use assemble::assemble;
+    use tokenizer::Tokenizer;
+
+    /// A xorshift generator, so that the generated tests are the same on every run.
+    pub struct Rng(u64);
+
+    impl Rng {
+        pub fn new(seed: u64) -> Self {
+            Rng(seed | 1)
+        }
+
+        pub fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        /// Returns a number in `0..n`.
+        pub fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+
+        fn register(&mut self) -> Register {
+            Register(self.below(32) as u8)
+        }
+
+        /// Returns a number that fits in `bits` bits when sign-extended, as a `u32`.
+        fn signed(&mut self, bits: u32) -> u32 {
+            (self.below(1 << bits) as i64 - (1 << (bits - 1))) as i32 as u32
+        }
+    }
+
+    type R = (Instruction, fn(Register, Register, Register) -> Opcode, fn(Opcode) -> Register,
+              fn(Opcode) -> Register, fn(Opcode) -> Register);
+    const R: &[R] = &[
+        (Instruction::Add, Opcode::Add, Opcode::add_rm, Opcode::add_rn, Opcode::add_rd),
+        (Instruction::Adds, Opcode::Adds, Opcode::adds_rm, Opcode::adds_rn, Opcode::adds_rd),
+        (Instruction::And, Opcode::And, Opcode::and_rm, Opcode::and_rn, Opcode::and_rd),
+        (Instruction::Ands, Opcode::Ands, Opcode::ands_rm, Opcode::ands_rn, Opcode::ands_rd),
+        (Instruction::Eor, Opcode::Eor, Opcode::eor_rm, Opcode::eor_rn, Opcode::eor_rd),
+        (Instruction::Orr, Opcode::Orr, Opcode::orr_rm, Opcode::orr_rn, Opcode::orr_rd),
+        (Instruction::Sub, Opcode::Sub, Opcode::sub_rm, Opcode::sub_rn, Opcode::sub_rd),
+        (Instruction::Subs, Opcode::Subs, Opcode::subs_rm, Opcode::subs_rn, Opcode::subs_rd),
+        (Instruction::Mul, Opcode::Mul, Opcode::mul_rm, Opcode::mul_rn, Opcode::mul_rd),
+        (Instruction::Sdiv, Opcode::Sdiv, Opcode::sdiv_rm, Opcode::sdiv_rn, Opcode::sdiv_rd),
+    ];
+
+    type I = (Instruction, fn(Register, Register, u16) -> Opcode, fn(Opcode) -> Register,
+              fn(Opcode) -> Register, fn(Opcode) -> u16);
+    const I: &[I] = &[
+        (Instruction::Addi, Opcode::Addi, Opcode::addi_rn, Opcode::addi_rd, Opcode::addi_imm),
+        (Instruction::Addis, Opcode::Addis, Opcode::addis_rn, Opcode::addis_rd, Opcode::addis_imm),
+        (Instruction::Andi, Opcode::Andi, Opcode::andi_rn, Opcode::andi_rd, Opcode::andi_imm),
+        (Instruction::Andis, Opcode::Andis, Opcode::andis_rn, Opcode::andis_rd, Opcode::andis_imm),
+        (Instruction::Eori, Opcode::Eori, Opcode::eori_rn, Opcode::eori_rd, Opcode::eori_imm),
+        (Instruction::Orri, Opcode::Orri, Opcode::orri_rn, Opcode::orri_rd, Opcode::orri_imm),
+        (Instruction::Subi, Opcode::Subi, Opcode::subi_rn, Opcode::subi_rd, Opcode::subi_imm),
+        (Instruction::Subis, Opcode::Subis, Opcode::subis_rn, Opcode::subis_rd, Opcode::subis_imm),
+    ];
+
+    type D = (Instruction, fn(Register, Register, i16) -> Opcode, fn(Opcode) -> Register,
+              fn(Opcode) -> Register, fn(Opcode) -> i16);
+    const D: &[D] = &[
+        (Instruction::Ldur, Opcode::Ldur, Opcode::ldur_rn, Opcode::ldur_rt, Opcode::ldur_addr),
+        (Instruction::Stur, Opcode::Stur, Opcode::stur_rn, Opcode::stur_rt, Opcode::stur_addr),
+    ];
+
+    type IM = (Instruction, fn(Register, u16, u32) -> Opcode, fn(Opcode) -> Register, fn(Opcode) -> u16,
+               fn(Opcode) -> u32);
+    const IM: &[IM] = &[
+        (Instruction::Movz, Opcode::Movz, Opcode::movz_rd, Opcode::movz_imm, Opcode::movz_shift),
+        (Instruction::Movk, Opcode::Movk, Opcode::movk_rd, Opcode::movk_imm, Opcode::movk_shift),
+    ];
+
+    type Branch = (Instruction, fn(u32) -> Opcode, fn(Opcode) -> u32);
+    const BRANCHES: &[Branch] = &[
+        (Instruction::B, Opcode::B, Opcode::b_addr),
+        (Instruction::Bl, Opcode::Bl, Opcode::bl_addr),
+        (Instruction::Beq, Opcode::Beq, Opcode::beq_addr),
+        (Instruction::Bne, Opcode::Bne, Opcode::bne_addr),
+        (Instruction::Bhs, Opcode::Bhs, Opcode::bhs_addr),
+        (Instruction::Blo, Opcode::Blo, Opcode::blo_addr),
+        (Instruction::Bmi, Opcode::Bmi, Opcode::bmi_addr),
+        (Instruction::Bpl, Opcode::Bpl, Opcode::bpl_addr),
+        (Instruction::Bvs, Opcode::Bvs, Opcode::bvs_addr),
+        (Instruction::Bvc, Opcode::Bvc, Opcode::bvc_addr),
+        (Instruction::Bhi, Opcode::Bhi, Opcode::bhi_addr),
+        (Instruction::Bls, Opcode::Bls, Opcode::bls_addr),
+        (Instruction::Bgt, Opcode::Bgt, Opcode::bgt_addr),
+        (Instruction::Bge, Opcode::Bge, Opcode::bge_addr),
+        (Instruction::Blt, Opcode::Blt, Opcode::blt_addr),
+        (Instruction::Ble, Opcode::Ble, Opcode::ble_addr),
+    ];
+
+    type CB = (Instruction, fn(Register, u32) -> Opcode, fn(Opcode) -> Register, fn(Opcode) -> u32);
+    const CB: &[CB] = &[
+        (Instruction::Cbz, Opcode::Cbz, Opcode::cbz_rt, Opcode::cbz_addr),
+        (Instruction::Cbnz, Opcode::Cbnz, Opcode::cbnz_rt, Opcode::cbnz_addr),
+    ];
+
+    /// Returns a random instruction that the assembler supports, checking that its fields read
+    /// back as they were given. Branch offsets are below `max_offset` in either direction.
+    pub fn random_opcode(rng: &mut Rng, max_offset: u32) -> Opcode {
+        let offset = |rng: &mut Rng| (rng.below(2 * max_offset as u64) as i64 - max_offset as i64) as u32;
+        let (instruction, op) = match rng.below(9) {
+            0 => {
+                let &(instruction, new, rm, rn, rd) = &R[rng.below(R.len() as u64) as usize];
+                let regs = (rng.register(), rng.register(), rng.register());
+                let op = new(regs.0, regs.1, regs.2);
+                assert_eq!((rm(op), rn(op), rd(op)), regs);
+                (instruction, op)
+            }
+            1 => {
+                let &(instruction, new, rn, rd, imm) = &I[rng.below(I.len() as u64) as usize];
+                let fields = (rng.register(), rng.register(), rng.below(4096) as u16);
+                let op = new(fields.0, fields.1, fields.2);
+                assert_eq!((rn(op), rd(op), imm(op)), fields);
+                (instruction, op)
+            }
+            2 => {
+                let &(instruction, new, rn, rt, addr) = &D[rng.below(D.len() as u64) as usize];
+                let fields = (rng.register(), rng.register(), rng.signed(9) as i16);
+                let op = new(fields.0, fields.1, fields.2);
+                assert_eq!((rn(op), rt(op), addr(op)), fields);
+                (instruction, op)
+            }
+            3 => {
+                let &(instruction, new, rd, imm, shift) = &IM[rng.below(IM.len() as u64) as usize];
+                let fields = (rng.register(), rng.below(65536) as u16, rng.below(4) as u32 * 16);
+                let op = new(fields.0, fields.1, fields.2);
+                assert_eq!((rd(op), imm(op), shift(op)), fields);
+                (instruction, op)
+            }
+            4 => {
+                let &(instruction, new, addr) = &BRANCHES[rng.below(BRANCHES.len() as u64) as usize];
+                let a = offset(rng);
+                let op = new(a);
+                assert_eq!(addr(op), a);
+                (instruction, op)
+            }
+            5 => {
+                let &(instruction, new, rt, addr) = &CB[rng.below(CB.len() as u64) as usize];
+                let fields = (rng.register(), offset(rng));
+                let op = new(fields.0, fields.1);
+                assert_eq!((rt(op), addr(op)), fields);
+                (instruction, op)
+            }
+            6 => {
+                let (rn, rd, shamt) = (rng.register(), rng.register(), rng.below(64) as u32);
+                let (instruction, op) = if rng.below(2) == 0 {
+                    let op = Opcode::Lsl(rn, rd, shamt);
+                    assert_eq!((op.lsl_rn(), op.lsl_rd(), op.lsl_shamt() as u32), (rn, rd, shamt));
+                    (Instruction::Lsl, op)
+                } else {
+                    let op = Opcode::Lsr(rn, rd, shamt);
+                    assert_eq!((op.lsr_rn(), op.lsr_rd(), op.lsr_shamt() as u32), (rn, rd, shamt));
+                    (Instruction::Lsr, op)
+                };
+                (instruction, op)
+            }
+            7 => {
+                let rt = rng.register();
+                let op = Opcode::Br(rt);
+                assert_eq!(op.br_rt(), rt);
+                (Instruction::Br, op)
+            }
+            _ => match rng.below(4) {
+                0 => {
+                    let rd = rng.register();
+                    let op = Opcode::Prnt(rd);
+                    assert_eq!(op.prnt_rd(), rd);
+                    (Instruction::Prnt, op)
+                }
+                1 => (Instruction::Prnl, Opcode::Prnl()),
+                2 => (Instruction::Dump, Opcode::Dump()),
+                _ => (Instruction::Halt, Opcode::Halt()),
+            },
+        };
+        assert_eq!(op.decode(), Some(instruction), "{:08x}", op.0);
+        assert!(op.is_canonical(), "{}", op);
+        op
+    }
+
+    fn assemble_one(source: &str) -> Opcode {
+        let (tokens, errors) = Tokenizer::tokenize(source);
+        assert!(errors.is_empty(), "{}", source);
+        let program = assemble(tokens).unwrap_or_else(|e| panic!("{}: {:?}", source, e));
+        assert_eq!(program.code.len(), 1, "{}", source);
+        program.code[0]
+    }
+
+    #[test]
+    fn random_instructions_round_trip() {
+        for m in Instruction::MNEMONICS {
+            assert!(Instruction::from_str(m).is_some(), "{}", m);
+        }
+        let mut rng = Rng::new(0x5eed);
+        for _ in 0..5000 {
+            let op = random_opcode(&mut rng, 1 << 18);
+            let text = op.to_string();
+            let mnemonic = text.split(' ').next().unwrap();
+            assert_eq!(Instruction::from_str(mnemonic), op.decode(), "{}", text);
+            if op.branch_addr().is_none() {
+                assert_eq!(assemble_one(&text), op, "{}", text);
+            }
+        }
+    }
+
+    #[test]
+    fn decoding_never_panics() {
+        let mut rng = Rng::new(42);
+        for _ in 0..100000 {
+            let op = Opcode(rng.next() as u32);
+            if let Some(i) = op.decode() {
+                assert_eq!(op.instruction(), i);
+                assert_eq!(Instruction::decode(i.as_u32() | (op.0 & i.operand_mask())), Some(i));
+            }
+            op.to_string();
+        }
+    }
+
+    /// Encodings worked out by hand from the fields and opcodes on the LEGv8 reference card.
+    const REFERENCE: &[(&str, u32)] = &[
+        ("ADD X9, X20, X21", 0x8b150289),
+        ("ADDS X1, X2, X3", 0xab030041),
+        ("SUB X0, X1, X2", 0xcb020020),
+        ("SUBS XZR, X5, X6", 0xeb0600bf),
+        ("AND X10, X11, X12", 0x8a0c016a),
+        ("ANDS X1, X1, X1", 0xea010021),
+        ("ORR X3, XZR, X4", 0xaa0403e3),
+        ("EOR X7, X8, X9", 0xca090107),
+        ("MUL X2, X3, X4", 0x9b047c62),
+        ("SDIV X2, X3, X4", 0x9ac40862),
+        ("LSL X1, X2, #3", 0xd3600c41),
+        ("LSR X1, X2, #63", 0xd340fc41),
+        ("BR LR", 0xd60003c0),
+        ("ADDI X0, X1, #4095", 0x913ffc20),
+        ("ADDIS X0, X1, #1", 0xb1000420),
+        ("SUBI SP, SP, #16", 0xd100439c),
+        ("SUBIS XZR, X9, #7", 0xf1001d3f),
+        ("ANDI X1, X2, #255", 0x9203fc41),
+        ("ANDIS X1, X2, #1", 0xf2000441),
+        ("ORRI X1, X2, #2", 0xb2000841),
+        ("EORI X1, X2, #3", 0xd2000c41),
+        ("LDUR X0, [SP, #8]", 0xf8408380),
+        ("STUR LR, [SP, #-8]", 0xf81f839e),
+        ("STUR X1, [X2, #255]", 0xf80ff041),
+        ("LDUR X1, [X2, #-256]", 0xf8500041),
+        ("MOVZ X9, #65535, LSL #48", 0xd2ffffe9),
+        ("MOVK X9, #4660, LSL #16", 0xf2a24689),
+        ("PRNT X5", 0xffa00005),
+        ("PRNL", 0xff800000),
+        ("DUMP", 0xffc00000),
+        ("HALT", 0xffe00000),
+    ];
 
     #[test]
+    fn encodings_match_reference_card() {
+        for &(text, word) in REFERENCE {
+            assert_eq!(assemble_one(text), Opcode(word), "{}", text);
+            assert_eq!(Opcode(word).to_string(), text);
+            assert!(Opcode(word).is_canonical(), "{}", text);
+        }
+
+        let (tokens, _) = Tokenizer::tokenize("
+        l0: B l2
+        l1: BL l0
+        l2: CBZ X3, l0
+            CBNZ X4, l4
+        l4: B.EQ l7
+            B.GE l2
+            B.LE l7
+        l7: HALT
+        ");
+        let code: Vec<u32> = assemble(tokens).unwrap().code.iter().map(|op| op.0).collect();
+        assert_eq!(code, vec![0x14000002, 0x97ffffff, 0xb4ffffc3, 0xb5000024, 0x54000060, 0x54ffffaa, 0x5400002d,
+                              0xffe00000]);
+    }
+
+    #[test]
+    #[allow(clippy::unusual_byte_groupings)]
     fn abc() {
         let i = 0b00010100_00000000_00000000_00000000;
-        assert_eq!(Instruction::B, Instruction::from(i));
+        assert_eq!(Instruction::B, Instruction::decode(i).unwrap());
         let i = 0b10010100_00000000_00000000_00000000;
-        assert_eq!(Instruction::Bl, Instruction::from(i));
+        assert_eq!(Instruction::Bl, Instruction::decode(i).unwrap());
         let a = Opcode::Add(Register(31), Register(31), Register(10)).0;
         let b = 0b10001011000_11111_000000_11111_01010;
         println!("{:032b}", a);
         println!("{:032b}", b);
         assert_eq!(a, b);
     }
+
+    #[test]
+    #[allow(clippy::unusual_byte_groupings)]
+    fn d_offsets_sign_extend() {
+        let op = Opcode::Stur(Register(28), Register(30), -8);
+        assert_eq!(op.0, 0b11111000000_111111000_00_11100_11110);
+        assert_eq!(op.stur_addr(), -8);
+        assert_eq!(Opcode::Ldur(Register(1), Register(2), 255).ldur_addr(), 255);
+        assert_eq!(Opcode::Ldur(Register(1), Register(2), -256).ldur_addr(), -256);
+    }
+
+    #[test]
+    fn d_format_matches_reference_card() {
+        // LDUR X9, [X22, #64] and STUR X9, [X22, #64], with the offset at bits 20 to 12.
+        assert_eq!(Opcode::Ldur(Register(22), Register(9), 64).0, 0xf84402c9);
+        assert_eq!(Opcode::Stur(Register(22), Register(9), 64).0, 0xf80402c9);
+        assert_eq!(Opcode::Ldur(Register(22), Register(9), 64).to_string(), "LDUR X9, [X22, #64]");
+    }
+
+    #[test]
+    fn unsupported_instructions_print_as_words() {
+        let op = Opcode::Ldurs(Register(2), Register(1), Register(0));
+        assert_eq!(op.decode(), Some(Instruction::Ldurs));
+        assert_eq!(op.to_string(), format!(".word {:#010x}", op.0));
+    }
+
+    #[test]
+    fn br_decodes_odd_registers() {
+        assert_eq!(Opcode::Br(Register(1)).br_rt().0, 1);
+        assert_eq!(Opcode::Br(Register(30)).to_string(), "BR LR");
+        assert_eq!(Opcode::Br(Register(31)).br_rt().0, 31);
+    }
 }
@@ -0,0 +1,357 @@
+use assemble::{pc_to_line, Program};
+use bytecode::{Instruction, Opcode};
+use disassemble::{names, target, text};
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+
+/// A straight-line run of instructions that is only entered at the top and left at the bottom.
+pub struct Block {
+    /// The instruction index of the first instruction.
+    pub start: usize,
+    /// The instruction index after the last instruction.
+    pub end: usize,
+    /// The blocks that execution may continue with, taken branch first.
+    pub successors: Vec<usize>,
+    /// The instruction index called by a `BL` at the end of the block.
+    pub call: Option<usize>,
+}
+
+/// The blocks reachable from the entry point or the target of a `BL` without following calls.
+pub struct Procedure {
+    pub name: String,
+    pub entry: usize,
+    pub blocks: Vec<usize>,
+    pub loops: Vec<Loop>,
+}
+
+/// A natural loop: the blocks that can reach a back edge to `header` without passing through it.
+pub struct Loop {
+    pub header: usize,
+    pub blocks: Vec<usize>,
+    /// 1 for outermost loops, 2 for loops directly inside them and so on.
+    pub depth: usize,
+}
+
+pub struct Cfg<'a> {
+    program: &'a Program,
+    names: HashMap<usize, String>,
+    pub blocks: Vec<Block>,
+    pub procedures: Vec<Procedure>,
+}
+
+/// Returns whether `op` ends a basic block.
+fn ends_block(op: Opcode) -> bool {
+    op.branch_addr().is_some() || matches!(op.decode(), Some(Instruction::Br) | Some(Instruction::Halt))
+}
+
+impl<'a> Cfg<'a> {
+    pub fn new(program: &'a Program) -> Self {
+        let code = &program.code;
+        let len = code.len();
+        let mut leaders = BTreeSet::new();
+        let mut entries = BTreeSet::new();
+        if len > 0 {
+            leaders.insert(0);
+            leaders.insert(program.entry.min(len - 1));
+        }
+        for (pc, &op) in code.iter().enumerate() {
+            if let Some(t) = target(op, pc).filter(|&t| t < len) {
+                leaders.insert(t);
+                if op.decode() == Some(Instruction::Bl) {
+                    entries.insert(t);
+                }
+            }
+            if ends_block(op) && pc + 1 < len {
+                leaders.insert(pc + 1);
+            }
+        }
+
+        let starts: Vec<usize> = leaders.into_iter().collect();
+        let index: HashMap<usize, usize> = starts.iter().enumerate().map(|(i, &s)| (s, i)).collect();
+        let mut blocks = Vec::new();
+        for (i, &start) in starts.iter().enumerate() {
+            let end = starts.get(i + 1).cloned().unwrap_or(len);
+            let last = end - 1;
+            let op = code[last];
+            let taken = target(op, last).and_then(|t| index.get(&t).cloned());
+            let next = index.get(&end).cloned();
+            let (successors, call) = match op.decode() {
+                Some(Instruction::B) => (taken.into_iter().collect(), None),
+                Some(Instruction::Bl) => (next.into_iter().collect(), target(op, last).filter(|&t| t < len)),
+                Some(Instruction::Br) | Some(Instruction::Halt) => (Vec::new(), None),
+                _ if op.branch_addr().is_some() => (taken.into_iter().chain(next).collect(), None),
+                _ => (next.into_iter().collect(), None),
+            };
+            blocks.push(Block { start, end, successors, call });
+        }
+
+        let names = names(program);
+        let mut procedures = Vec::new();
+        if !blocks.is_empty() {
+            let main = index[&program.entry.min(len - 1)];
+            let others = entries.iter().map(|e| index[e]).filter(|&b| b != main);
+            for entry in std::iter::once(main).chain(others) {
+                let start = blocks[entry].start;
+                let name = match names.get(&start) {
+                    Some(l) => l.clone(),
+                    None if entry == main => "(entry)".to_string(),
+                    None => format!("{:#06x}", start * 4),
+                };
+                let reachable = reachable(&blocks, entry);
+                let loops = loops(&blocks, entry, &reachable);
+                procedures.push(Procedure { name, entry, blocks: reachable, loops });
+            }
+        }
+
+        Cfg { program, names, blocks, procedures }
+    }
+
+    /// Returns the blocks that no procedure reaches.
+    pub fn unreachable(&self) -> Vec<usize> {
+        (0..self.blocks.len())
+            .filter(|b| !self.procedures.iter().any(|p| p.blocks.contains(b)))
+            .collect()
+    }
+
+    fn describe(&self, b: usize) -> String {
+        let block = &self.blocks[b];
+        match self.names.get(&block.start) {
+            Some(l) => format!("{} ({:#06x})", l, block.start * 4),
+            None => format!("{:#06x}", block.start * 4),
+        }
+    }
+
+    /// Returns the graph in Graphviz DOT format, with each procedure in its own cluster. Blocks
+    /// reached from several procedures are drawn in the first one. Calls are dashed edges.
+    pub fn dot(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "digraph cfg {{").unwrap();
+        writeln!(out, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+
+        let mut drawn = vec![false; self.blocks.len()];
+        let unreachable = self.unreachable();
+        let groups = self.procedures.iter().map(|p| (p.name.clone(), &p.blocks))
+            .chain(std::iter::once(("(unreachable)".to_string(), &unreachable)));
+        for (i, (name, blocks)) in groups.enumerate() {
+            let blocks: Vec<usize> = blocks.iter().cloned().filter(|&b| !drawn[b]).collect();
+            if blocks.is_empty() {
+                continue;
+            }
+            writeln!(out, "    subgraph cluster_{} {{", i).unwrap();
+            writeln!(out, "        label=\"{}\";", escape(&name)).unwrap();
+            for b in blocks {
+                drawn[b] = true;
+                writeln!(out, "        b{} [label=\"{}\"];", b, self.block_label(b)).unwrap();
+            }
+            writeln!(out, "    }}").unwrap();
+        }
+
+        for (i, block) in self.blocks.iter().enumerate() {
+            let conditional = block.successors.len() == 2;
+            for (n, &s) in block.successors.iter().enumerate() {
+                if conditional && n == 0 {
+                    writeln!(out, "    b{} -> b{} [label=\"taken\"];", i, s).unwrap();
+                } else {
+                    writeln!(out, "    b{} -> b{};", i, s).unwrap();
+                }
+            }
+            if let Some(c) = block.call.and_then(|c| self.blocks.iter().position(|b| b.start == c)) {
+                writeln!(out, "    b{} -> b{} [style=dashed, label=\"call\"];", i, c).unwrap();
+            }
+        }
+        writeln!(out, "}}").unwrap();
+        out
+    }
+
+    /// The instructions of block `b`, left-aligned.
+    fn block_label(&self, b: usize) -> String {
+        let block = &self.blocks[b];
+        let mut label = String::new();
+        if let Some(l) = self.names.get(&block.start) {
+            write!(label, "{}:\\l", escape(l)).unwrap();
+        }
+        for pc in block.start..block.end {
+            let text = text(self.program.code[pc], pc, &self.names);
+            write!(label, "{:08x}  {}\\l", pc * 4, escape(&text)).unwrap();
+        }
+        label
+    }
+
+    /// Returns a summary of every procedure's blocks and loops, with loops indented by how deeply
+    /// they are nested.
+    pub fn summary(&self) -> String {
+        let mut out = String::new();
+        for p in &self.procedures {
+            writeln!(out, "Procedure {} ({:#06x}), {}", p.name, self.blocks[p.entry].start * 4,
+                     plural(p.blocks.len(), "block")).unwrap();
+            let mut blocks = p.blocks.clone();
+            blocks.sort();
+            for b in blocks {
+                writeln!(out, "    {}", self.block_summary(b)).unwrap();
+            }
+            if !p.loops.is_empty() {
+                writeln!(out, "  Loops:").unwrap();
+            }
+            for l in &p.loops {
+                writeln!(out, "{}loop at {}, {}, depth {}", "    ".repeat(l.depth), self.describe(l.header),
+                         plural(l.blocks.len(), "block"), l.depth).unwrap();
+            }
+        }
+        let unreachable = self.unreachable();
+        if !unreachable.is_empty() {
+            writeln!(out, "Unreachable").unwrap();
+            for b in unreachable {
+                writeln!(out, "    {}", self.block_summary(b)).unwrap();
+            }
+        }
+        out
+    }
+
+    fn block_summary(&self, b: usize) -> String {
+        let block = &self.blocks[b];
+        let mut out = format!("block {:#06x}-{:#06x}", block.start * 4, (block.end - 1) * 4);
+        if let Some(l) = self.names.get(&block.start) {
+            write!(out, " {}", l).unwrap();
+        }
+        let lines = (pc_to_line(&self.program.line_map, block.start),
+                     pc_to_line(&self.program.line_map, block.end - 1));
+        match lines {
+            (Some(a), Some(b)) if a == b => write!(out, ", line {}", a).unwrap(),
+            (Some(a), Some(b)) => write!(out, ", lines {}-{}", a, b).unwrap(),
+            _ => (),
+        }
+        if !block.successors.is_empty() {
+            let next: Vec<String> = block.successors.iter().map(|&s| self.describe(s)).collect();
+            write!(out, " -> {}", next.join(", ")).unwrap();
+        }
+        if let Some(c) = block.call {
+            match self.names.get(&c) {
+                Some(l) => write!(out, ", calls {}", l).unwrap(),
+                None => write!(out, ", calls {:#x}", c * 4).unwrap(),
+            }
+        }
+        out
+    }
+}
+
+pub fn plural(n: usize, what: &str) -> String {
+    format!("{} {}{}", n, what, if n == 1 { "" } else { "s" })
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Returns the blocks reachable from `entry`, in the order they are found.
+fn reachable(blocks: &[Block], entry: usize) -> Vec<usize> {
+    let mut seen = vec![false; blocks.len()];
+    let mut order = Vec::new();
+    let mut stack = vec![entry];
+    while let Some(b) = stack.pop() {
+        if seen[b] {
+            continue;
+        }
+        seen[b] = true;
+        order.push(b);
+        stack.extend(blocks[b].successors.iter().rev());
+    }
+    order
+}
+
+/// Finds the natural loops among `nodes`, which are the blocks reachable from `entry`. Loops with
+/// the same header are merged.
+fn loops(blocks: &[Block], entry: usize, nodes: &[usize]) -> Vec<Loop> {
+    let dominators = dominators(blocks, entry, nodes);
+    let mut bodies: Vec<(usize, BTreeSet<usize>)> = Vec::new();
+    for &n in nodes {
+        for &h in &blocks[n].successors {
+            if !dominators[&n].contains(&h) {
+                continue;
+            }
+            // `n -> h` is a back edge: walk backwards from `n` until reaching the header.
+            let mut body: BTreeSet<usize> = [h].iter().cloned().collect();
+            let mut stack = vec![n];
+            while let Some(b) = stack.pop() {
+                if body.insert(b) {
+                    stack.extend(nodes.iter().filter(|&&p| blocks[p].successors.contains(&b)));
+                }
+            }
+            match bodies.iter_mut().find(|(header, _)| *header == h) {
+                Some((_, existing)) => existing.extend(body),
+                None => bodies.push((h, body)),
+            }
+        }
+    }
+
+    let mut loops: Vec<Loop> = bodies.iter().map(|(h, body)| Loop {
+        header: *h,
+        blocks: body.iter().cloned().collect(),
+        depth: 1 + bodies.iter().filter(|(o, other)| o != h && other.is_superset(body)).count(),
+    }).collect();
+    loops.sort_by_key(|l| (blocks[l.header].start, l.depth));
+    loops
+}
+
+/// Returns the blocks that dominate each of `nodes`, including the block itself.
+fn dominators(blocks: &[Block], entry: usize, nodes: &[usize]) -> HashMap<usize, BTreeSet<usize>> {
+    let all: BTreeSet<usize> = nodes.iter().cloned().collect();
+    let mut dom: HashMap<usize, BTreeSet<usize>> = nodes.iter().map(|&n| (n, all.clone())).collect();
+    dom.insert(entry, [entry].iter().cloned().collect());
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &n in nodes.iter().filter(|&&n| n != entry) {
+            let mut new: Option<BTreeSet<usize>> = None;
+            for p in nodes.iter().filter(|&&p| blocks[p].successors.contains(&n)) {
+                new = Some(match new {
+                    Some(d) => d.intersection(&dom[p]).cloned().collect(),
+                    None => dom[p].clone(),
+                });
+            }
+            let mut new = new.unwrap_or_default();
+            new.insert(n);
+            if new != dom[&n] {
+                dom.insert(n, new);
+                changed = true;
+            }
+        }
+    }
+    dom
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assemble::assemble;
+    use tokenizer::Tokenizer;
+
+    #[test]
+    fn finds_procedures_and_nested_loops() {
+        let source = "
+            ADDI X0, XZR, #2
+        outer:
+            ADDI X1, XZR, #2
+        inner:
+            SUBI X1, X1, #1
+            CBNZ X1, inner
+            BL f
+            SUBI X0, X0, #1
+            CBNZ X0, outer
+            HALT
+        f:  BR LR
+        ";
+        let (tokens, _) = Tokenizer::tokenize(source);
+        let program = assemble(tokens).unwrap();
+        let cfg = Cfg::new(&program);
+
+        assert_eq!(cfg.blocks.iter().map(|b| b.start).collect::<Vec<_>>(), vec![0, 1, 2, 4, 5, 7, 8]);
+        assert_eq!(cfg.procedures.len(), 2);
+        assert_eq!(cfg.procedures[1].name, "f");
+        let loops: Vec<_> = cfg.procedures[0].loops.iter().map(|l| (l.header, l.blocks.len(), l.depth)).collect();
+        assert_eq!(loops, vec![(1, 4, 1), (2, 1, 2)]);
+        assert!(cfg.unreachable().is_empty());
+        assert!(cfg.dot().contains("b3 -> b6 [style=dashed, label=\"call\"];"));
+    }
+}
@@ -0,0 +1,223 @@
+use assemble::pc_to_line;
+use bytecode::Opcode;
+use disassemble::target;
+
+use std::fmt::Write;
+
+/// Records which instructions executed and which directions each conditional branch took.
+pub struct Coverage {
+    code: Vec<Opcode>,
+    line_map: Vec<usize>,
+    hits: Vec<usize>,
+    taken: Vec<usize>,
+    not_taken: Vec<usize>,
+}
+
+impl Coverage {
+    pub fn new(code: Vec<Opcode>, line_map: Vec<usize>) -> Self {
+        let len = code.len();
+        Coverage {
+            code,
+            line_map,
+            hits: vec![0; len],
+            taken: vec![0; len],
+            not_taken: vec![0; len],
+        }
+    }
+
+    /// Records that the instruction at `pc` executed and moved the PC to `next`.
+    pub fn record(&mut self, pc: usize, next: usize) {
+        if pc >= self.hits.len() {
+            return;
+        }
+        self.hits[pc] += 1;
+        if Self::is_conditional(self.code[pc]) {
+            if next == pc + 1 {
+                self.not_taken[pc] += 1;
+            } else {
+                self.taken[pc] += 1;
+            }
+        }
+    }
+
+    fn is_conditional(op: Opcode) -> bool {
+        use bytecode::Instruction::*;
+        matches!(op.decode(), Some(Cbz | Cbnz | Beq | Bne | Bhs | Blo | Bmi | Bpl | Bvs | Bvc | Bhi |
+                 Bls | Bgt | Bge | Blt | Ble))
+    }
+
+    /// Returns the instructions on each source line, indexed by line number - 1.
+    fn lines(&self, len: usize) -> Vec<Vec<usize>> {
+        let mut lines = vec![Vec::new(); len];
+        for pc in 0..self.code.len() {
+            if let Some(l) = pc_to_line(&self.line_map, pc) {
+                if l <= len {
+                    lines[l-1].push(pc);
+                }
+            }
+        }
+        lines
+    }
+
+    /// Returns (lines found, lines hit, branches found, branches hit).
+    fn totals(&self) -> (usize, usize, usize, usize) {
+        let mut lines = Vec::new();
+        let mut lines_hit = Vec::new();
+        let mut branches = 0;
+        let mut branches_hit = 0;
+        for pc in 0..self.code.len() {
+            let l = pc_to_line(&self.line_map, pc);
+            if !lines.contains(&l) {
+                lines.push(l);
+            }
+            if self.hits[pc] > 0 && !lines_hit.contains(&l) {
+                lines_hit.push(l);
+            }
+            if Self::is_conditional(self.code[pc]) {
+                branches += 2;
+                branches_hit += (self.taken[pc] > 0) as usize + (self.not_taken[pc] > 0) as usize;
+            }
+        }
+        (lines.len(), lines_hit.len(), branches, branches_hit)
+    }
+
+    fn percent(hit: usize, found: usize) -> f64 {
+        if found == 0 {
+            100.0
+        } else {
+            hit as f64 * 100.0 / found as f64
+        }
+    }
+
+    /// Prints the source annotated with execution counts, followed by the summary. Lines that
+    /// contain instructions which never executed are marked with `#####`. Without the source, as
+    /// when running an executable, each instruction is listed by address instead.
+    pub fn report(&self, source: Option<&str>) {
+        println!("\nCoverage:");
+        let source: Vec<&str> = match source {
+            Some(source) => source.lines().collect(),
+            None => {
+                for (pc, &op) in self.code.iter().enumerate() {
+                    let text = match target(op, pc) {
+                        Some(t) => op.print_branch_label(&format!("{:08x}", t * 4)),
+                        None => op.to_string(),
+                    };
+                    println!("{:>10} | {:08x}  {}{}", Self::count(self.hits[pc]), pc * 4, text, self.branches(&[pc]));
+                }
+                return self.summary();
+            }
+        };
+        let lines = self.lines(source.len());
+        for (i, line) in source.iter().enumerate() {
+            let pcs = &lines[i];
+            if pcs.is_empty() {
+                println!("{:>10} | {}", "", line);
+                continue;
+            }
+            let hits = pcs.iter().map(|&pc| self.hits[pc]).sum();
+            println!("{:>10} | {}{}", Self::count(hits), line, self.branches(pcs));
+        }
+        self.summary();
+    }
+
+    fn count(hits: usize) -> String {
+        if hits == 0 { "#####".to_string() } else { hits.to_string() }
+    }
+
+    /// Returns the directions taken by the conditional branches among `pcs`.
+    fn branches(&self, pcs: &[usize]) -> String {
+        let mut branches = String::new();
+        for &pc in pcs {
+            if Self::is_conditional(self.code[pc]) {
+                write!(branches, "  [taken {}, not taken {}]", self.taken[pc], self.not_taken[pc]).unwrap();
+            }
+        }
+        branches
+    }
+
+    fn summary(&self) {
+        let (lf, lh, bf, bh) = self.totals();
+        println!("\nLines executed: {:.2}% of {}", Self::percent(lh, lf), lf);
+        println!("Branch outcomes covered: {:.2}% of {}", Self::percent(bh, bf), bf);
+    }
+
+    /// Returns the coverage data as an lcov tracefile for the source file `filename`.
+    pub fn lcov(&self, filename: &str) -> String {
+        let mut out = String::new();
+        writeln!(out, "TN:").unwrap();
+        writeln!(out, "SF:{}", filename).unwrap();
+
+        let len = self.line_map.len();
+        let lines = self.lines(len);
+        for (i, pcs) in lines.iter().enumerate() {
+            if pcs.is_empty() {
+                continue;
+            }
+            let hits: usize = pcs.iter().map(|&pc| self.hits[pc]).sum();
+            for &pc in pcs {
+                if !Self::is_conditional(self.code[pc]) {
+                    continue;
+                }
+                for (b, &c) in [self.taken[pc], self.not_taken[pc]].iter().enumerate() {
+                    if hits == 0 {
+                        writeln!(out, "BRDA:{},{},{},-", i + 1, pc, b).unwrap();
+                    } else {
+                        writeln!(out, "BRDA:{},{},{},{}", i + 1, pc, b, c).unwrap();
+                    }
+                }
+            }
+            writeln!(out, "DA:{},{}", i + 1, hits).unwrap();
+        }
+
+        let (lf, lh, bf, bh) = self.totals();
+        writeln!(out, "BRF:{}", bf).unwrap();
+        writeln!(out, "BRH:{}", bh).unwrap();
+        writeln!(out, "LF:{}", lf).unwrap();
+        writeln!(out, "LH:{}", lh).unwrap();
+        writeln!(out, "end_of_record").unwrap();
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assemble::assemble;
+    use tokenizer::Tokenizer;
+    use vm::VM;
+
+    #[test]
+    fn counts_branch_directions() {
+        let (tokens, _) = Tokenizer::tokenize("ADDI X0, XZR, #2\nloop: CBZ X0, done\nSUBI X0, X0, #1\nB loop\n\
+                                               done: HALT\nADDI X1, X1, #1\n");
+        let program = assemble(tokens).unwrap();
+        let mut vm = VM::new();
+        vm.set_trace(false);
+        vm.load_coverage(Coverage::new(program.code.clone(), program.line_map.clone()));
+        vm.load_code(program.code, 0);
+        vm.run();
+
+        let c = vm.coverage().unwrap();
+        assert_eq!((c.taken[1], c.not_taken[1]), (1, 2));
+        assert_eq!(c.totals(), (6, 5, 2, 2));
+        assert_eq!(c.lcov("t.s"), "TN:\nSF:t.s\nDA:1,1\nBRDA:2,1,0,1\nBRDA:2,1,1,2\nDA:2,3\nDA:3,2\nDA:4,2\n\
+                                   DA:5,1\nDA:6,0\nBRF:2\nBRH:2\nLF:6\nLH:5\nend_of_record\n");
+    }
+
+    #[test]
+    fn counts_invalid_words_as_instructions() {
+        let (tokens, _) = Tokenizer::tokenize("CBZ XZR, skip\n.word 0\nskip: ADDI X0, XZR, #1\n.word 0\n");
+        let program = assemble(tokens).unwrap();
+        let mut vm = VM::new();
+        vm.set_trace(false);
+        vm.load_coverage(Coverage::new(program.code.clone(), program.line_map.clone()));
+        vm.load_code(program.code, 0);
+        vm.run();
+        assert!(vm.fault().is_some());
+
+        let c = vm.coverage().unwrap();
+        assert_eq!(c.totals(), (4, 2, 2, 1));
+        assert_eq!(c.lcov("t.s"), "TN:\nSF:t.s\nBRDA:1,0,0,1\nBRDA:1,0,1,0\nDA:1,1\nDA:2,0\nDA:3,1\nDA:4,0\n\
+                                   BRF:2\nBRH:1\nLF:4\nLH:2\nend_of_record\n");
+    }
+}
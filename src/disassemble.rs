@@ -1,65 +1,227 @@
+use assemble::Program;
 use bytecode::Opcode;
+use listing::label_names;
 
 use std::collections::HashMap;
 
-pub fn disassemble(code: Vec<Opcode>) -> Vec<String> {
-    let mut out = Vec::new();
-    let mut jumps = HashMap::new();
-    let mut ln = 0;
+/// Disassembles `program`, adding a label for every branch target. Targets without a name in
+/// `program.labels` are named `label0`, `label1`, ... in order of address. If `pseudo` is set,
+/// instruction sequences that the assembler produces for pseudo-instructions are shown as the
+/// pseudo-instruction instead. If `addresses` is set, each instruction is preceded by its byte
+/// address and encoding. The data section, if any, is shown first.
+///
+/// Words that are not valid instructions, and branches to addresses outside the program, are shown
+/// as `.word` so that the output can be assembled again.
+pub fn disassemble(program: &Program, pseudo: bool, addresses: bool) -> Vec<String> {
+    let code = &program.code;
+    let mut out = data(program);
+    let names = names(program);
+    let label_indent = if addresses { " ".repeat(20) } else { String::new() };
+
     let mut pc = 0;
+    while pc <= code.len() {
+        if let Some(l) = names.get(&pc) {
+            out.push(format!("{}{}:", label_indent, l));
+        }
+        if pc == code.len() {
+            break;
+        }
 
-    for op in code {
-        use bytecode::Instruction::*;
-        match op.instruction() {
-            B | Bl | Beq | Bne | Bhs | Blo | Bmi | Bpl | Bvs | Bvc | Bhi | Bls |
-            Bge | Blt | Bgt | Ble | Cbz | Cbnz => {
-                let label = get_label(op, &mut pc, &mut ln, &mut jumps);
-                out.push(op.print_branch_label(&label));
+        let op = code[pc];
+        let sugared = if pseudo && target(op, pc).is_none() && op.is_canonical() {
+            sugar(&code[pc..], &names, pc)
+        } else {
+            None
+        };
+        let (text, len) = sugared.unwrap_or_else(|| (text(op, pc, &names), 1));
+        if addresses {
+            out.push(format!("{:08x}  {:08x}  {}", pc * 4, op.0, text));
+            // The other words of a pseudo-instruction are shown without any text.
+            for (i, op) in code.iter().enumerate().take(pc + len).skip(pc + 1) {
+                out.push(format!("{:08x}  {:08x}", i * 4, op.0));
             }
-            _ => out.push(op.to_string()),
+        } else {
+            out.push(text);
         }
-        pc += 1;
+        pc += len;
+    }
+
+    out
+}
+
+/// Returns the assembly for the instruction `op` at `pc`, using `names` for branch targets.
+pub fn text(op: Opcode, pc: usize, names: &HashMap<usize, String>) -> String {
+    // Words whose disassembly wouldn't assemble back to the same word are kept as they are.
+    match op.decode() {
+        Some(i) if !i.is_supported() => return op.to_string(),
+        Some(_) if !op.is_canonical() => return format!(".word {:#010x} // {}", op.0, op),
+        _ => (),
+    }
+    match target(op, pc) {
+        Some(t) => match names.get(&t) {
+            Some(l) => op.print_branch_label(l),
+            None => format!(".word {:#010x} // {}, outside the program", op.0,
+                            op.print_branch_label(&signed_hex((t as u32) as i32 as i64 * 4))),
+        },
+        None => op.to_string(),
     }
+}
+
+/// Returns the instruction index that the branch `op` at `pc` jumps to, which may be outside the
+/// program.
+pub fn target(op: Opcode, pc: usize) -> Option<usize> {
+    op.branch_addr().map(|offset| (pc as u32).wrapping_add(offset) as usize)
+}
 
-    let mut i = 0;
-    for (j, l) in jumps {
-        out.insert((j+i) as usize, format!("{}:", l));
-        i += 1;
+fn signed_hex(v: i64) -> String {
+    if v < 0 {
+        format!("-{:#x}", -v)
+    } else {
+        format!("{:#x}", v)
     }
+}
 
+/// Names every instruction index that is labelled in `program` or is the target of a branch. The
+/// index one past the last instruction may also be named.
+pub fn names(program: &Program) -> HashMap<usize, String> {
+    let mut names: HashMap<usize, String> = label_names(&program.labels).into_iter()
+        .filter(|&(i, _)| i <= program.code.len())
+        .map(|(i, l)| (i, l.clone()))
+        .collect();
+
+    let mut targets: Vec<usize> = program.code.iter().enumerate()
+        .filter_map(|(pc, &op)| target(op, pc))
+        .filter(|&t| t <= program.code.len() && !names.contains_key(&t))
+        .collect();
+    targets.sort();
+    targets.dedup();
+
+    let mut n = 0;
+    for t in targets {
+        // Skip names that are already used by the program's own labels.
+        while program.labels.contains_key(&format!("label{}", n)) {
+            n += 1;
+        }
+        names.insert(t, format!("label{}", n));
+        n += 1;
+    }
+    names
+}
+
+/// Shows the data section as `.dword`s, with `.byte`s for any bytes after the last whole
+/// doubleword.
+fn data(program: &Program) -> Vec<String> {
+    let mut out = Vec::new();
+    if program.data.is_empty() {
+        return out;
+    }
+
+    let mut labels: Vec<_> = program.data_labels.iter().map(|(l, &a)| (a, l)).collect();
+    labels.sort();
+    let mut labels = labels.into_iter().peekable();
+    out.push(".data".to_string());
+    for (i, chunk) in program.data.chunks(8).enumerate() {
+        let addr = i as u64 * 8;
+        // Labels inside a doubleword can't be shown, so they are placed before it.
+        while let Some((_, l)) = labels.next_if(|&(a, _)| a < addr + 8) {
+            out.push(format!("{}:", l));
+        }
+        if chunk.len() == 8 {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(chunk);
+            out.push(format!(".dword {:#x}", u64::from_le_bytes(bytes)));
+        } else {
+            let bytes: Vec<String> = chunk.iter().map(|b| b.to_string()).collect();
+            out.push(format!(".byte {}", bytes.join(", ")));
+        }
+    }
+    for (_, l) in labels {
+        out.push(format!("{}:", l));
+    }
+    out.push(".text".to_string());
     out
 }
 
-fn get_label(op: Opcode, pc: &mut u32, ln: &mut usize, jumps: &mut HashMap<u32, String>) -> String {
+/// Returns the pseudo-instruction that the instructions at the start of `code` implement along
+/// with the number of instructions it covers. Sequences are not combined across a branch target.
+fn sugar(code: &[Opcode], names: &HashMap<usize, String>, pc: usize) -> Option<(String, usize)> {
     use bytecode::Instruction::*;
-    let addr = *pc + match op.instruction() {
-        B => op.b_addr(),
-        Bl => op.bl_addr(),
-        Cbz => op.cbz_addr(),
-        Cbnz => op.cbnz_addr(),
-        Beq => op.beq_addr(),
-        Bne => op.bne_addr(),
-        Bhs => op.bhs_addr(),
-        Blo => op.blo_addr(),
-        Bmi => op.bmi_addr(),
-        Bpl => op.bpl_addr(),
-        Bvs => op.bvs_addr(),
-        Bvc => op.bvc_addr(),
-        Bhi => op.bhi_addr(),
-        Bls => op.bls_addr(),
-        Bgt => op.bgt_addr(),
-        Bge => op.bge_addr(),
-        Blt => op.blt_addr(),
-        Ble => op.ble_addr(),
-        _ => unreachable!(),
-    };
-
-    if let Some(l) = jumps.get(&addr) {
-        l.clone()
-    } else {
-        let label = format!("label{}", ln);
-        jumps.insert(addr, label.clone());
-        *ln += 1;
-        label
+    let op = code[0];
+    Some((match op.decode()? {
+        Orr if *op.orr_rd() == 31 && *op.orr_rn() == 31 && *op.orr_rm() == 31 => "NOP".to_string(),
+        Orr if *op.orr_rn() == 31 => format!("MOV {}, {}", op.orr_rd(), op.orr_rm()),
+        Subs if *op.subs_rd() == 31 => format!("CMP {}, {}", op.subs_rn(), op.subs_rm()),
+        Subis if *op.subis_rd() == 31 => format!("CMPI {}, #{}", op.subis_rn(), op.subis_imm()),
+        Addis if *op.addis_rd() == 31 => format!("CMPI {}, #-{}", op.addis_rn(), op.addis_imm()),
+        Sub if *op.sub_rn() == 31 => format!("NEG {}, {}", op.sub_rd(), op.sub_rm()),
+        Br if *op.br_rt() == 30 => "RET".to_string(),
+        Movz => {
+            let rd = op.movz_rd();
+            let mut value = (op.movz_imm() as u64) << op.movz_shift();
+            let mut shift = op.movz_shift();
+            let mut len = 1;
+            for &next in &code[1..] {
+                if next.decode() != Some(Movk) || !next.is_canonical() || *next.movk_rd() != *rd || next.movk_shift() <= shift ||
+                    names.contains_key(&(pc + len)) {
+                    break;
+                }
+                shift = next.movk_shift();
+                value |= (next.movk_imm() as u64) << shift;
+                len += 1;
+            }
+            if len == 1 {
+                return None;
+            }
+            return Some((format!("MOVI {}, #{:#x}", rd, value), len));
+        }
+        _ => return None,
+    }, 1))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assemble::assemble;
+    use bytecode::test::{random_opcode, Rng};
+    use tokenizer::Tokenizer;
+    use Register;
+
+    #[test]
+    fn handles_invalid_words_and_targets() {
+        let code = vec![
+            Opcode::Cbz(Register(0), 3),
+            Opcode(0xdeadbeef),
+            Opcode::B(100),
+            Opcode::B(-1i32 as u32),
+        ];
+        let program = Program { code, ..Program::default() };
+        assert_eq!(disassemble(&program, false, false), vec![
+            "CBZ X0, label1",
+            ".word 0xdeadbeef",
+            "label0:",
+            ".word 0x14000064 // B 0x198, outside the program",
+            "label1:",
+            "B label0",
+        ]);
+        assert_eq!(disassemble(&program, false, true)[1], "00000004  deadbeef  .word 0xdeadbeef");
+    }
+
+    #[test]
+    fn disassembly_assembles_to_the_same_words() {
+        let mut rng = Rng::new(7);
+        for _ in 0..50 {
+            let len = 1 + rng.below(64) as usize;
+            let code: Vec<Opcode> = (0..len)
+                .map(|_| if rng.below(8) == 0 { Opcode(rng.next() as u32) } else { random_opcode(&mut rng, 80) })
+                .collect();
+            let program = Program { code, ..Program::default() };
+            for &pseudo in &[false, true] {
+                let source = disassemble(&program, pseudo, false).join("\n");
+                let (tokens, errors) = Tokenizer::tokenize(&source);
+                assert!(errors.is_empty(), "{}", source);
+                let assembled = assemble(tokens).unwrap_or_else(|e| panic!("{:?}\n{}", e, source));
+                assert_eq!(assembled.code, program.code, "{}", source);
+            }
+        }
     }
 }
@@ -0,0 +1,232 @@
+use cfg::plural;
+use register::Register;
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// The ways the state of the machine can be shown.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DumpFormat {
+    /// Every register and doubleword of memory, as `DUMP` shows them.
+    Full,
+    Json,
+    /// A hexdump in which runs of identical lines are replaced by a `*`.
+    Compact,
+    /// Only what changed since the previous stop.
+    Diff,
+}
+
+/// The values accepted by `--dump` and the debugger's `d`.
+pub const DUMP_FORMATS: [&str; 4] = ["full", "json", "compact", "diff"];
+
+impl DumpFormat {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "full" => Some(DumpFormat::Full),
+            "json" => Some(DumpFormat::Json),
+            "compact" => Some(DumpFormat::Compact),
+            "diff" => Some(DumpFormat::Diff),
+            _ => None,
+        }
+    }
+}
+
+/// A copy of the state of the machine, taken when it stops.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    pub registers: [u64; 32],
+    pub flags: u64,
+    /// The instruction index about to be executed.
+    pub pc: usize,
+    pub stack: Vec<u64>,
+    pub heap: Vec<u64>,
+    pub steps: usize,
+    pub loads: usize,
+    pub stores: usize,
+}
+
+#[derive(Serialize)]
+struct Range {
+    address: usize,
+    values: Vec<u64>,
+}
+
+#[derive(Serialize)]
+struct Statistics {
+    instructions: usize,
+    loads: usize,
+    stores: usize,
+}
+
+#[derive(Serialize)]
+struct Json {
+    /// X0 to X30 followed by XZR.
+    registers: Vec<u64>,
+    flags: u64,
+    /// The byte address of the next instruction.
+    pc: usize,
+    stack: Vec<Range>,
+    memory: Vec<Range>,
+    /// The byte address of each data label.
+    labels: HashMap<String, u64>,
+    statistics: Statistics,
+}
+
+/// Returns the runs of non-zero doublewords in `words` along with their byte addresses.
+fn ranges(words: &[u64]) -> Vec<Range> {
+    let mut ranges: Vec<Range> = Vec::new();
+    for (i, &w) in words.iter().enumerate() {
+        if w == 0 {
+            continue;
+        }
+        match ranges.last_mut() {
+            Some(r) if r.address + r.values.len() * 8 == i * 8 => r.values.push(w),
+            _ => ranges.push(Range { address: i * 8, values: vec![w] }),
+        }
+    }
+    ranges
+}
+
+impl Snapshot {
+    /// Returns the registers, flags, non-zero memory and statistics as a JSON object.
+    pub fn json(&self, labels: &HashMap<String, u64>) -> String {
+        let json = Json {
+            registers: self.registers.to_vec(),
+            flags: self.flags,
+            pc: self.pc * 4,
+            stack: ranges(&self.stack),
+            memory: ranges(&self.heap),
+            labels: labels.clone(),
+            statistics: Statistics { instructions: self.steps, loads: self.loads, stores: self.stores },
+        };
+        serde_json::to_string_pretty(&json).unwrap() + "\n"
+    }
+
+    /// Returns the registers and a hexdump of memory in which runs of identical lines are shown
+    /// once followed by a `*`, like `hexdump -C`.
+    pub fn compact(&self, labels: &HashMap<String, u64>) -> String {
+        let mut out = String::new();
+        for (i, v) in self.registers.iter().enumerate() {
+            let sep = if i % 4 == 3 { "\n" } else { "  " };
+            write!(out, "{:>4} {:016x}{}", Register(i as u8).to_string(), v, sep).unwrap();
+        }
+        writeln!(out, "flags {:016x}  pc {:08x}", self.flags, self.pc * 4).unwrap();
+        writeln!(out, "\nStack:").unwrap();
+        hexdump(&mut out, &self.stack);
+        writeln!(out, "\nMain memory:").unwrap();
+        hexdump(&mut out, &self.heap);
+        let mut labels: Vec<_> = labels.iter().collect();
+        labels.sort_by_key(|&(l, &a)| (a, l));
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels.iter().map(|(l, a)| format!("{}@{:x}", l, a)).collect();
+            writeln!(out, "Labels: {}", labels.join(" ")).unwrap();
+        }
+        writeln!(out, "\n{}, {}, {}", plural(self.steps, "instruction"), plural(self.loads, "load"),
+                 plural(self.stores, "store")).unwrap();
+        out
+    }
+
+    /// Returns the registers, flags and doublewords of memory that differ from `before`.
+    pub fn diff(&self, before: &Snapshot) -> String {
+        let mut out = String::new();
+        for (i, (&old, &new)) in before.registers.iter().zip(&self.registers).enumerate() {
+            if old != new {
+                writeln!(out, "{:>5}: {:#x} -> {:#x} ({})", Register(i as u8).to_string(), old, new, new as i64)
+                    .unwrap();
+            }
+        }
+        if before.flags != self.flags {
+            writeln!(out, "flags: {:#x} -> {:#x}", before.flags, self.flags).unwrap();
+        }
+        for (name, old, new) in &[("stack", &before.stack, &self.stack), ("memory", &before.heap, &self.heap)] {
+            for (i, (&o, &n)) in old.iter().zip(new.iter()).enumerate() {
+                if o != n {
+                    writeln!(out, "{} {:#x}: {:#x} -> {:#x} ({})", name, i * 8, o, n, n as i64).unwrap();
+                }
+            }
+        }
+        if out.is_empty() {
+            out.push_str("No changes\n");
+        }
+        writeln!(out, "{} since the previous stop, pc {:08x} -> {:08x}",
+                 plural(self.steps.saturating_sub(before.steps), "instruction"), before.pc * 4, self.pc * 4).unwrap();
+        out
+    }
+}
+
+/// Writes `words` 16 bytes to a line, showing a run of lines identical to the one before as `*`.
+fn hexdump(out: &mut String, words: &[u64]) {
+    let mut previous: Option<&[u64]> = None;
+    let mut starred = false;
+    for (i, line) in words.chunks(2).enumerate() {
+        if previous == Some(line) {
+            if !starred {
+                out.push_str("*\n");
+                starred = true;
+            }
+            continue;
+        }
+        previous = Some(line);
+        starred = false;
+
+        let bytes: Vec<u8> = line.iter().flat_map(|w| w.to_le_bytes().to_vec()).collect();
+        write!(out, "{:08x} ", i * 16).unwrap();
+        for (j, b) in bytes.iter().enumerate() {
+            let sep = if j == 8 { "  " } else { " " };
+            write!(out, "{}{:02x}", sep, b).unwrap();
+        }
+        let text: String = bytes.iter().map(|&b| if (32..=126).contains(&b) { b as char } else { '.' }).collect();
+        writeln!(out, "  |{}|", text).unwrap();
+    }
+    writeln!(out, "{:08x}", words.len() * 8).unwrap();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn snapshot() -> Snapshot {
+        Snapshot {
+            registers: [0; 32],
+            flags: 0,
+            pc: 0,
+            stack: vec![0; 4],
+            heap: vec![0; 8],
+            steps: 0,
+            loads: 0,
+            stores: 0,
+        }
+    }
+
+    #[test]
+    fn collapses_repeated_lines() {
+        let mut s = snapshot();
+        s.heap[6] = 0x41;
+        let mut out = String::new();
+        hexdump(&mut out, &s.heap);
+        assert_eq!(out, "\
+00000000  00 00 00 00 00 00 00 00  00 00 00 00 00 00 00 00  |................|
+*
+00000030  41 00 00 00 00 00 00 00  00 00 00 00 00 00 00 00  |A...............|
+00000040
+");
+    }
+
+    #[test]
+    fn shows_changes_and_ranges() {
+        let before = snapshot();
+        let mut after = snapshot();
+        after.registers[1] = 5;
+        after.heap[2] = 7;
+        after.heap[3] = 8;
+        after.heap[6] = 9;
+        after.steps = 3;
+        after.pc = 3;
+        assert_eq!(after.diff(&before), "   X1: 0x0 -> 0x5 (5)\nmemory 0x10: 0x0 -> 0x7 (7)\n\
+                                         memory 0x18: 0x0 -> 0x8 (8)\nmemory 0x30: 0x0 -> 0x9 (9)\n\
+                                         3 instructions since the previous stop, pc 00000000 -> 0000000c\n");
+        let r = ranges(&after.heap);
+        assert_eq!(r.iter().map(|r| (r.address, r.values.clone())).collect::<Vec<_>>(),
+                   vec![(0x10, vec![7, 8]), (0x30, vec![9])]);
+    }
+}
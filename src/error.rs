@@ -0,0 +1,89 @@
+use std::fmt;
+use std::fs;
+
+/// A region of a single source line. Lines and columns start at 1.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub len: usize,
+    /// 0 for the file being assembled, otherwise the number of the file it includes, counting
+    /// from 1 in the order they are read.
+    pub file: usize,
+}
+
+impl Span {
+    pub fn new(line: usize, column: usize, len: usize) -> Self {
+        Span { line, column, len, file: 0 }
+    }
+
+    /// Returns the position just after the span, for pointing at something that is missing.
+    pub fn after(self) -> Span {
+        Span { column: self.column + self.len, len: 1, ..self }
+    }
+
+    /// Returns true if `other` is on the same line of the same file.
+    pub fn same_line(self, other: Span) -> bool {
+        self.file == other.file && self.line == other.line
+    }
+}
+
+/// An error in the assembly source, reported by either the tokenizer or the assembler.
+#[derive(Clone, Debug)]
+pub struct AssembleError {
+    /// The file the error is in. Errors in the file being assembled leave it empty until they are
+    /// reported, errors in included files name the included file.
+    pub file: String,
+    pub span: Span,
+    pub message: String,
+}
+
+impl AssembleError {
+    pub fn new<S: Into<String>>(span: Span, message: S) -> Self {
+        AssembleError {
+            file: String::new(),
+            span,
+            message: message.into(),
+        }
+    }
+
+    /// Formats the error followed by the offending source line with the span underlined.
+    pub fn render(&self, source: &str) -> String {
+        let line = source.lines().nth(self.span.line - 1).unwrap_or("");
+        let number = self.span.line.to_string();
+        let pad = " ".repeat(number.len());
+        // Tabs are kept so that the carets line up with the source when printed.
+        let indent: String = line.chars().take(self.span.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        format!("error: {}\n{}--> {}:{}:{}\n{} |\n{} | {}\n{} | {}{}\n",
+                self.message, pad, self.file, self.span.line, self.span.column,
+                pad, number, line, pad, indent, "^".repeat(self.span.len.max(1)))
+    }
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}: {}", self.file, self.span.line, self.span.column, self.message)
+    }
+}
+
+/// Prints every error in `errors` with a source snippet to stderr, in source order. `source` is the
+/// contents of `file`; errors in included files are shown with a snippet of the included file.
+pub fn report(file: &str, source: &str, mut errors: Vec<AssembleError>) {
+    errors.sort_by_key(|e| (e.span.file, e.span.line, e.span.column));
+    for e in errors.iter_mut() {
+        eprintln!("{}", render_in(e, file, source));
+    }
+    eprintln!("{} error{} found in {}", errors.len(), if errors.len() == 1 { "" } else { "s" }, file);
+}
+
+/// Renders `e` with a source snippet, naming `file` if the error is in the file being assembled
+/// and reading the included file otherwise.
+pub fn render_in(e: &mut AssembleError, file: &str, source: &str) -> String {
+    if e.file.is_empty() {
+        e.file = file.to_string();
+        return e.render(source);
+    }
+    e.render(&fs::read_to_string(&e.file).unwrap_or_default())
+}
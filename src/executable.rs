@@ -0,0 +1,169 @@
+use assemble::Program;
+use bytecode::Opcode;
+use vm::HEAP_SIZE;
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use std::collections::HashMap;
+use std::io::{self, Cursor, Read};
+
+/// The first bytes of every executable.
+pub const MAGIC: &[u8; 8] = b"LEGv8EXE";
+const VERSION: u8 = 1;
+
+/// Returns true if `buf` holds an executable.
+pub fn is_executable(buf: &[u8]) -> bool {
+    buf.starts_with(MAGIC)
+}
+
+/// Serializes an assembled program so that it can be run or disassembled without its source.
+///
+/// After the magic number come the version and a flag that is 1 if the rest of the file is
+/// little-endian and 0 if it is big-endian, then two reserved bytes. These are followed by the
+/// entry point, the text section, the data section, the symbol table and the line map, each of
+/// which starts with a 32-bit count.
+pub fn write(program: &Program, le: bool) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&[VERSION, le as u8, 0, 0]);
+    if le {
+        write_body::<LittleEndian>(&mut out, program);
+    } else {
+        write_body::<BigEndian>(&mut out, program);
+    }
+    out
+}
+
+fn write_body<B: ByteOrder>(out: &mut Vec<u8>, program: &Program) {
+    out.write_u32::<B>(program.entry as u32).unwrap();
+    out.write_u32::<B>(program.code.len() as u32).unwrap();
+    for op in &program.code {
+        out.write_u32::<B>(op.0).unwrap();
+    }
+    out.write_u32::<B>(program.data.len() as u32).unwrap();
+    out.extend_from_slice(&program.data);
+
+    // Sorted so that assembling the same source always produces the same file.
+    let mut symbols: Vec<_> = program.labels.iter().map(|(l, &i)| (l, 0, i as u64))
+        .chain(program.data_labels.iter().map(|(l, &a)| (l, 1, a)))
+        .collect();
+    symbols.sort();
+    out.write_u32::<B>(symbols.len() as u32).unwrap();
+    for (l, section, value) in symbols {
+        out.write_u8(section).unwrap();
+        out.write_u64::<B>(value).unwrap();
+        out.write_u32::<B>(l.len() as u32).unwrap();
+        out.extend_from_slice(l.as_bytes());
+    }
+
+    out.write_u32::<B>(program.line_map.len() as u32).unwrap();
+    for &i in &program.line_map {
+        out.write_u32::<B>(i as u32).unwrap();
+    }
+}
+
+/// Reads an executable written by `write`.
+pub fn read(buf: &[u8]) -> Result<Program, String> {
+    if !is_executable(buf) || buf.len() < MAGIC.len() + 4 {
+        return Err("not an executable".to_string());
+    }
+    let header = &buf[MAGIC.len()..MAGIC.len() + 4];
+    if header[0] != VERSION {
+        return Err(format!("unsupported executable version {}", header[0]));
+    }
+    let mut rdr = Cursor::new(&buf[MAGIC.len() + 4..]);
+    let program = if header[1] != 0 {
+        read_body::<LittleEndian>(&mut rdr)
+    } else {
+        read_body::<BigEndian>(&mut rdr)
+    };
+    program.map_err(|e| format!("corrupt executable: {}", e))
+}
+
+fn read_body<B: ByteOrder>(rdr: &mut Cursor<&[u8]>) -> io::Result<Program> {
+    let invalid = |m: String| io::Error::new(io::ErrorKind::InvalidData, m);
+
+    let entry = rdr.read_u32::<B>()? as usize;
+    let mut code = Vec::new();
+    for _ in 0..read_count::<B>(rdr, 4)? {
+        code.push(Opcode(rdr.read_u32::<B>()?));
+    }
+    if entry > code.len() {
+        return Err(invalid(format!("entry point {} is outside the text section", entry)));
+    }
+    let mut data = vec![0; read_count::<B>(rdr, 1)?];
+    if data.len() > HEAP_SIZE {
+        return Err(invalid(format!("the data section is {} bytes but memory is only {} bytes",
+                                   data.len(), HEAP_SIZE)));
+    }
+    rdr.read_exact(&mut data)?;
+
+    let mut labels = HashMap::new();
+    let mut data_labels = HashMap::new();
+    // Each symbol is at least a section, a value and the length of its name.
+    for _ in 0..read_count::<B>(rdr, 13)? {
+        let section = rdr.read_u8()?;
+        let value = rdr.read_u64::<B>()?;
+        let mut name = vec![0; read_count::<B>(rdr, 1)?];
+        rdr.read_exact(&mut name)?;
+        let name = String::from_utf8(name).map_err(|e| invalid(e.to_string()))?;
+        if section == 0 {
+            labels.insert(name, value as usize);
+        } else {
+            data_labels.insert(name, value);
+        }
+    }
+
+    let mut line_map = Vec::new();
+    for _ in 0..read_count::<B>(rdr, 4)? {
+        line_map.push(rdr.read_u32::<B>()? as usize);
+    }
+
+    Ok(Program { code, entry, line_map, labels, data, data_labels, ..Program::default() })
+}
+
+/// Reads the count of a list whose items are at least `size` bytes, checking that the rest of the
+/// file is long enough to hold them so that a corrupt count can't cause a huge allocation.
+pub fn read_count<B: ByteOrder>(rdr: &mut Cursor<&[u8]>, size: usize) -> io::Result<usize> {
+    let count = rdr.read_u32::<B>()? as usize;
+    let left = rdr.get_ref().len() - rdr.position() as usize;
+    if count.saturating_mul(size) > left {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!(
+            "a count of {} needs more than the {} bytes left", count, left)));
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assemble::assemble;
+    use tokenizer::Tokenizer;
+
+    #[test]
+    fn round_trips_in_both_byte_orders() {
+        let (tokens, _) = Tokenizer::tokenize(".data\nx: .dword 5\n.text\nf: BR LR\n_start: BL f\n");
+        let program = assemble(tokens).unwrap();
+        for &le in &[true, false] {
+            let read = read(&write(&program, le)).unwrap();
+            assert_eq!(read.code, program.code);
+            assert_eq!(read.entry, 1);
+            assert_eq!(read.data, program.data);
+            assert_eq!(read.labels, program.labels);
+            assert_eq!(read.data_labels, program.data_labels);
+            assert_eq!(read.line_map, program.line_map);
+        }
+        assert!(read(b"LEGv8EXE\x02\x01\0\0").is_err());
+    }
+
+    #[test]
+    fn rejects_oversized_sections() {
+        // A text section of 2^32 - 1 instructions in a file of a few bytes.
+        assert!(read(b"LEGv8EXE\x01\x01\0\0\0\0\0\0\xff\xff\xff\xff").unwrap_err().contains("bytes left"));
+
+        let (tokens, _) = Tokenizer::tokenize("HALT\n");
+        let mut program = assemble(tokens).unwrap();
+        program.data = vec![0; HEAP_SIZE + 8];
+        assert!(read(&write(&program, true)).unwrap_err().contains("memory is only"));
+    }
+}
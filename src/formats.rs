@@ -0,0 +1,422 @@
+use assemble::{pc_to_line, Program, ENTRY_LABEL};
+use bytecode::Opcode;
+use executable;
+use listing;
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+use std::fmt::Write;
+
+/// The most instructions the text formats are read into. Their addresses and repeat counts could
+/// otherwise ask for far more memory than the file itself takes up.
+const MAX_WORDS: usize = 1 << 20;
+
+/// The ways machine code can be written out. Apart from `Executable`, only the text section is
+/// included.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Format {
+    /// The executable container, with the data section, symbols and line map.
+    Executable,
+    /// Bare 32-bit words.
+    Raw,
+    /// Intel HEX records.
+    IntelHex,
+    /// One word per line in hex, for Verilog's `$readmemh`.
+    ReadMemH,
+    /// One word per line in binary, for Verilog's `$readmemb`.
+    ReadMemB,
+    /// A Logisim ROM image.
+    Logisim,
+    /// The listing written by `assemble --listing`.
+    Listing,
+}
+
+impl Format {
+    pub fn from_str(s: &str) -> Option<Self> {
+        Some(match s {
+            "exe" => Format::Executable,
+            "raw" => Format::Raw,
+            "ihex" => Format::IntelHex,
+            "readmemh" => Format::ReadMemH,
+            "readmemb" => Format::ReadMemB,
+            "logisim" => Format::Logisim,
+            "listing" => Format::Listing,
+            _ => return None,
+        })
+    }
+
+    /// The extension of files written in this format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Executable => "exe",
+            Format::Raw => "machine",
+            Format::IntelHex => "hex",
+            Format::ReadMemH => "memh",
+            Format::ReadMemB => "memb",
+            Format::Logisim => "rom",
+            Format::Listing => "lst",
+        }
+    }
+
+    /// Guesses the format of `buf` from its contents.
+    pub fn detect(buf: &[u8]) -> Self {
+        if executable::is_executable(buf) {
+            return Format::Executable;
+        }
+        let text = match std::str::from_utf8(buf) {
+            Ok(text) => text.trim_start(),
+            Err(_) => return Format::Raw,
+        };
+        if text.is_empty() {
+            return Format::Raw;
+        }
+        if text.lines().next() == Some(&listing::header()) {
+            return Format::Listing;
+        }
+        if text.starts_with("v2.0 raw") {
+            return Format::Logisim;
+        }
+        if text.starts_with(':') {
+            return Format::IntelHex;
+        }
+        let mut words = text.lines()
+            .map(|l| l.split("//").next().unwrap().trim())
+            .filter(|l| !l.is_empty() && !l.starts_with('@'));
+        if words.clone().all(|w| w.len() == 32 && w.chars().all(|c| c == '0' || c == '1' || c == '_')) {
+            Format::ReadMemB
+        } else if words.all(|w| w.chars().all(|c| c.is_ascii_hexdigit() || c == '_')) {
+            Format::ReadMemH
+        } else {
+            Format::Raw
+        }
+    }
+}
+
+/// Writes `program` in `format`. `le` selects the byte order of the formats that contain bytes
+/// rather than words. If `source` is given, the `$readmem` formats have the source line of each
+/// instruction as a comment.
+pub fn write(format: Format, program: &Program, le: bool, source: Option<&str>) -> Vec<u8> {
+    match format {
+        Format::Executable => return executable::write(program, le),
+        Format::Listing => return listing::listing(source.unwrap_or(""), program).into_bytes(),
+        _ => (),
+    }
+
+    let code = &program.code;
+    let mut bytes = vec![0; code.len() * 4];
+    for (i, op) in code.iter().enumerate() {
+        if le {
+            LittleEndian::write_u32(&mut bytes[i*4..], op.0);
+        } else {
+            BigEndian::write_u32(&mut bytes[i*4..], op.0);
+        }
+    }
+
+    let mut out = String::new();
+    match format {
+        Format::Executable | Format::Listing | Format::Raw => return bytes,
+        Format::IntelHex => {
+            for (i, chunk) in bytes.chunks(16).enumerate() {
+                let addr = i * 16;
+                // Addresses past 64KiB need an extended linear address record first.
+                if addr > 0 && addr.is_multiple_of(0x10000) {
+                    let upper = (addr >> 16) as u16;
+                    out.push_str(&ihex_record(0x04, 0, &upper.to_be_bytes()));
+                }
+                out.push_str(&ihex_record(0x00, addr as u16, chunk));
+            }
+            out.push_str(&ihex_record(0x01, 0, &[]));
+        }
+        Format::ReadMemH | Format::ReadMemB => {
+            let lines: Vec<&str> = source.map(|s| s.lines().collect()).unwrap_or_default();
+            let mut last = None;
+            for (pc, op) in code.iter().enumerate() {
+                if format == Format::ReadMemH {
+                    write!(out, "{:08x}", op.0).unwrap();
+                } else {
+                    write!(out, "{:032b}", op.0).unwrap();
+                }
+                if source.is_some() {
+                    let line = pc_to_line(&program.line_map, pc);
+                    // Instructions expanded from the same line only show the source once.
+                    match line {
+                        Some(l) if line != last && l <= lines.len() => {
+                            write!(out, " // {:>4}: {}", l, lines[l-1].trim()).unwrap();
+                        }
+                        _ => write!(out, " //       {}", op).unwrap(),
+                    }
+                    last = line;
+                }
+                out.push('\n');
+            }
+        }
+        Format::Logisim => {
+            out.push_str("v2.0 raw\n");
+            for chunk in code.chunks(8) {
+                let words: Vec<String> = chunk.iter().map(|op| format!("{:x}", op.0)).collect();
+                writeln!(out, "{}", words.join(" ")).unwrap();
+            }
+        }
+    }
+    out.into_bytes()
+}
+
+fn ihex_record(kind: u8, addr: u16, data: &[u8]) -> String {
+    let mut record = vec![data.len() as u8];
+    record.extend_from_slice(&addr.to_be_bytes());
+    record.push(kind);
+    record.extend_from_slice(data);
+    let sum = record.iter().fold(0u8, |s, &b| s.wrapping_add(b));
+    record.push(sum.wrapping_neg());
+
+    let mut out = ":".to_string();
+    for b in record {
+        write!(out, "{:02X}", b).unwrap();
+    }
+    out.push('\n');
+    out
+}
+
+/// Reads a program written in `format`. Except for executables and listings, only the code is
+/// filled in.
+pub fn read(format: Format, buf: &[u8], le: bool) -> Result<Program, String> {
+    let code = match format {
+        Format::Executable => return executable::read(buf),
+        Format::Listing => return read_listing(text(buf)?),
+        Format::ReadMemH | Format::ReadMemB => read_readmem(text(buf)?, format == Format::ReadMemH)?,
+        Format::Logisim => read_logisim(text(buf)?)?,
+        Format::Raw => words(buf.to_vec(), le)?,
+        Format::IntelHex => words(read_ihex(text(buf)?)?, le)?,
+    };
+    Ok(Program { code, ..Program::default() })
+}
+
+fn words(bytes: Vec<u8>, le: bool) -> Result<Vec<Opcode>, String> {
+    if !bytes.len().is_multiple_of(4) {
+        return Err(format!("{} bytes is not a whole number of instructions", bytes.len()));
+    }
+    Ok(bytes.chunks(4).map(|w| Opcode(if le {
+        LittleEndian::read_u32(w)
+    } else {
+        BigEndian::read_u32(w)
+    })).collect())
+}
+
+fn text(buf: &[u8]) -> Result<&str, String> {
+    std::str::from_utf8(buf).map_err(|_| "file is not text".to_string())
+}
+
+fn read_ihex(text: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    let mut upper = 0;
+    for (i, line) in text.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+        let line = line.trim();
+        let err = |m: &str| format!("line {}: {}", i + 1, m);
+        let digits = line.strip_prefix(':').ok_or_else(|| err("expected `:`"))?;
+        if digits.len() < 10 || !digits.len().is_multiple_of(2) {
+            return Err(err("record is too short"));
+        }
+        let record = (0..digits.len()).step_by(2)
+            .map(|j| u8::from_str_radix(&digits[j..j+2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| err("invalid hex digit"))?;
+        if record.iter().fold(0u8, |s, &b| s.wrapping_add(b)) != 0 {
+            return Err(err("checksum mismatch"));
+        }
+        let len = record[0] as usize;
+        if record.len() != len + 5 {
+            return Err(err("record length does not match its contents"));
+        }
+        let addr = ((record[1] as usize) << 8) | record[2] as usize;
+        let data = &record[4..4+len];
+        match record[3] {
+            0x00 => {
+                let start = upper + addr;
+                let end = start.checked_add(len).filter(|&end| end <= MAX_WORDS * 4)
+                    .ok_or_else(|| err(&format!("address {:#x} is past the largest program of {} bytes",
+                                                start, MAX_WORDS * 4)))?;
+                if bytes.len() < end {
+                    bytes.resize(end, 0);
+                }
+                bytes[start..end].copy_from_slice(data);
+            }
+            0x01 => break,
+            0x02 if len == 2 => upper = (((data[0] as usize) << 8) | data[1] as usize) << 4,
+            0x04 if len == 2 => upper = (((data[0] as usize) << 8) | data[1] as usize) << 16,
+            0x03 | 0x05 => (),
+            _ => return Err(err("unsupported record type")),
+        }
+    }
+    Ok(bytes)
+}
+
+fn read_readmem(text: &str, hex: bool) -> Result<Vec<Opcode>, String> {
+    let mut code = Vec::new();
+    let mut addr = 0;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split("//").next().unwrap();
+        for word in line.split_whitespace() {
+            let (digits, radix) = match word.strip_prefix('@') {
+                Some(a) => {
+                    addr = usize::from_str_radix(a, 16)
+                        .map_err(|_| format!("line {}: invalid address `{}`", i + 1, word))?;
+                    continue;
+                }
+                None => (word.replace('_', ""), if hex { 16 } else { 2 }),
+            };
+            let w = u32::from_str_radix(&digits, radix)
+                .map_err(|_| format!("line {}: invalid word `{}`", i + 1, word))?;
+            if addr >= MAX_WORDS {
+                return Err(format!("line {}: address {:#x} is past the largest program of {} instructions",
+                                   i + 1, addr, MAX_WORDS));
+            }
+            if code.len() <= addr {
+                code.resize(addr + 1, Opcode(0));
+            }
+            code[addr] = Opcode(w);
+            addr += 1;
+        }
+    }
+    Ok(code)
+}
+
+/// Reads the code, labels and line map back from a listing. The columns are found by position, as
+/// written by `listing::listing`.
+fn read_listing(text: &str) -> Result<Program, String> {
+    let mut program = Program::default();
+    let mut first_pc = Vec::new();
+    let mut lines = text.lines().enumerate().skip(1);
+    for (i, row) in lines.by_ref() {
+        if row.is_empty() {
+            break;
+        }
+        let err = || format!("line {}: unexpected row `{}`", i + 1, row);
+        let column = |start: usize, end: usize| row.get(start..end.min(row.len())).unwrap_or("").trim();
+
+        let line: usize = match column(58, 63) {
+            "" => 0,
+            l => l.parse().map_err(|_| err())?,
+        };
+        if line > first_pc.len() {
+            first_pc.resize(line, None);
+        }
+        if column(0, 8).is_empty() {
+            continue;
+        }
+        let addr = usize::from_str_radix(column(0, 8), 16).map_err(|_| err())?;
+        let word = u32::from_str_radix(column(10, 18), 16).map_err(|_| err())?;
+        if addr != program.code.len() * 4 {
+            return Err(err());
+        }
+        if line > 0 && first_pc[line-1].is_none() {
+            first_pc[line-1] = Some(program.code.len());
+        }
+        program.code.push(Opcode(word));
+    }
+
+    // Each line maps to the first instruction at or after it.
+    let mut next = program.code.len();
+    program.line_map = vec![0; first_pc.len()];
+    for (l, pc) in first_pc.iter().enumerate().rev() {
+        next = pc.unwrap_or(next);
+        program.line_map[l] = next;
+    }
+
+    for (i, row) in lines.skip(2) {
+        let fields: Vec<&str> = row.split_whitespace().collect();
+        let (addr, section, name) = match fields[..] {
+            [addr, section, _, name] => (addr, section, name),
+            [_, _] => continue,
+            _ => return Err(format!("line {}: unexpected symbol `{}`", i + 1, row)),
+        };
+        let addr = u64::from_str_radix(addr, 16).map_err(|_| format!("line {}: invalid address", i + 1))?;
+        if section == "text" {
+            program.labels.insert(name.to_string(), addr as usize / 4);
+        } else {
+            program.data_labels.insert(name.to_string(), addr);
+        }
+    }
+    program.entry = program.labels.get(ENTRY_LABEL).cloned().unwrap_or(0);
+    Ok(program)
+}
+
+fn read_logisim(text: &str) -> Result<Vec<Opcode>, String> {
+    let mut lines = text.lines().enumerate();
+    match lines.next() {
+        Some((_, l)) if l.trim() == "v2.0 raw" => (),
+        _ => return Err("expected `v2.0 raw` header".to_string()),
+    }
+
+    let mut code = Vec::new();
+    for (i, line) in lines {
+        let line = line.split('#').next().unwrap();
+        for word in line.split_whitespace() {
+            let err = || format!("line {}: invalid word `{}`", i + 1, word);
+            // `N*value` repeats a value N times.
+            let (count, value) = match word.find('*') {
+                Some(j) => (word[..j].parse().map_err(|_| err())?, &word[j+1..]),
+                None => (1, word),
+            };
+            let value = u32::from_str_radix(value, 16).map_err(|_| err())?;
+            if code.len().checked_add(count).is_none_or(|len| len > MAX_WORDS) {
+                return Err(format!("line {}: more than the largest program of {} instructions", i + 1, MAX_WORDS));
+            }
+            code.extend(std::iter::repeat_n(Opcode(value), count));
+        }
+    }
+    Ok(code)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use Register;
+    use assemble::assemble;
+    use tokenizer::Tokenizer;
+
+    #[test]
+    fn round_trips_every_format() {
+        let code = vec![Opcode::Add(Register(2), Register(1), Register(0)), Opcode::Cbz(Register(3), 2), Opcode(0)];
+        let program = Program { code: code.clone(), line_map: vec![0, 1, 2], ..Program::default() };
+        for &format in &[Format::Executable, Format::Raw, Format::IntelHex, Format::ReadMemH, Format::ReadMemB,
+                         Format::Logisim] {
+            let out = write(format, &program, true, Some("ADD X0, X1, X2\nCBZ X3, end\n.word 0\n"));
+            if format != Format::Raw {
+                assert_eq!(Format::detect(&out), format);
+            }
+            assert_eq!(read(format, &out, true).unwrap().code, code);
+        }
+        assert_eq!(read(Format::Logisim, b"v2.0 raw\n3*8b010002 0\n", false).unwrap().code.len(), 4);
+    }
+
+    #[test]
+    fn rejects_oversized_programs() {
+        let err = |format: Format, text: &str| read(format, text.as_bytes(), false).unwrap_err();
+        assert_eq!(err(Format::ReadMemH, "@ffffffffffffffff\n8b010002\n"),
+                   "line 2: address 0xffffffffffffffff is past the largest program of 1048576 instructions");
+        assert_eq!(err(Format::ReadMemH, "@100000 8b010002\n"),
+                   "line 1: address 0x100000 is past the largest program of 1048576 instructions");
+        // An extended linear address of 0xffff followed by a data record near the top of it.
+        assert_eq!(err(Format::IntelHex, ":02000004FFFFFC\n:04FFF000000000000D\n:00000001FF\n"),
+                   "line 2: address 0xfffffff0 is past the largest program of 4194304 bytes");
+        assert_eq!(err(Format::Logisim, "v2.0 raw\n99999999999*0\n"),
+                   "line 2: more than the largest program of 1048576 instructions");
+    }
+
+    #[test]
+    fn reads_listings() {
+        let source = "  ADDI X0, XZR, #3\nloop:\n  MOVI X1, #0x10001\n  CBNZ X0, loop\n\n";
+        let (tokens, _) = Tokenizer::tokenize(source);
+        let program = assemble(tokens).unwrap();
+        let out = write(Format::Listing, &program, false, Some(source));
+        assert_eq!(Format::detect(&out), Format::Listing);
+        assert_eq!(Format::detect(b"address: .dword 0\n"), Format::Raw);
+
+        let read = read(Format::Listing, &out, false).unwrap();
+        assert_eq!(read.code, program.code);
+        assert_eq!(read.labels, program.labels);
+        for pc in 0..program.code.len() {
+            assert_eq!(pc_to_line(&read.line_map, pc), pc_to_line(&program.line_map, pc));
+        }
+    }
+}
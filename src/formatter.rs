@@ -0,0 +1,297 @@
+use bytecode::Instruction;
+use register::Register;
+use tokenizer::{Directive, Pseudo, Token, Tokenizer};
+
+use std::collections::HashSet;
+
+/// The column instructions start at when no label shares a line with one.
+const INDENT: usize = 4;
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Options {
+    /// Write registers with their ABI names, such as `SP` and `LR`, instead of `X28` and `X30`.
+    pub aliases: bool,
+}
+
+/// A source line split into its parts.
+enum Line {
+    /// A line the tokenizer rejected, which is kept as it is.
+    Verbatim(String),
+    Code {
+        labels: Vec<String>,
+        mnemonic: Option<String>,
+        /// Whether the statement is a directive that starts at the first column, such as `.text`.
+        outdent: bool,
+        operands: String,
+        comment: Option<String>,
+        /// Whether a comment on a line of its own started at the first column.
+        flush: bool,
+    },
+}
+
+/// Splits `line` into the code and a `//` comment, ignoring any inside string and character
+/// literals.
+fn split_comment(line: &str) -> (&str, Option<&str>) {
+    let mut chars = line.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' | '\'' => {
+                while let Some((_, d)) = chars.next() {
+                    if d == '\\' {
+                        chars.next();
+                    } else if d == c {
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek().is_some_and(|&(_, d)| d == '/') => return (&line[..i], Some(&line[i..])),
+            _ => (),
+        }
+    }
+    (line, None)
+}
+
+/// Returns the canonical spelling of a mnemonic, pseudo-instruction or directive written in the
+/// wrong case.
+fn canonical_mnemonic(word: &str) -> Option<String> {
+    let upper = word.to_uppercase();
+    let lower = word.to_lowercase();
+    if Instruction::from_str(&upper).is_some() || Pseudo::from_str(&upper).is_some() {
+        Some(upper)
+    } else if Directive::from_str(&lower).is_some() {
+        Some(lower)
+    } else {
+        None
+    }
+}
+
+fn register_name(r: Register, options: Options) -> String {
+    if options.aliases || r.0 == 31 {
+        r.to_string()
+    } else {
+        format!("X{}", r.0)
+    }
+}
+
+/// Joins the operand tokens of a statement with the spacing `fmt` uses: a space after each comma,
+/// none inside brackets and parentheses, and spaces around binary operators.
+fn join_operands(tokens: &[(&Token, String)]) -> String {
+    let mut out = String::new();
+    let mut previous: Option<&Token> = None;
+    // Whether the previous token is a `-` negating what follows rather than subtracting.
+    let mut negation = false;
+    for &(t, ref text) in tokens {
+        let space = match (previous, t) {
+            (None, _) | (_, Token::Comma(_)) | (_, Token::RBrace(_)) | (_, Token::Operator(_, ')')) => false,
+            (Some(Token::LBrace(_)), _) | (Some(Token::Operator(_, '(')), _) => false,
+            _ => !negation,
+        };
+        negation = matches!(t, Token::Operator(_, '-')) &&
+            !matches!(previous, Some(Token::Immediate(..)) | Some(Token::Label(..)) | Some(Token::Constant(..)) |
+                               Some(Token::Operator(_, ')')));
+        if space {
+            out.push(' ');
+        }
+        out.push_str(text);
+        previous = Some(t);
+    }
+    out
+}
+
+/// Reformats `source` with labels in the first column, mnemonics and operands aligned, registers
+/// spelled consistently, canonical mnemonic case and comments aligned. Lines the tokenizer
+/// rejects are left alone.
+pub fn format(source: &str, options: Options) -> String {
+    let (tokens, errors) = Tokenizer::tokenize(source);
+    let rejected: HashSet<usize> = errors.iter().map(|e| e.span.line).collect();
+    let lines: Vec<&str> = source.lines().collect();
+    let text = |t: &Token| {
+        let s = t.span();
+        lines[s.line - 1].chars().skip(s.column - 1).take(s.len).collect::<String>()
+    };
+    let is_definition = |t: &Token| {
+        let s = t.span();
+        matches!(t, Token::Label(..)) && lines[s.line - 1].chars().nth(s.column - 1 + s.len) == Some(':')
+    };
+    let defined: HashSet<String> = tokens.iter().filter(|t| is_definition(t)).map(&text).collect();
+
+    let mut parsed = Vec::new();
+    let mut tokens = tokens.iter().peekable();
+    for (i, line) in lines.iter().enumerate() {
+        let number = i + 1;
+        let mut line_tokens = Vec::new();
+        while let Some(t) = tokens.next_if(|t| t.line() == number) {
+            line_tokens.push(t);
+        }
+        if rejected.contains(&number) {
+            parsed.push(Line::Verbatim(line.trim_end().to_string()));
+            continue;
+        }
+
+        let (_, comment) = split_comment(line);
+        let comment = comment.map(|c| c.trim_end().to_string());
+        let flush = !line.starts_with(char::is_whitespace);
+        let mut rest = line_tokens.as_slice();
+        let mut labels = Vec::new();
+        while let Some((t, r)) = rest.split_first().filter(|(t, _)| is_definition(t)) {
+            labels.push(text(t) + ":");
+            rest = r;
+        }
+        let (mnemonic, outdent) = match rest.split_first() {
+            Some((t, r)) => {
+                rest = r;
+                let word = text(t);
+                let mnemonic = match t {
+                    Token::Label(..) => canonical_mnemonic(&word).unwrap_or(word),
+                    _ => word,
+                };
+                let outdent = match Directive::from_str(&mnemonic) {
+                    Some(d) => !matches!(d, Directive::Dword | Directive::Word | Directive::Byte |
+                                           Directive::Asciz | Directive::Space | Directive::Align),
+                    None => false,
+                };
+                (Some(mnemonic), outdent)
+            }
+            None => (None, false),
+        };
+        let operands: Vec<(&Token, String)> = rest.iter().map(|&t| {
+            let word = text(t);
+            let word = match t {
+                Token::Register(_, r) => register_name(*r, options),
+                Token::Label(..) if !defined.contains(&word) => match Register::from_str(&word.to_uppercase()) {
+                    Some(r) => register_name(r, options),
+                    // The shift of MOVZ and MOVK.
+                    None if word.eq_ignore_ascii_case("LSL") => word.to_uppercase(),
+                    None => word,
+                },
+                _ => word,
+            };
+            (t, word)
+        }).collect();
+        parsed.push(Line::Code { labels, mnemonic, outdent, operands: join_operands(&operands), comment, flush });
+    }
+
+    // Instructions start after the longest run of labels sharing a line with one, and operands
+    // after the longest mnemonic, so that both line up across the file.
+    let indent = parsed.iter()
+        .filter_map(|l| match l {
+            Line::Code { labels, mnemonic: Some(_), .. } if !labels.is_empty() => Some(labels.join(" ").len() + 1),
+            _ => None,
+        })
+        .map(|n| n.div_ceil(INDENT) * INDENT)
+        .fold(INDENT, usize::max);
+    let width = parsed.iter()
+        .filter_map(|l| match l {
+            Line::Code { mnemonic: Some(m), outdent: false, .. } if canonical_mnemonic(m).is_some() => Some(m.len()),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+
+    let mut code = Vec::new();
+    for line in &parsed {
+        let (labels, mnemonic, outdent, operands, comment, flush) = match line {
+            Line::Verbatim(l) => {
+                code.push((l.clone(), None));
+                continue;
+            }
+            Line::Code { labels, mnemonic, outdent, operands, comment, flush } => {
+                (labels, mnemonic, *outdent, operands, comment, *flush)
+            }
+        };
+        let mut out = labels.join(" ");
+        if let Some(m) = mnemonic {
+            let column = if outdent && labels.is_empty() { 0 } else { indent };
+            out = format!("{:1$}", out, column);
+            if operands.is_empty() {
+                out.push_str(m);
+            } else if outdent {
+                out = format!("{}{} {}", out, m, operands);
+            } else {
+                out = format!("{}{:w$} {}", out, m, operands, w = width);
+            }
+        }
+        match comment {
+            Some(c) if out.is_empty() => {
+                let column = if flush { 0 } else { indent };
+                code.push((format!("{:1$}{2}", "", column, c), None));
+            }
+            _ => code.push((out, comment.clone())),
+        }
+    }
+
+    // Trailing comments on consecutive lines line up one space after the longest code among them.
+    let mut out = String::new();
+    let mut i = 0;
+    while i < code.len() {
+        let end = i + code[i..].iter().take_while(|(_, c)| c.is_some()).count();
+        let column = code[i..end].iter().map(|(l, _)| l.chars().count()).max().unwrap_or(0);
+        for (line, comment) in &code[i..end.max(i + 1)] {
+            match comment {
+                Some(c) => out += &format!("{:1$} {2}\n", line, column, c),
+                None => out += &format!("{}\n", line),
+            }
+        }
+        i = end.max(i + 1);
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn formats_source() {
+        let source = "\
+// Sums the array
+.DATA
+arr:   .dword 1,2 , 3  // values
+.text
+.equ N,(3+1)*-2
+.equ M,N-1
+main: addi x0,XZR,#N
+  subi x1,x0,#M -1
+\tLDA X1, arr // address
+  loop:
+ cbz X0,done\t// end?
+    stur x29,[ sp ,#-8]
+   ldur FP, [SP,#'a']
+    movz X9,#1,lsl #16
+\tb.eq   loop
+    SUBI X0, X0, #1 ??
+done: RET
+";
+        let formatted = format(source, Options::default());
+        assert_eq!(formatted, "\
+// Sums the array
+.data
+arr:    .dword 1, 2, 3 // values
+.text
+.equ N, (3 + 1) * -2
+.equ M, N - 1
+main:   ADDI   X0, XZR, #N
+        SUBI   X1, X0, #M - 1
+        LDA    X1, arr // address
+loop:
+        CBZ    X0, done // end?
+        STUR   X29, [X28, #-8]
+        LDUR   X29, [X28, #'a']
+        MOVZ   X9, #1, LSL #16
+        B.EQ   loop
+    SUBI X0, X0, #1 ??
+done:   RET
+");
+        assert_eq!(format(&formatted, Options::default()), formatted);
+
+        let aliases = format("    ADD X29, X28, X30\n    PRNT X16\n", Options { aliases: true });
+        assert_eq!(aliases, "    ADD  FP, SP, LR\n    PRNT IP0\n");
+    }
+
+    #[test]
+    fn ignores_slashes_in_literals() {
+        assert_eq!(split_comment(".asciz \"a//b\" // c"), (".asciz \"a//b\" ", Some("// c")));
+        assert_eq!(split_comment("MOVZ X0, #'/' // d"), ("MOVZ X0, #'/' ", Some("// d")));
+        assert_eq!(split_comment("MOVZ X0, #'\\''"), ("MOVZ X0, #'\\''", None));
+    }
+}
@@ -0,0 +1,416 @@
+use assemble::Program;
+use harness::{assemble_file, check, describe, execute, load, Expect, Memory, Spec, Status, Value, DEFAULT_MAX_STEPS};
+
+use vm::Fault;
+
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The time each case may run for, in milliseconds, unless the rubric says otherwise.
+pub const DEFAULT_TIME_LIMIT: u64 = 1000;
+
+/// The test cases a submission is graded on. The limits apply to every case that doesn't set its
+/// own.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rubric {
+    #[serde(default)]
+    pub max_steps: Option<usize>,
+    #[serde(default)]
+    pub time_limit_ms: Option<u64>,
+    #[serde(rename = "case", alias = "cases")]
+    pub cases: Vec<Case>,
+}
+
+/// A named test case worth `points`. The other fields are as in the spec of `test`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Case {
+    pub name: String,
+    #[serde(default = "one_point")]
+    pub points: f64,
+    #[serde(default)]
+    pub time_limit_ms: Option<u64>,
+    #[serde(default)]
+    pub max_steps: Option<usize>,
+    #[serde(default)]
+    pub registers: BTreeMap<String, Value>,
+    #[serde(default)]
+    pub flags: Option<Value>,
+    #[serde(default)]
+    pub memory: Vec<Memory>,
+    #[serde(default)]
+    pub expect: Expect,
+}
+
+impl Case {
+    fn spec(&self) -> Spec {
+        Spec {
+            max_steps: self.max_steps,
+            registers: self.registers.clone(),
+            flags: self.flags.clone(),
+            memory: self.memory.clone(),
+            expect: self.expect.clone(),
+        }
+    }
+}
+
+fn one_point() -> f64 {
+    1.0
+}
+
+/// The result of one case. `status` is one of `pass`, `fail`, `fault`, `step_limit`, `timeout`,
+/// `panic` or `error`, the last for a case that couldn't be run at all.
+#[derive(Debug, Serialize)]
+pub struct CaseReport {
+    pub name: String,
+    pub status: &'static str,
+    pub score: f64,
+    pub points: f64,
+    pub steps: usize,
+    /// The kind of fault that stopped the program, if any.
+    pub fault: Option<&'static str>,
+    /// Why the program stopped early or the case couldn't be run.
+    pub message: Option<String>,
+    /// How the final state differed from what was expected.
+    pub diffs: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub submission: String,
+    pub score: f64,
+    pub total: f64,
+    /// Errors from assembling the submission, in which case no case is run. A panic in the
+    /// assembler is reported here too.
+    pub errors: Vec<String>,
+    pub cases: Vec<CaseReport>,
+}
+
+/// Limits that apply to cases unless the rubric sets its own.
+#[derive(Copy, Clone)]
+pub struct Limits {
+    pub max_steps: usize,
+    pub time_limit_ms: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits { max_steps: DEFAULT_MAX_STEPS, time_limit_ms: DEFAULT_TIME_LIMIT }
+    }
+}
+
+/// Assembles the submission at `path` and runs every case of `rubric` on it. Assembling is held to
+/// the same time limit as each case.
+pub fn grade_file(path: &Path, rubric: &Rubric, limits: Limits) -> Report {
+    let submission = path.display().to_string();
+    let time_limit = rubric.time_limit_ms.unwrap_or(limits.time_limit_ms);
+    match assemble_within(path, Duration::from_millis(time_limit)) {
+        Ok(program) => grade(submission, &program, rubric, limits),
+        Err(errors) => {
+            let cases = rubric.cases.iter().map(|c| not_run(c, "the submission did not assemble")).collect();
+            report(submission, errors, cases)
+        }
+    }
+}
+
+/// Assembles the file at `path` on a worker thread, giving up after `time_limit`. The thread is
+/// left to finish on its own if it takes too long.
+fn assemble_within(path: &Path, time_limit: Duration) -> Result<Program, Vec<String>> {
+    let (tx, rx) = mpsc::channel();
+    let path = path.to_path_buf();
+    thread::spawn(move || {
+        let program = panic::catch_unwind(|| assemble_file(&path, false))
+            .unwrap_or_else(|e| Err(vec![format!("the assembler panicked: {}", panic_message(&*e))]));
+        // The receiver is gone if grading already gave up.
+        let _ = tx.send(program);
+    });
+    rx.recv_timeout(time_limit)
+        .unwrap_or_else(|_| Err(vec![format!("assembling took longer than {} ms", time_limit.as_millis())]))
+}
+
+/// Runs every case of `rubric` on `program`, each in a fresh VM. A case that panics the emulator
+/// is reported as such without affecting the others.
+pub fn grade(submission: String, program: &Program, rubric: &Rubric, limits: Limits) -> Report {
+    let limits = Limits {
+        max_steps: rubric.max_steps.unwrap_or(limits.max_steps),
+        time_limit_ms: rubric.time_limit_ms.unwrap_or(limits.time_limit_ms),
+    };
+    let cases = rubric.cases.iter()
+        .map(|case| match panic::catch_unwind(AssertUnwindSafe(|| run_case(program, case, limits))) {
+            Ok(report) => report,
+            Err(e) => CaseReport { status: "panic", message: Some(panic_message(&*e)), ..not_run(case, "") },
+        })
+        .collect();
+    report(submission, Vec::new(), cases)
+}
+
+fn report(submission: String, errors: Vec<String>, cases: Vec<CaseReport>) -> Report {
+    Report {
+        submission,
+        score: cases.iter().map(|c| c.score).sum(),
+        total: cases.iter().map(|c| c.points).sum(),
+        errors,
+        cases,
+    }
+}
+
+fn not_run(case: &Case, message: &str) -> CaseReport {
+    CaseReport {
+        name: case.name.clone(),
+        status: "error",
+        score: 0.0,
+        points: case.points,
+        steps: 0,
+        fault: None,
+        message: Some(message.to_string()),
+        diffs: Vec::new(),
+    }
+}
+
+fn panic_message(e: &(dyn Any + Send)) -> String {
+    match e.downcast_ref::<&str>() {
+        Some(s) => s.to_string(),
+        None => e.downcast_ref::<String>().cloned().unwrap_or_else(|| "the emulator panicked".to_string()),
+    }
+}
+
+fn run_case(program: &Program, case: &Case, limits: Limits) -> CaseReport {
+    let spec = case.spec();
+    let mut vm = match load(program, &spec) {
+        Ok(vm) => vm,
+        Err(e) => return not_run(case, &format!("invalid case: {}", e)),
+    };
+    let max_steps = spec.max_steps.unwrap_or(limits.max_steps);
+    let time_limit = Duration::from_millis(case.time_limit_ms.unwrap_or(limits.time_limit_ms));
+    let status = execute(&mut vm, max_steps, Some(Instant::now() + time_limit));
+    let diffs = match check(&vm, program, &spec.expect) {
+        Ok(diffs) => diffs,
+        Err(e) => return not_run(case, &format!("invalid case: {}", e)),
+    };
+
+    let (status, message) = match status {
+        Status::Finished if diffs.is_empty() => ("pass", None),
+        Status::Finished => ("fail", None),
+        Status::Fault(_) => ("fault", Some(describe(&status, &vm, max_steps))),
+        Status::StepLimit => ("step_limit", Some(describe(&status, &vm, max_steps))),
+        Status::Timeout => ("timeout", Some(describe(&status, &vm, max_steps))),
+    };
+    CaseReport {
+        name: case.name.clone(),
+        status,
+        score: if status == "pass" { case.points } else { 0.0 },
+        points: case.points,
+        steps: vm.steps(),
+        fault: vm.fault().map(Fault::kind),
+        message,
+        diffs,
+    }
+}
+
+impl Report {
+    /// Formats the report for people, with the differences for every case that didn't pass.
+    pub fn text(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "{}: {}/{} points", self.submission, self.score, self.total).unwrap();
+        for e in &self.errors {
+            writeln!(out, "  {}", e).unwrap();
+        }
+        for c in &self.cases {
+            writeln!(out, "  {:<10} {} ({}/{}, {} steps)", c.status.to_uppercase(), c.name, c.score, c.points,
+                     c.steps).unwrap();
+            for line in c.message.iter().chain(&c.diffs) {
+                writeln!(out, "             {}", line).unwrap();
+            }
+        }
+        out
+    }
+}
+
+/// The report of one student when grading a directory of submissions.
+#[derive(Debug, Serialize)]
+pub struct Row {
+    pub student: String,
+    #[serde(flatten)]
+    pub report: Report,
+}
+
+/// Finds the submission of every student in `dir`, sorted by student. A `.s` file directly inside
+/// `dir` is the submission of the student it's named after. A subdirectory belongs to the student
+/// it's named after and must hold a single `.s` file or a `main.s`.
+pub fn submissions(dir: &Path) -> io::Result<Vec<(String, Result<PathBuf, String>)>> {
+    let mut found = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let student = match path.file_stem() {
+            Some(s) => s.to_string_lossy().into_owned(),
+            None => continue,
+        };
+        if path.is_dir() {
+            let sources: Vec<PathBuf> = fs::read_dir(&path)?
+                .filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|p| p.is_file() && p.extension().is_some_and(|e| e == "s"))
+                .collect();
+            let main = path.join("main.s");
+            let submission = match sources.len() {
+                0 => Err("no .s file found".to_string()),
+                1 => Ok(sources[0].clone()),
+                _ if main.is_file() => Ok(main),
+                _ => Err("more than one .s file and no main.s".to_string()),
+            };
+            found.push((student, submission));
+        } else if path.extension().is_some_and(|e| e == "s") {
+            found.push((student, Ok(path)));
+        }
+    }
+    found.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(found)
+}
+
+/// Grades every submission in `dir` using `jobs` threads.
+pub fn grade_all(dir: &Path, rubric: &Rubric, limits: Limits, jobs: usize) -> io::Result<Vec<Row>> {
+    let submissions = submissions(dir)?;
+    let next = AtomicUsize::new(0);
+    let rows: Mutex<Vec<Option<Row>>> = Mutex::new(submissions.iter().map(|_| None).collect());
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, submissions.len().max(1)) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                let (student, submission) = match submissions.get(i) {
+                    Some(s) => s,
+                    None => break,
+                };
+                let report = match *submission {
+                    Ok(ref path) => grade_file(path, rubric, limits),
+                    Err(ref e) => {
+                        let cases = rubric.cases.iter().map(|c| not_run(c, "there is no submission")).collect();
+                        report(String::new(), vec![e.clone()], cases)
+                    }
+                };
+                rows.lock().unwrap()[i] = Some(Row { student: student.clone(), report });
+            });
+        }
+    });
+    Ok(rows.into_inner().unwrap().into_iter().map(Option::unwrap).collect())
+}
+
+/// Formats `rows` as CSV with a row per student. After the totals and any assembly errors come
+/// the status, score, step count and fault kind of each case of `rubric`.
+pub fn csv(rows: &[Row], rubric: &Rubric) -> String {
+    let mut header = vec!["student".to_string(), "submission".to_string(), "score".to_string(),
+                          "total".to_string(), "errors".to_string()];
+    for c in &rubric.cases {
+        for field in &["status", "score", "steps", "fault"] {
+            header.push(format!("{} {}", c.name, field));
+        }
+    }
+
+    let mut out = String::new();
+    let line = |fields: Vec<String>| fields.iter().map(|f| csv_field(f)).collect::<Vec<_>>().join(",") + "\n";
+    out.push_str(&line(header));
+    for row in rows {
+        let r = &row.report;
+        let mut fields = vec![row.student.clone(), r.submission.clone(), r.score.to_string(), r.total.to_string(),
+                              r.errors.join("; ")];
+        for c in &r.cases {
+            fields.extend(vec![c.status.to_string(), c.score.to_string(), c.steps.to_string(),
+                               c.fault.unwrap_or("").to_string()]);
+        }
+        out.push_str(&line(fields));
+    }
+    out
+}
+
+fn csv_field(f: &str) -> String {
+    if f.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", f.replace('"', "\"\""))
+    } else {
+        f.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assemble::assemble;
+    use tokenizer::Tokenizer;
+
+    use std::{env, process};
+
+    #[test]
+    fn scores_cases_independently() {
+        let (tokens, _) = Tokenizer::tokenize("CBZ X0, spin\nLDUR X1, [X0, #0]\nLSL X0, X0, #1\nHALT\n\
+                                               spin: B spin\n");
+        let program = assemble(tokens).unwrap();
+        let rubric: Rubric = toml::from_str(r#"
+            max_steps = 100
+
+            [[case]]
+            name = "doubles"
+            points = 2
+            registers = { X0 = 8 }
+            expect.registers = { X0 = 16 }
+
+            [[case]]
+            name = "wrong"
+            registers = { X0 = 8 }
+            expect.registers = { X0 = 8 }
+
+            [[case]]
+            name = "misaligned"
+            registers = { X0 = 4 }
+
+            [[case]]
+            name = "spins"
+
+            [[case]]
+            name = "slow"
+            max_steps = 1000000000
+            time_limit_ms = 10
+        "#).unwrap();
+
+        let report = grade("s".to_string(), &program, &rubric, Limits::default());
+        let statuses: Vec<_> = report.cases.iter().map(|c| c.status).collect();
+        assert_eq!(statuses, vec!["pass", "fail", "fault", "step_limit", "timeout"]);
+        assert_eq!((report.score, report.total), (2.0, 6.0));
+        assert_eq!(report.cases[3].steps, 100);
+        assert!(toml::from_str::<Rubric>("[[case]]\nname = \"x\"\nregister = {}").is_err());
+        assert_eq!(report.cases[2].fault, Some("misaligned"));
+
+        let rows = vec![Row { student: "a, b".to_string(), report }];
+        let csv = csv(&rows, &rubric);
+        assert!(csv.starts_with("student,submission,score,total,errors,doubles status,doubles score,"));
+        let row = csv.lines().nth(1).unwrap();
+        assert!(row.starts_with("\"a, b\",s,2,6,,pass,2,4,,fail,0,4,,fault,0,2,misaligned,step_limit,0,100,,timeout,0,"),
+                "{}", row);
+    }
+
+    #[test]
+    fn limits_assembly_time() {
+        let path = env::temp_dir().join(format!("legv8-grade-{}.s", process::id()));
+        fs::write(&path, "HALT\n").unwrap();
+        let rubric: Rubric = toml::from_str("[[case]]\nname = \"halts\"").unwrap();
+        let report = grade_file(&path, &rubric, Limits::default());
+        assert_eq!(report.cases[0].status, "pass");
+
+        // Takes far longer than a millisecond to expand before the preprocessor gives up.
+        let mut src = ".macro m0\nNOP\n.endm\n".to_string();
+        for i in 1..=8 {
+            src += &format!(".macro m{}\n{}.endm\n", i, format!("m{}\n", i - 1).repeat(10));
+        }
+        fs::write(&path, src + "m8\n").unwrap();
+        let report = grade_file(&path, &rubric, Limits { time_limit_ms: 1, ..Limits::default() });
+        fs::remove_file(&path).unwrap();
+        assert_eq!(report.errors, vec!["assembling took longer than 1 ms"]);
+        assert_eq!(report.cases[0].status, "error");
+    }
+}
@@ -0,0 +1,493 @@
+use assemble::{assemble_source, Program};
+use error::{render_in, AssembleError};
+use register::Register;
+use vm::{Fault, Stop, VM};
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use serde::de::DeserializeOwned;
+
+/// The number of instructions a program may execute before it is stopped, unless its spec says
+/// otherwise.
+pub const DEFAULT_MAX_STEPS: usize = 100_000;
+
+/// A number in a spec. Besides integers, strings may hold decimal or `0x` hex numbers, which is
+/// the only way to write values above `i64::MAX` in TOML. Addresses may also name a data label.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Value {
+    Signed(i64),
+    Unsigned(u64),
+    Text(String),
+}
+
+impl Value {
+    pub fn to_u64(&self) -> Result<u64, String> {
+        match *self {
+            Value::Signed(v) => Ok(v as u64),
+            Value::Unsigned(v) => Ok(v),
+            Value::Text(ref s) => parse_u64(s).ok_or_else(|| format!("`{}` is not a number", s)),
+        }
+    }
+
+    /// Returns the byte address in main memory, looking up data labels in `program`.
+    pub fn to_address(&self, program: &Program) -> Result<u64, String> {
+        match *self {
+            Value::Text(ref s) if parse_u64(s).is_none() => program.data_labels.get(s).cloned()
+                .ok_or_else(|| format!("there is no data label `{}`", s)),
+            _ => self.to_u64(),
+        }
+    }
+}
+
+/// Parses a decimal, negative or `0x` hex number.
+pub fn parse_u64(s: &str) -> Option<u64> {
+    let s = s.trim();
+    if let Some(hex) = s.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16).ok()
+    } else if s.starts_with('-') {
+        s.parse::<i64>().ok().map(|v| v as u64)
+    } else {
+        s.parse().ok()
+    }
+}
+
+/// Consecutive doublewords of main memory starting at `address`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Memory {
+    pub address: Value,
+    pub values: Vec<Value>,
+}
+
+/// What the machine should look like once the program has finished. Registers and memory that
+/// aren't mentioned aren't checked.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Expect {
+    pub registers: BTreeMap<String, Value>,
+    pub memory: Vec<Memory>,
+    /// The exact number of instructions executed.
+    pub steps: Option<usize>,
+    /// Everything printed by `PRNT` and `PRNL`.
+    pub output: Option<String>,
+}
+
+/// Registers, flags and memory to set before a program starts. Registers and memory that aren't
+/// mentioned keep their initial values.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct State {
+    pub registers: BTreeMap<String, Value>,
+    /// The value that the last flag-setting instruction produced.
+    pub flags: Option<Value>,
+    pub memory: Vec<Memory>,
+}
+
+impl State {
+    /// Adds a register from a `NAME=VALUE` argument.
+    pub fn add_register(&mut self, arg: &str) -> Result<(), String> {
+        let (name, value) = arg.split_once('=').ok_or_else(|| format!("expected NAME=VALUE, got `{}`", arg))?;
+        let r = register(name.trim())?;
+        let value = parse_u64(value).ok_or_else(|| format!("`{}` is not a number", value))?;
+        self.registers.insert(r.to_string(), Value::Unsigned(value));
+        Ok(())
+    }
+
+    /// Adds memory from an `ADDRESS=VALUE,VALUE,...` argument, where the address may be a data
+    /// label.
+    pub fn add_memory(&mut self, arg: &str) -> Result<(), String> {
+        let (address, values) = arg.split_once('=')
+            .ok_or_else(|| format!("expected ADDRESS=VALUE,..., got `{}`", arg))?;
+        let values = values.split(',')
+            .map(|v| parse_u64(v).map(Value::Unsigned).ok_or_else(|| format!("`{}` is not a number", v)))
+            .collect::<Result<_, _>>()?;
+        self.memory.push(Memory { address: Value::Text(address.trim().to_string()), values });
+        Ok(())
+    }
+
+    /// Adds memory from an `ADDRESS:PATH` argument. The file is copied to memory byte for byte,
+    /// with the last doubleword padded with zeros.
+    pub fn add_memory_file(&mut self, arg: &str) -> Result<(), String> {
+        let (address, path) = arg.split_once(':').ok_or_else(|| format!("expected ADDRESS:PATH, got `{}`", arg))?;
+        let mut buf = Vec::new();
+        File::open(path).and_then(|mut f| f.read_to_end(&mut buf))
+            .map_err(|e| format!("unable to read {}: {}", path, e))?;
+        let values = buf.chunks(8)
+            .map(|chunk| {
+                let mut bytes = [0; 8];
+                bytes[..chunk.len()].copy_from_slice(chunk);
+                Value::Unsigned(u64::from_le_bytes(bytes))
+            })
+            .collect();
+        self.memory.push(Memory { address: Value::Text(address.trim().to_string()), values });
+        Ok(())
+    }
+}
+
+/// The sidecar file of a program, giving the registers and memory to start with and what to
+/// expect at the end.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Spec {
+    pub max_steps: Option<usize>,
+    pub registers: BTreeMap<String, Value>,
+    pub flags: Option<Value>,
+    pub memory: Vec<Memory>,
+    pub expect: Expect,
+}
+
+impl Spec {
+    pub fn state(&self) -> State {
+        State { registers: self.registers.clone(), flags: self.flags.clone(), memory: self.memory.clone() }
+    }
+}
+
+/// Reads a spec or rubric in TOML, or in JSON if `path` ends in `.json`.
+pub fn read_file<T: DeserializeOwned>(path: &Path) -> Result<T, String> {
+    let mut buf = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut buf)).map_err(|e| e.to_string())?;
+    if path.extension().is_some_and(|e| e == "json") {
+        serde_json::from_str(&buf).map_err(|e| e.to_string())
+    } else {
+        toml::from_str(&buf).map_err(|e| e.to_string())
+    }
+}
+
+fn register(name: &str) -> Result<Register, String> {
+    Register::from_str(&name.to_uppercase()).ok_or_else(|| format!("`{}` is not a register", name))
+}
+
+/// Creates a VM with `program` loaded and the registers and memory set as in `spec`. Tracing is
+/// turned off.
+pub fn load(program: &Program, spec: &Spec) -> Result<VM, String> {
+    let mut vm = VM::new();
+    vm.set_trace(false);
+    vm.load_data(&program.data, program.data_labels.clone())?;
+    vm.load_code(program.code.clone(), program.entry);
+    vm.load_line_map(program.line_map.clone());
+    set_state(&mut vm, program, &spec.state())?;
+    Ok(vm)
+}
+
+/// Sets the registers, flags and memory of `vm` as in `state`, after `program`'s data has been
+/// loaded.
+pub fn set_state(vm: &mut VM, program: &Program, state: &State) -> Result<(), String> {
+    for (name, v) in &state.registers {
+        vm.set_register(register(name)?, v.to_u64()?);
+    }
+    if let Some(ref flags) = state.flags {
+        vm.set_flags(flags.to_u64()?);
+    }
+    for m in &state.memory {
+        let addr = m.address.to_address(program)?;
+        for (i, v) in m.values.iter().enumerate() {
+            vm.write_memory(addr.wrapping_add(i as u64 * 8), v.to_u64()?).map_err(|f| f.to_string())?;
+        }
+    }
+    Ok(())
+}
+
+/// How a run ended.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Status {
+    Finished,
+    Fault(Fault),
+    /// The program was stopped after executing the maximum number of instructions.
+    StepLimit,
+    /// The program was stopped when it ran past its deadline.
+    Timeout,
+}
+
+/// Runs the program loaded in `vm` until it finishes, faults, has executed `max_steps`
+/// instructions or runs past `deadline`. Breakpoints are run past.
+pub fn execute(vm: &mut VM, max_steps: usize, deadline: Option<Instant>) -> Status {
+    vm.set_limits(Some(max_steps), deadline);
+    loop {
+        match vm.run() {
+            Stop::Breakpoint => (),
+            Stop::StepLimit => return Status::StepLimit,
+            Stop::Timeout => return Status::Timeout,
+            Stop::Finished => break,
+        }
+    }
+    match vm.fault() {
+        Some(f) => Status::Fault(f.clone()),
+        None => Status::Finished,
+    }
+}
+
+/// Describes why a run that ended with `status` didn't finish.
+pub fn describe(status: &Status, vm: &VM, max_steps: usize) -> String {
+    match *status {
+        Status::Finished => "finished".to_string(),
+        Status::Fault(ref f) => format!("fault on step {}: {}", vm.steps(), f),
+        Status::StepLimit => format!("did not finish within {} steps", max_steps),
+        Status::Timeout => format!("timed out after {} steps", vm.steps()),
+    }
+}
+
+/// Compares the state of `vm` with `expect`, returning a description of every difference.
+pub fn check(vm: &VM, program: &Program, expect: &Expect) -> Result<Vec<String>, String> {
+    let mut diffs = Vec::new();
+    for (name, v) in &expect.registers {
+        let r = register(name)?;
+        let (expected, got) = (v.to_u64()?, vm.get_register(r));
+        if expected != got {
+            diffs.push(format!("{}: expected {}, got {}", r, show(expected), show(got)));
+        }
+    }
+    for m in &expect.memory {
+        let addr = m.address.to_address(program)?;
+        for (i, v) in m.values.iter().enumerate() {
+            let a = addr.wrapping_add(i as u64 * 8);
+            let expected = v.to_u64()?;
+            match vm.read_memory(a) {
+                Some(got) if got == expected => (),
+                Some(got) => diffs.push(format!("memory {:#x}: expected {}, got {}", a, show(expected), show(got))),
+                None => return Err(format!("{:#x} is not a doubleword address in main memory", a)),
+            }
+        }
+    }
+    if let Some(steps) = expect.steps {
+        if steps != vm.steps() {
+            diffs.push(format!("steps: expected {}, got {}", steps, vm.steps()));
+        }
+    }
+    if let Some(ref output) = expect.output {
+        // Trailing newlines are easy to get wrong in a spec and don't matter.
+        if output.trim_end() != vm.output().trim_end() {
+            diffs.push("output differs (- expected, + actual):".to_string());
+            diffs.extend(diff_lines(output, vm.output()));
+        }
+    }
+    Ok(diffs)
+}
+
+/// Shows a doubleword as a signed decimal and in hex.
+fn show(v: u64) -> String {
+    format!("{} ({:#x})", v as i64, v)
+}
+
+/// Returns a line diff of `a` and `b` with the lines only in `a` marked `-`, the lines only in `b`
+/// marked `+` and the common lines indented.
+pub fn diff_lines(a: &str, b: &str) -> Vec<String> {
+    let a: Vec<&str> = a.lines().collect();
+    let b: Vec<&str> = b.lines().collect();
+    // lcs[i][j] is the length of the longest common subsequence of a[i..] and b[j..].
+    let mut lcs = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] { lcs[i+1][j+1] + 1 } else { lcs[i+1][j].max(lcs[i][j+1]) };
+        }
+    }
+
+    let mut out = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            out.push(format!("  {}", a[i]));
+            i += 1;
+            j += 1;
+        } else if j == b.len() || (i < a.len() && lcs[i+1][j] >= lcs[i][j+1]) {
+            out.push(format!("- {}", a[i]));
+            i += 1;
+        } else {
+            out.push(format!("+ {}", b[j]));
+            j += 1;
+        }
+    }
+    out
+}
+
+/// Assembles the source at `path`. Each error is either rendered with a source snippet or, if
+/// `snippets` isn't set, given on one line as `file:line:column: message`.
+pub fn assemble_file(path: &Path, snippets: bool) -> Result<Program, Vec<String>> {
+    let mut buf = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut buf))
+        .map_err(|e| vec![format!("unable to read {}: {}", path.display(), e)])?;
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut errors = match assemble_source(&buf, dir) {
+        Ok(program) => {
+            if program.externs.is_empty() {
+                return Ok(program);
+            }
+            program.externs.iter()
+                .map(|(l, span)| AssembleError::new(*span, format!("Label `{}` is external", l)))
+                .collect()
+        }
+        Err(errors) => errors,
+    };
+    errors.sort_by_key(|e| (e.span.file, e.span.line, e.span.column));
+    let file = path.display().to_string();
+    Err(errors.iter_mut()
+        .map(|e| if snippets {
+            render_in(e, &file, &buf)
+        } else {
+            if e.file.is_empty() {
+                e.file = file.clone();
+            }
+            e.to_string()
+        })
+        .collect())
+}
+
+/// The result of testing one program.
+pub enum Outcome {
+    Pass(usize),
+    Fail(Vec<String>),
+    /// There is no spec for the program.
+    Skip,
+}
+
+/// Returns the spec beside `source`: the file with the same name ending in `.toml` or `.json`.
+pub fn spec_path(source: &Path) -> Option<PathBuf> {
+    ["toml", "json"].iter().map(|e| source.with_extension(e)).find(|p| p.exists())
+}
+
+/// Assembles and runs the program at `path` as its spec describes.
+pub fn test(path: &Path, max_steps: usize) -> Outcome {
+    let spec = match spec_path(path) {
+        Some(p) => match read_file::<Spec>(&p) {
+            Ok(spec) => spec,
+            Err(e) => return Outcome::Fail(vec![format!("unable to read {}: {}", p.display(), e)]),
+        },
+        None => return Outcome::Skip,
+    };
+    let program = match assemble_file(path, true) {
+        Ok(program) => program,
+        Err(errors) => return Outcome::Fail(errors.iter().flat_map(|e| e.lines()).map(str::to_string).collect()),
+    };
+    let mut vm = match load(&program, &spec) {
+        Ok(vm) => vm,
+        Err(e) => return Outcome::Fail(vec![format!("invalid spec: {}", e)]),
+    };
+
+    let max_steps = spec.max_steps.unwrap_or(max_steps);
+    let mut diffs = match execute(&mut vm, max_steps, None) {
+        Status::Finished => Vec::new(),
+        status => vec![describe(&status, &vm, max_steps)],
+    };
+    match check(&vm, &program, &spec.expect) {
+        Ok(d) => diffs.extend(d),
+        Err(e) => return Outcome::Fail(vec![format!("invalid spec: {}", e)]),
+    }
+    if diffs.is_empty() {
+        Outcome::Pass(vm.steps())
+    } else {
+        Outcome::Fail(diffs)
+    }
+}
+
+/// Tests every `.s` file in `dir`, or just `dir` if it is a file, printing the results. Returns
+/// true if no test failed.
+pub fn test_all(dir: &Path, max_steps: usize) -> Result<bool, String> {
+    let mut paths = if dir.is_dir() {
+        let entries = fs::read_dir(dir).map_err(|e| e.to_string())?;
+        entries.filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.extension().is_some_and(|e| e == "s"))
+            .collect()
+    } else {
+        vec![dir.to_path_buf()]
+    };
+    paths.sort();
+
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+    let mut out = String::new();
+    for path in paths {
+        let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        match test(&path, max_steps) {
+            Outcome::Pass(steps) => {
+                passed += 1;
+                writeln!(out, "PASS  {} ({} steps)", name, steps).unwrap();
+            }
+            Outcome::Fail(diffs) => {
+                failed += 1;
+                writeln!(out, "FAIL  {}", name).unwrap();
+                for d in diffs {
+                    writeln!(out, "      {}", d).unwrap();
+                }
+            }
+            Outcome::Skip => {
+                skipped += 1;
+                writeln!(out, "SKIP  {} (no spec)", name).unwrap();
+            }
+        }
+    }
+    writeln!(out, "\n{} passed, {} failed, {} skipped", passed, failed, skipped).unwrap();
+    print!("{}", out);
+    Ok(failed == 0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assemble::assemble;
+    use tokenizer::Tokenizer;
+
+    #[test]
+    fn checks_final_state() {
+        let (tokens, _) = Tokenizer::tokenize(".data\na: .dword 0, 0\n.text\n\
+            ADD X2, X0, X1\nPRNT X2\nPRNL\nADDI X9, XZR, a\nSTUR X2, [X9, #8]\n");
+        let program = assemble(tokens).unwrap();
+        let spec: Spec = toml::from_str(r#"
+            [registers]
+            X0 = 2
+            x1 = "0x3"
+
+            [expect]
+            steps = 5
+            output = "X2: 0x0000000000000005 (5)\n\n"
+
+            [expect.registers]
+            X2 = 5
+
+            [[expect.memory]]
+            address = "a"
+            values = [0, -1]
+        "#).unwrap();
+
+        let mut vm = load(&program, &spec).unwrap();
+        assert_eq!(execute(&mut vm, DEFAULT_MAX_STEPS, None), Status::Finished);
+        assert_eq!(check(&vm, &program, &spec.expect).unwrap(),
+                   vec!["memory 0x8: expected -1 (0xffffffffffffffff), got 5 (0x5)"]);
+
+        let mut vm = load(&program, &spec).unwrap();
+        vm.add_address_breakpoint(1);
+        assert_eq!(execute(&mut vm, DEFAULT_MAX_STEPS, None), Status::Finished);
+
+        let mut vm = load(&program, &spec).unwrap();
+        assert_eq!(execute(&mut vm, 2, None), Status::StepLimit);
+        assert!(load(&program, &toml::from_str("registers = { X32 = 1 }").unwrap()).is_err());
+    }
+
+    #[test]
+    fn parses_state_arguments() {
+        let (tokens, _) = Tokenizer::tokenize(".data\na: .dword 0, 0\n.text\nHALT\n");
+        let program = assemble(tokens).unwrap();
+        let mut state = State::default();
+        state.add_register("x1=0x100").unwrap();
+        state.add_register("LR=-1").unwrap();
+        state.add_memory("a=1,-2").unwrap();
+        assert!(state.add_register("X1").is_err());
+        assert!(state.add_memory("0x10=1,x").is_err());
+
+        let mut vm = VM::new();
+        set_state(&mut vm, &program, &state).unwrap();
+        assert_eq!(vm.get_register(Register(1)), 0x100);
+        assert_eq!(vm.get_register(Register(30)), u64::MAX);
+        assert_eq!(vm.read_memory(8), Some(-2i64 as u64));
+        state.add_memory("b=1").unwrap();
+        assert!(set_state(&mut vm, &program, &state).is_err());
+    }
+
+    #[test]
+    fn diffs_lines() {
+        assert_eq!(diff_lines("a\nb\nc\n", "a\nc\nd\n"), vec!["  a", "- b", "  c", "+ d"]);
+    }
+}
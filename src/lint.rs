@@ -0,0 +1,364 @@
+use assemble::{assemble_source_tokens, pc_to_line, Program};
+use bytecode::{Instruction, Opcode};
+use cfg::Cfg;
+use error::{AssembleError, Span};
+use register::Register;
+use tokenizer::Token;
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// SP, FP and XZR, which hold values before the program starts.
+const INITIALIZED: u32 = (1 << 28) | (1 << 29) | (1 << 31);
+/// IP0 and IP1, which a linker may overwrite on any call.
+const SCRATCH: u32 = (1 << 16) | (1 << 17);
+
+/// A likely mistake found without running the program.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Warning {
+    pub line: usize,
+    /// A short name for the check that found it, such as `unused-label`.
+    pub kind: &'static str,
+    pub message: String,
+}
+
+impl Warning {
+    fn new<S: Into<String>>(line: usize, kind: &'static str, message: S) -> Self {
+        Warning { line, kind, message: message.into() }
+    }
+}
+
+fn mnemonic(instr: Instruction) -> String {
+    format!("{:?}", instr).to_uppercase()
+}
+
+/// Returns the registers `op` reads and writes, as bit sets indexed by register number.
+fn registers(op: Opcode) -> (u32, u32) {
+    use bytecode::Instruction::*;
+    let bit = |r: Register| 1u32 << r.0;
+    match op.decode() {
+        Some(Ldur) => (bit(op.ldur_rn()), bit(op.ldur_rt())),
+        Some(Stur) => (bit(op.stur_rn()) | bit(op.stur_rt()), 0),
+        Some(Addi) | Some(Addis) | Some(Andi) | Some(Andis) | Some(Eori) | Some(Orri) | Some(Subi) |
+            Some(Subis) => (bit(op.addi_rn()), bit(op.addi_rd())),
+        Some(Lsl) | Some(Lsr) => (bit(op.lsl_rn()), bit(op.lsl_rd())),
+        Some(Cbz) | Some(Cbnz) => (bit(op.cbz_rt()), 0),
+        Some(Br) => (bit(op.br_rt()), 0),
+        Some(Prnt) => (bit(op.prnt_rd()), 0),
+        Some(Movz) => (0, bit(op.movz_rd())),
+        Some(Movk) => (bit(op.movk_rd()), bit(op.movk_rd())),
+        Some(Bl) => (0, 1 << 30),
+        Some(Add) | Some(Adds) | Some(And) | Some(Ands) | Some(Eor) | Some(Orr) | Some(Sub) | Some(Subs) |
+            Some(Mul) | Some(Sdiv) | Some(Udiv) | Some(Smulh) | Some(Umulh) => {
+            (bit(op.add_rn()) | bit(op.add_rm()), bit(op.add_rd()))
+        }
+        _ => (0, 0),
+    }
+}
+
+/// The registers that may have been written, and those of IP0 and IP1 that a call may have
+/// overwritten since, on some path to a point in the program.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+struct State {
+    written: u32,
+    clobbered: u32,
+}
+
+impl State {
+    fn join(self, other: State) -> State {
+        State { written: self.written | other.written, clobbered: self.clobbered | other.clobbered }
+    }
+}
+
+struct Linter<'a> {
+    program: &'a Program,
+    cfg: Cfg<'a>,
+    /// The registers written by each procedure and the procedures it calls, by entry block.
+    calls: HashMap<usize, u32>,
+}
+
+impl<'a> Linter<'a> {
+    fn new(program: &'a Program) -> Self {
+        let cfg = Cfg::new(program);
+        let mut calls = HashMap::new();
+        for b in cfg.blocks.iter().filter_map(|b| b.call) {
+            if let Some(entry) = cfg.blocks.iter().position(|block| block.start == b) {
+                calls.entry(entry).or_insert(0);
+            }
+        }
+        let mut linter = Linter { program, cfg, calls };
+        let entries: Vec<usize> = linter.calls.keys().cloned().collect();
+        for entry in entries {
+            let writes = linter.writes(entry);
+            linter.calls.insert(entry, writes);
+        }
+        linter
+    }
+
+    fn line(&self, pc: usize) -> usize {
+        pc_to_line(&self.program.line_map, pc).unwrap_or(0)
+    }
+
+    fn block_of(&self, pc: usize) -> Option<usize> {
+        self.cfg.blocks.iter().position(|b| b.start == pc)
+    }
+
+    /// Returns the registers written by the blocks reachable from `entry`, following calls.
+    fn writes(&self, entry: usize) -> u32 {
+        let mut seen = HashSet::new();
+        let mut stack = vec![entry];
+        let mut writes = 0;
+        while let Some(b) = stack.pop() {
+            if !seen.insert(b) {
+                continue;
+            }
+            let block = &self.cfg.blocks[b];
+            for &op in &self.program.code[block.start..block.end] {
+                writes |= registers(op).1;
+            }
+            stack.extend(&block.successors);
+            stack.extend(block.call.and_then(|c| self.block_of(c)));
+        }
+        writes
+    }
+
+    /// Runs `state` through block `b`, returning the state after it and the state on entry to the
+    /// procedure it calls. Reads of registers that were never written or that a call may have
+    /// overwritten are added to `warnings`.
+    fn transfer(&self, b: usize, mut state: State, mut warnings: Option<&mut Vec<Warning>>) -> (State, State) {
+        let block = &self.cfg.blocks[b];
+        let mut call = state;
+        for pc in block.start..block.end {
+            let op = self.program.code[pc];
+            let (reads, writes) = registers(op);
+            if let Some(warnings) = warnings.as_mut() {
+                for r in (0..32).filter(|r| reads & (1 << r) != 0).map(Register) {
+                    if state.written & (1 << r.0) == 0 {
+                        warnings.push(Warning::new(self.line(pc), "uninitialized",
+                                                   format!("{} is read but is not written on any path to here", r)));
+                    } else if state.clobbered & (1 << r.0) != 0 {
+                        warnings.push(Warning::new(self.line(pc), "scratch-register",
+                                                   format!("{} is read after a BL, which may overwrite it", r)));
+                    }
+                }
+            }
+            state.written |= writes;
+            state.clobbered &= !writes;
+            if let Some(c) = block.call.filter(|_| op.decode() == Some(Instruction::Bl)) {
+                call = State { written: state.written, clobbered: state.clobbered | SCRATCH };
+                let callee = self.block_of(c).and_then(|e| self.calls.get(&e)).cloned().unwrap_or(0);
+                state.written |= callee;
+                state.clobbered |= SCRATCH;
+            }
+        }
+        (state, call)
+    }
+
+    /// Follows the registers written along every path from the entry point.
+    fn registers(&self, warnings: &mut Vec<Warning>) {
+        let blocks = &self.cfg.blocks;
+        let mut states: Vec<Option<State>> = vec![None; blocks.len()];
+        match self.cfg.procedures.first() {
+            Some(main) => states[main.entry] = Some(State { written: INITIALIZED, clobbered: 0 }),
+            None => return,
+        }
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for b in 0..blocks.len() {
+                let state = match states[b] {
+                    Some(s) => s,
+                    None => continue,
+                };
+                let (out, call) = self.transfer(b, state, None);
+                let callee = blocks[b].call.and_then(|c| self.block_of(c));
+                let edges = blocks[b].successors.iter().map(|&s| (s, out)).chain(callee.map(|c| (c, call)));
+                for (s, state) in edges {
+                    let joined = states[s].map_or(state, |old| old.join(state));
+                    if states[s] != Some(joined) {
+                        states[s] = Some(joined);
+                        changed = true;
+                    }
+                }
+            }
+        }
+        for (b, state) in states.iter().enumerate() {
+            if let Some(state) = *state {
+                self.transfer(b, state, Some(warnings));
+            }
+        }
+    }
+
+    fn instructions(&self, warnings: &mut Vec<Warning>) {
+        use bytecode::Instruction::*;
+        let nop = Opcode::Orr(Register(31), Register(31), Register(31));
+        for (pc, &op) in self.program.code.iter().enumerate() {
+            let line = self.line(pc);
+            let instr = match op.decode() {
+                Some(i) => i,
+                None => continue,
+            };
+            // Flag-setting instructions are written to XZR to compare, as CMP and CMPI do.
+            let compare = matches!(instr, Adds | Subs | Ands | Addis | Subis | Andis);
+            if registers(op).1 & (1 << 31) != 0 && !compare && op != nop {
+                warnings.push(Warning::new(line, "xzr-write", format!("{} writes to XZR, which discards the result",
+                                                                     mnemonic(instr))));
+            }
+            let offset = match instr {
+                Ldur => Some(op.ldur_addr()),
+                Stur => Some(op.stur_addr()),
+                _ => None,
+            };
+            if let Some(offset) = offset.filter(|o| o % 8 != 0) {
+                warnings.push(Warning::new(line, "misaligned-offset",
+                                           format!("{} offset #{} is not a multiple of 8", mnemonic(instr), offset)));
+            }
+        }
+    }
+
+    fn control_flow(&self, warnings: &mut Vec<Warning>) {
+        let unreachable = self.cfg.unreachable();
+        let code = &self.program.code;
+        for (i, &b) in unreachable.iter().enumerate() {
+            // Only the first of a run of unreachable blocks is reported.
+            if i > 0 && unreachable[i - 1] + 1 == b {
+                continue;
+            }
+            let start = self.cfg.blocks[b].start;
+            let message = match start.checked_sub(1).and_then(|pc| code[pc].decode()) {
+                Some(i @ Instruction::B) | Some(i @ Instruction::Br) | Some(i @ Instruction::Halt) => {
+                    format!("Unreachable code after the {} on line {}", mnemonic(i), self.line(start - 1))
+                }
+                _ => "Unreachable code".to_string(),
+            };
+            warnings.push(Warning::new(self.line(start), "unreachable", message));
+        }
+
+        for (b, block) in self.cfg.blocks.iter().enumerate().filter(|(b, _)| !unreachable.contains(b)) {
+            let last = block.end - 1;
+            let instr = code[last].decode();
+            if matches!(instr, Some(Instruction::B) | Some(Instruction::Br) | Some(Instruction::Halt)) {
+                continue;
+            }
+            if block.end == code.len() {
+                warnings.push(Warning::new(self.line(last), "fall-through", "Execution runs past the last \
+                                           instruction; end the program with HALT or a branch"));
+                continue;
+            }
+            let next = self.block_of(block.end).filter(|n| self.calls.contains_key(n) && *n != b);
+            if let Some(next) = next.filter(|_| instr != Some(Instruction::Bl)) {
+                let start = self.cfg.blocks[next].start;
+                let mut names: Vec<&String> = self.program.labels.iter()
+                    .filter(|&(_, &i)| i == start)
+                    .map(|(l, _)| l)
+                    .collect();
+                names.sort();
+                let name = names.first().map_or_else(|| format!("{:#06x}", start * 4), |l| format!("`{}`", l));
+                warnings.push(Warning::new(self.line(last), "fall-through",
+                                           format!("Execution falls through into {}, which is called with BL", name)));
+            }
+        }
+    }
+
+    /// Reports labels that nothing refers to. The label at the entry point is used by starting
+    /// there, and `.global` counts as a use. Labels defined in included files are not reported.
+    fn labels(&self, tokens: &[Token], warnings: &mut Vec<Warning>) {
+        let mut definitions = Vec::new();
+        let mut used = HashSet::new();
+        let mut line = None;
+        let mut leading = true;
+        for t in tokens {
+            if !line.is_some_and(|l: Span| l.same_line(t.span())) {
+                line = Some(t.span());
+                leading = true;
+            }
+            match t {
+                Token::Label(span, l) if leading => if span.file == 0 {
+                    definitions.push((l, span.line));
+                },
+                Token::Label(_, l) => {
+                    used.insert(l);
+                }
+                _ => leading = false,
+            }
+        }
+        for (l, line) in definitions {
+            let entry = self.program.labels.get(l) == Some(&self.program.entry);
+            if !used.contains(l) && !entry {
+                warnings.push(Warning::new(line, "unused-label", format!("Label `{}` is never used", l)));
+            }
+        }
+    }
+}
+
+/// Checks `program`, assembled from `tokens`, for likely mistakes. The warnings are in source order
+/// with at most one of each message per line.
+pub fn lint(program: &Program, tokens: &[Token]) -> Vec<Warning> {
+    let linter = Linter::new(program);
+    let mut warnings = Vec::new();
+    linter.instructions(&mut warnings);
+    linter.registers(&mut warnings);
+    linter.control_flow(&mut warnings);
+    linter.labels(tokens, &mut warnings);
+    warnings.sort_by_key(|w| w.line);
+    let mut seen = HashSet::new();
+    warnings.retain(|w| seen.insert((w.line, w.message.clone())));
+    warnings
+}
+
+/// Assembles `source`, with `.include` paths relative to `dir`, and checks it.
+pub fn lint_source(source: &str, dir: &Path) -> Result<Vec<Warning>, Vec<AssembleError>> {
+    let (program, tokens) = assemble_source_tokens(source, dir)?;
+    Ok(lint(&program, &tokens))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn kinds(source: &str) -> Vec<(usize, &'static str)> {
+        lint_source(source, Path::new(".")).unwrap().iter().map(|w| (w.line, w.kind)).collect()
+    }
+
+    #[test]
+    fn finds_mistakes() {
+        let source = "\
+.data
+arr: .dword 1, 2
+spare: .dword 3
+.text
+main:
+    LDA X19, arr
+    ADD XZR, X19, X19
+    CMP X19, X1
+    MOVZ X16, #4
+    BL sum
+    PRNT X16
+    LDUR X9, [X19, #4]
+    B end
+    ADDI X0, X0, #1
+end:
+    PRNT X9
+sum:
+    ADD X0, X19, X2
+    BR LR
+";
+        assert_eq!(kinds(source), vec![(3, "unused-label"), (7, "xzr-write"), (8, "uninitialized"),
+                                       (11, "scratch-register"), (12, "misaligned-offset"), (14, "unreachable"),
+                                       (16, "fall-through"), (18, "uninitialized")]);
+
+        assert_eq!(kinds("main:\n    ADDI X0, XZR, #1\n    CBZ X0, main\n"), vec![(3, "fall-through")]);
+        // Values passed to a procedure and flags compared with CMP are fine, as is NOP.
+        let source = "\
+    ADDI X0, XZR, #2
+    BL double
+    CMPI X0, #4
+    NOP
+    HALT
+double:
+    ADD X0, X0, X0
+    BR LR
+";
+        assert_eq!(kinds(source), vec![]);
+    }
+}
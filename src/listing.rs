@@ -0,0 +1,140 @@
+use assemble::{pc_to_line, Fixup, Program};
+use bytecode::Opcode;
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// Returns the encoding of `op` in binary with a space between each field of its format.
+fn fields(op: Opcode) -> String {
+    let format = match op.decode() {
+        Some(i) => i.format(),
+        None => return format!("{:032b}", op.0),
+    };
+    let mut out = String::new();
+    let mut bit = 32;
+    for &(_, width) in format.fields() {
+        bit -= width;
+        if !out.is_empty() {
+            out.push(' ');
+        }
+        write!(out, "{:0width$b}", (op.0 >> bit) & ((1 << width) - 1), width = width as usize).unwrap();
+    }
+    out
+}
+
+/// Returns the name of the label at each instruction index, picking one deterministically when
+/// several labels share an address.
+pub fn label_names(labels: &HashMap<String, usize>) -> HashMap<usize, &String> {
+    let mut names: HashMap<usize, &String> = HashMap::new();
+    for (l, &i) in labels {
+        let name = names.entry(i).or_insert(l);
+        if l < *name {
+            *name = l;
+        }
+    }
+    names
+}
+
+/// Returns the first line of a listing, which names its columns.
+pub fn header() -> String {
+    format!("{:<8}  {:<8}  {:<36}  {:>5}  source", "address", "hex", "binary", "line")
+}
+
+/// Produces a listing of the assembled `program` next to its `source`. Every source line is shown;
+/// lines that produced instructions are preceded by the byte address, the encoding in hex and the
+/// encoding in binary split into the fields of its format. A symbol table follows.
+pub fn listing(source: &str, program: &Program) -> String {
+    let lines: Vec<&str> = source.lines().collect();
+    let names = label_names(&program.labels);
+    let mut by_line = vec![Vec::new(); lines.len()];
+    for pc in 0..program.code.len() {
+        if let Some(l) = pc_to_line(&program.line_map, pc) {
+            if l <= lines.len() {
+                by_line[l-1].push(pc);
+            }
+        }
+    }
+
+    let mut out = String::new();
+    writeln!(out, "{}", header()).unwrap();
+    for (i, line) in lines.iter().enumerate() {
+        if by_line[i].is_empty() {
+            writeln!(out, "{:<8}  {:<8}  {:<36}  {:>5}  {}", "", "", "", i + 1, line).unwrap();
+            continue;
+        }
+
+        for (n, &pc) in by_line[i].iter().enumerate() {
+            let op = program.code[pc];
+            let mut row = format!("{:08x}  {:08x}  {:<36}  ", pc * 4, op.0, fields(op));
+            // The source is only shown once for lines that expand to several instructions.
+            if n == 0 {
+                write!(row, "{:>5}  {}", i + 1, line).unwrap();
+            }
+            if let Some(offset) = op.branch_addr() {
+                let target = (pc as u32).wrapping_add(offset) as usize;
+                let external = program.relocations.iter()
+                    .find(|r| r.offset == pc && r.fixup == Fixup::Branch);
+                match (external, names.get(&target)) {
+                    (Some(r), _) => write!(row, "  -> {} (external)", r.symbol).unwrap(),
+                    (None, Some(l)) => write!(row, "  -> {} ({:08x})", l, target * 4).unwrap(),
+                    (None, None) => write!(row, "  -> {:08x}", target * 4).unwrap(),
+                }
+            }
+            writeln!(out, "{}", row.trim_end()).unwrap();
+        }
+    }
+
+    let mut symbols: Vec<_> = program.labels.iter().map(|(l, &i)| ("text", (i * 4) as u64, l))
+        .chain(program.data_labels.iter().map(|(l, &a)| ("data", a, l)))
+        .collect();
+    symbols.sort();
+    writeln!(out, "\nSymbols:").unwrap();
+    writeln!(out, "{:<8}  {:<7}  {:<6}  name", "address", "section", "scope").unwrap();
+    for (section, addr, l) in symbols {
+        let scope = if program.globals.contains(l) { "global" } else { "local" };
+        writeln!(out, "{:08x}  {:<7}  {:<6}  {}", addr, section, scope, l).unwrap();
+    }
+    let mut externs: Vec<_> = program.externs.iter().map(|(l, _)| l).collect();
+    externs.sort();
+    for l in externs {
+        writeln!(out, "{:<8}  {:<7}  {:<6}  {}", "", "", "extern", l).unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use Register;
+    use assemble::assemble;
+    use tokenizer::Tokenizer;
+
+    #[test]
+    fn lists_code_and_symbols() {
+        let source = ".data\nx: .dword 1\n.text\nmain: ADDI X0, XZR, x\nloop: CBZ X0, loop\n";
+        let program = assemble(Tokenizer::tokenize(source).0).unwrap();
+        assert_eq!(listing(source, &program), "\
+address   hex       binary                                 line  source
+                                                              1  .data
+                                                              2  x: .dword 1
+                                                              3  .text
+00000000  910003e0  1001000100 000000000000 11111 00000       4  main: ADDI X0, XZR, x
+00000004  b4000000  10110100 0000000000000000000 00000        5  loop: CBZ X0, loop  -> loop (00000004)
+
+Symbols:
+address   section  scope   name
+00000000  data     local   x
+00000000  text     local   main
+00000004  text     local   loop
+");
+    }
+
+    #[test]
+    fn splits_fields_by_format() {
+        assert_eq!(fields(Opcode::Add(Register(2), Register(1), Register(0))),
+                   "10001011000 00010 000000 00001 00000");
+        assert_eq!(fields(Opcode::Stur(Register(1), Register(0), -8)),
+                   "11111000000 111111000 00 00001 00000");
+        assert_eq!(fields(Opcode::Cbz(Register(3), 2)), "10110100 0000000000000000010 00011");
+    }
+}
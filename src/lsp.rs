@@ -0,0 +1,703 @@
+use assemble::assemble_source;
+use bytecode::Instruction;
+use error::Span;
+use register::Register;
+use tokenizer::{Directive, Pseudo, Token, Tokenizer};
+
+use serde_json::Value;
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+
+const DIRECTIVES: [(&str, &str); 14] = [
+    (".data", "Starts the data section"),
+    (".text", "Starts the text section"),
+    (".dword", "Emits 64-bit values"),
+    (".word", "Emits 32-bit values"),
+    (".byte", "Emits bytes"),
+    (".asciz", "Emits a string followed by a zero byte"),
+    (".space", "Emits the given number of zero bytes"),
+    (".align", "Pads the data section to a multiple of the given number of bytes"),
+    (".equ", "Defines a constant: `.equ NAME, expr`"),
+    (".macro", "Starts a macro: `.macro name param, ...`"),
+    (".endm", "Ends a macro"),
+    (".include", "Assembles another file in place: `.include \"file.s\"`"),
+    (".global", "Exports labels to other object files"),
+    (".extern", "Declares labels defined in other object files"),
+];
+
+/// The operands and a description of each pseudo-instruction.
+fn pseudo_doc(p: Pseudo) -> (&'static str, &'static str, &'static str) {
+    match p {
+        Pseudo::Mov => ("MOV", "Rd, Rm", "Copies Rm into Rd. Expands to `ORR Rd, XZR, Rm`."),
+        Pseudo::Cmp => ("CMP", "Rn, Rm", "Sets the flags from Rn - Rm. Expands to `SUBS XZR, Rn, Rm`."),
+        Pseudo::Cmpi => ("CMPI", "Rn, #imm", "Sets the flags from Rn - imm. Expands to `SUBIS XZR, Rn, #imm`."),
+        Pseudo::Lda => ("LDA", "Rd, label", "Loads the address of a label. Expands to `ADDI Rd, XZR, label`."),
+        Pseudo::Movi => ("MOVI", "Rd, #imm", "Loads a 64-bit immediate with a `MOVZ` and up to three `MOVK`s."),
+        Pseudo::Neg => ("NEG", "Rd, Rm", "Rd = -Rm. Expands to `SUB Rd, XZR, Rm`."),
+        Pseudo::Nop => ("NOP", "", "Does nothing. Expands to `ORR XZR, XZR, XZR`."),
+        Pseudo::Ret => ("RET", "", "Returns from a procedure. Expands to `BR LR`."),
+    }
+}
+
+const PSEUDOS: [Pseudo; 8] = [Pseudo::Mov, Pseudo::Cmp, Pseudo::Cmpi, Pseudo::Lda, Pseudo::Movi, Pseudo::Neg,
+                              Pseudo::Nop, Pseudo::Ret];
+
+/// The operands and a description of each instruction the assembler supports.
+fn instruction_doc(i: Instruction) -> Option<(&'static str, String)> {
+    use bytecode::Instruction::*;
+    let condition = match i {
+        Beq => "equal",
+        Bne => "not equal",
+        Bhs => "unsigned higher or same",
+        Blo => "unsigned lower",
+        Bmi => "negative",
+        Bpl => "positive or zero",
+        Bvs => "overflow",
+        Bvc => "no overflow",
+        Bhi => "unsigned higher",
+        Bls => "unsigned lower or same",
+        Bgt => "signed greater than",
+        Blt => "signed less than",
+        Bge => "signed greater than or equal",
+        Ble => "signed less than or equal",
+        _ => "",
+    };
+    if !condition.is_empty() {
+        return Some(("label", format!("Branches to label if the flags show {}", condition)));
+    }
+    let (operands, description) = match i {
+        Add => ("Rd, Rn, Rm", "Rd = Rn + Rm"),
+        Adds => ("Rd, Rn, Rm", "Rd = Rn + Rm, setting the flags"),
+        And => ("Rd, Rn, Rm", "Rd = Rn & Rm"),
+        Ands => ("Rd, Rn, Rm", "Rd = Rn & Rm, setting the flags"),
+        Eor => ("Rd, Rn, Rm", "Rd = Rn ^ Rm"),
+        Orr => ("Rd, Rn, Rm", "Rd = Rn | Rm"),
+        Sub => ("Rd, Rn, Rm", "Rd = Rn - Rm"),
+        Subs => ("Rd, Rn, Rm", "Rd = Rn - Rm, setting the flags"),
+        Mul => ("Rd, Rn, Rm", "Rd = the low 64 bits of Rn * Rm"),
+        Sdiv => ("Rd, Rn, Rm", "Rd = Rn / Rm, signed"),
+        Lsl => ("Rd, Rn, #shamt", "Rd = Rn << shamt"),
+        Lsr => ("Rd, Rn, #shamt", "Rd = Rn >> shamt, shifting in zeros"),
+        Addi => ("Rd, Rn, #imm", "Rd = Rn + imm"),
+        Addis => ("Rd, Rn, #imm", "Rd = Rn + imm, setting the flags"),
+        Andi => ("Rd, Rn, #imm", "Rd = Rn & imm"),
+        Andis => ("Rd, Rn, #imm", "Rd = Rn & imm, setting the flags"),
+        Eori => ("Rd, Rn, #imm", "Rd = Rn ^ imm"),
+        Orri => ("Rd, Rn, #imm", "Rd = Rn | imm"),
+        Subi => ("Rd, Rn, #imm", "Rd = Rn - imm"),
+        Subis => ("Rd, Rn, #imm", "Rd = Rn - imm, setting the flags"),
+        Ldur => ("Rt, [Rn, #offset]", "Rt = the doubleword at Rn + offset"),
+        Stur => ("Rt, [Rn, #offset]", "Stores Rt to the doubleword at Rn + offset"),
+        Movz => ("Rd, #imm, LSL #shift", "Rd = imm << shift"),
+        Movk => ("Rd, #imm, LSL #shift", "Replaces the 16 bits of Rd starting at shift with imm"),
+        B => ("label", "Branches to label"),
+        Bl => ("label", "Sets LR to the address of the next instruction and branches to label"),
+        Br => ("Rt", "Branches to the address in Rt"),
+        Cbz => ("Rt, label", "Branches to label if Rt is 0"),
+        Cbnz => ("Rt, label", "Branches to label if Rt is not 0"),
+        Prnt => ("Rd", "Prints Rd in hexadecimal and decimal"),
+        Prnl => ("", "Prints a newline"),
+        Dump => ("", "Shows the registers and memory when tracing"),
+        Halt => ("", "Stops the program"),
+        _ => return None,
+    };
+    Some((operands, description.to_string()))
+}
+
+/// The conventional use of a register.
+fn register_doc(r: Register) -> &'static str {
+    match r.0 {
+        0 ..= 7 => "Argument and result register",
+        8 => "Indirect result location register",
+        9 ..= 15 => "Temporary register",
+        16 | 17 => "Intra-procedure-call scratch register, which a linker may overwrite between a call and the \
+                    procedure",
+        18 => "Platform register",
+        19 ..= 27 => "Saved register, preserved across calls",
+        28 => "Stack pointer",
+        29 => "Frame pointer",
+        30 => "Link register, holding the return address",
+        _ => "Zero register: reads as 0 and ignores writes",
+    }
+}
+
+/// Where a symbol was defined, which decides how editors show it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum SymbolKind {
+    Code,
+    Data,
+    Constant,
+    Macro,
+}
+
+impl SymbolKind {
+    /// The LSP `SymbolKind`.
+    fn lsp(self) -> u32 {
+        match self {
+            SymbolKind::Code | SymbolKind::Macro => 12,
+            SymbolKind::Data => 13,
+            SymbolKind::Constant => 14,
+        }
+    }
+
+    /// The LSP `CompletionItemKind`.
+    fn completion(self) -> u32 {
+        match self {
+            SymbolKind::Code | SymbolKind::Data => 18,
+            SymbolKind::Constant => 21,
+            SymbolKind::Macro => 3,
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            SymbolKind::Code => "label",
+            SymbolKind::Data => "data label",
+            SymbolKind::Constant => "constant",
+            SymbolKind::Macro => "macro",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Symbol {
+    name: String,
+    kind: SymbolKind,
+    span: Span,
+}
+
+/// The tokens of a document along with the labels, constants and macros it defines and every
+/// place a name is used.
+struct Analysis {
+    tokens: Vec<Token>,
+    symbols: Vec<Symbol>,
+    references: Vec<(String, Span)>,
+}
+
+impl Analysis {
+    fn new(text: &str) -> Self {
+        let lines: Vec<&str> = text.lines().collect();
+        let char_at = |line: usize, column: usize| {
+            lines.get(line - 1).and_then(|l| l.chars().nth(column - 1))
+        };
+
+        let (tokens, _) = Tokenizer::tokenize(text);
+        let mut symbols = Vec::new();
+        let mut references = Vec::new();
+        let mut data = false;
+        for (i, t) in tokens.iter().enumerate() {
+            let (span, name) = match t {
+                Token::Directive(_, Directive::Data) => {
+                    data = true;
+                    continue;
+                }
+                Token::Directive(_, Directive::Text) => {
+                    data = false;
+                    continue;
+                }
+                Token::Label(span, name) => (*span, name),
+                // `#NAME` refers to a constant, without the `#`.
+                Token::Constant(span, name) if !name.starts_with('\\') => {
+                    references.push((name.clone(), Span::new(span.line, span.column + 1, span.len - 1)));
+                    continue;
+                }
+                _ => continue,
+            };
+            // Macro parameters only mean something inside their macro.
+            if name.starts_with('\\') {
+                continue;
+            }
+            let kind = match i.checked_sub(1).map(|i| &tokens[i]) {
+                Some(Token::Directive(s, Directive::Equ)) if s.line == span.line => Some(SymbolKind::Constant),
+                Some(Token::Directive(s, Directive::Macro)) if s.line == span.line => Some(SymbolKind::Macro),
+                _ if char_at(span.line, span.column + span.len) == Some(':') => {
+                    Some(if data { SymbolKind::Data } else { SymbolKind::Code })
+                }
+                _ => None,
+            };
+            match kind {
+                Some(kind) => symbols.push(Symbol { name: name.clone(), kind, span }),
+                None => references.push((name.clone(), span)),
+            }
+        }
+        Analysis { tokens, symbols, references }
+    }
+
+    fn definition(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|s| s.name == name)
+    }
+
+    /// Returns the token under the 0-based `line` and `character`. A position just after a token
+    /// also counts, as that is where the cursor is after typing it.
+    fn token_at(&self, line: usize, character: usize) -> Option<&Token> {
+        self.tokens.iter().find(|t| {
+            let s = t.span();
+            s.line == line + 1 && s.column <= character + 1 && character < s.column + s.len
+        })
+    }
+}
+
+/// Converts a 1-based span into an LSP range.
+fn range(span: Span) -> Value {
+    json!({
+        "start": { "line": span.line - 1, "character": span.column - 1 },
+        "end": { "line": span.line - 1, "character": span.column - 1 + span.len },
+    })
+}
+
+/// Returns the path of a `file://` URI, used to resolve `.include`s.
+fn uri_path(uri: &str) -> Option<PathBuf> {
+    let path = uri.strip_prefix("file://")?;
+    let mut bytes = Vec::new();
+    let mut chars = path.bytes();
+    while let Some(b) = chars.next() {
+        if b == b'%' {
+            let hex: Vec<u8> = chars.by_ref().take(2).collect();
+            let hex = String::from_utf8(hex).ok()?;
+            bytes.push(u8::from_str_radix(&hex, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+/// Returns the `file://` URI of `path`, which is absolute when it was resolved from a document.
+fn path_uri(path: &str) -> String {
+    let mut uri = String::from("file://");
+    for b in path.bytes() {
+        match b {
+            b'A' ..= b'Z' | b'a' ..= b'z' | b'0' ..= b'9' | b'/' | b'-' | b'.' | b'_' | b'~' => uri.push(b as char),
+            b => uri += &format!("%{:02X}", b),
+        }
+    }
+    uri
+}
+
+/// Returns the bits of `instr` for each field of its format, with operand bits shown as `x`.
+fn encoding_table(instr: Instruction) -> String {
+    let fields = instr.format().fields();
+    let (word, mask) = (instr.as_u32(), instr.operand_mask());
+    let mut bits = Vec::new();
+    let mut shift = 32;
+    for &(_, width) in fields {
+        shift -= width;
+        bits.push((0..width).rev().map(|b| {
+            let bit = 1 << (shift + b);
+            if mask & bit != 0 { 'x' } else if word & bit != 0 { '1' } else { '0' }
+        }).collect::<String>());
+    }
+    let names: Vec<&str> = fields.iter().map(|&(n, _)| n).collect();
+    format!("| {} |\n|{}\n| {} |", names.join(" | "), "---|".repeat(names.len()), bits.join(" | "))
+}
+
+fn instruction_hover(mnemonic: &str, instr: Instruction) -> String {
+    let mut text = format!("**{}** ({:?}-format)\n\n", mnemonic, instr.format());
+    match instruction_doc(instr) {
+        Some((operands, description)) => {
+            text += &format!("`{}`\n\n{}\n\n", format!("{} {}", mnemonic, operands).trim_end(), description);
+        }
+        None => text += "Not supported by the assembler.\n\n",
+    }
+    text + &format!("{}\n\nEncoding `{:#010x}`, operand bits `{:#010x}`", encoding_table(instr), instr.as_u32(),
+                    instr.operand_mask())
+}
+
+/// A language server for LEGv8 assembly. Documents are synchronised in full on every change.
+#[derive(Default)]
+pub struct Server {
+    documents: HashMap<String, String>,
+    /// The URIs of the included files that each document published diagnostics for.
+    included: HashMap<String, Vec<String>>,
+    shutdown: bool,
+}
+
+impl Server {
+    pub fn new() -> Self {
+        Server::default()
+    }
+
+    /// Handles one JSON-RPC message and returns the responses and notifications to send back.
+    pub fn handle(&mut self, message: &Value) -> Vec<Value> {
+        let method = message["method"].as_str().unwrap_or("");
+        let params = &message["params"];
+        let id = match message.get("id") {
+            Some(id) => id.clone(),
+            None => return self.notification(method, params),
+        };
+        let result = match method {
+            "initialize" => json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "completionProvider": {},
+                    "hoverProvider": true,
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "documentSymbolProvider": true,
+                },
+                "serverInfo": { "name": "legv8debug" },
+            }),
+            "shutdown" => {
+                self.shutdown = true;
+                Value::Null
+            }
+            "textDocument/completion" => self.completion(params),
+            "textDocument/hover" => self.hover(params),
+            "textDocument/definition" => self.definition(params),
+            "textDocument/references" => self.references(params),
+            "textDocument/documentSymbol" => self.symbols(params),
+            _ => {
+                return vec![json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": { "code": -32601, "message": format!("Unknown method `{}`", method) },
+                })];
+            }
+        };
+        vec![json!({ "jsonrpc": "2.0", "id": id, "result": result })]
+    }
+
+    /// Returns true once the client has asked the server to shut down.
+    pub fn is_shut_down(&self) -> bool {
+        self.shutdown
+    }
+
+    fn notification(&mut self, method: &str, params: &Value) -> Vec<Value> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("").to_string();
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or("");
+                self.documents.insert(uri.clone(), text.to_string());
+            }
+            "textDocument/didChange" => {
+                let changes = params["contentChanges"].as_array();
+                match changes.and_then(|c| c.last()).and_then(|c| c["text"].as_str()) {
+                    Some(text) => self.documents.insert(uri.clone(), text.to_string()),
+                    None => return vec![],
+                };
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                let mut messages = vec![publish(&uri, vec![])];
+                for included in self.included.remove(&uri).unwrap_or_default() {
+                    messages.push(publish(&included, vec![]));
+                }
+                return messages;
+            }
+            _ => return vec![],
+        }
+        self.diagnostics(&uri)
+    }
+
+    /// Assembles the document at `uri` and publishes its errors. Errors in included files are
+    /// published for those files, and files that no longer have errors are cleared.
+    fn diagnostics(&mut self, uri: &str) -> Vec<Value> {
+        let text = &self.documents[uri];
+        let dir = uri_path(uri).and_then(|p| p.parent().map(|p| p.to_path_buf())).unwrap_or_default();
+        let mut errors = assemble_source(text, &dir).err().unwrap_or_default();
+        errors.sort_by_key(|e| (e.span.file, e.span.line, e.span.column));
+
+        let mut files: Vec<(String, Vec<Value>)> = vec![(uri.to_string(), Vec::new())];
+        for e in errors {
+            let uri = if e.file.is_empty() { uri.to_string() } else { path_uri(&e.file) };
+            let diagnostic = json!({ "range": range(e.span), "severity": 1, "source": "legv8", "message": e.message });
+            match files.iter_mut().find(|(u, _)| *u == uri) {
+                Some((_, diagnostics)) => diagnostics.push(diagnostic),
+                None => files.push((uri, vec![diagnostic])),
+            }
+        }
+
+        let included: Vec<String> = files[1..].iter().map(|(u, _)| u.clone()).collect();
+        for old in self.included.insert(uri.to_string(), included).unwrap_or_default() {
+            if !files.iter().any(|(u, _)| *u == old) {
+                files.push((old, Vec::new()));
+            }
+        }
+        files.into_iter().map(|(uri, diagnostics)| publish(&uri, diagnostics)).collect()
+    }
+
+    /// Returns the analysis of the document in `params` and the 0-based position in it.
+    fn document(&self, params: &Value) -> Option<(Analysis, usize, usize)> {
+        let text = self.documents.get(params["textDocument"]["uri"].as_str()?)?;
+        let position = &params["position"];
+        let line = position["line"].as_u64().unwrap_or(0) as usize;
+        let character = position["character"].as_u64().unwrap_or(0) as usize;
+        Some((Analysis::new(text), line, character))
+    }
+
+    fn completion(&self, params: &Value) -> Value {
+        let mut items = Vec::new();
+        for m in Instruction::MNEMONICS {
+            let instr = Instruction::from_str(m).unwrap();
+            if let Some((operands, description)) = instruction_doc(instr) {
+                items.push(json!({ "label": m, "kind": 14, "detail": format!("{} {}", m, operands).trim_end(),
+                                   "documentation": description }));
+            }
+        }
+        for &p in &PSEUDOS {
+            let (name, operands, description) = pseudo_doc(p);
+            items.push(json!({ "label": name, "kind": 14, "detail": format!("{} {}", name, operands).trim_end(),
+                               "documentation": description }));
+        }
+        for &(name, description) in &DIRECTIVES {
+            items.push(json!({ "label": name, "kind": 14, "detail": description }));
+        }
+        for r in 0..32 {
+            let r = Register(r);
+            let name = r.to_string();
+            let mut names = vec![format!("X{}", r.0)];
+            if !name.starts_with('X') || r.0 == 31 {
+                names.push(name);
+            }
+            for n in names {
+                items.push(json!({ "label": n, "kind": 6, "detail": register_doc(r) }));
+            }
+        }
+        if let Some((analysis, _, _)) = self.document(params) {
+            for s in &analysis.symbols {
+                items.push(json!({ "label": s.name, "kind": s.kind.completion(),
+                                   "detail": format!("{} on line {}", s.kind.describe(), s.span.line) }));
+            }
+        }
+        Value::Array(items)
+    }
+
+    fn hover(&self, params: &Value) -> Value {
+        let (analysis, line, character) = match self.document(params) {
+            Some(d) => d,
+            None => return Value::Null,
+        };
+        let token = match analysis.token_at(line, character) {
+            Some(t) => t,
+            None => return Value::Null,
+        };
+        let text = self.documents[params["textDocument"]["uri"].as_str().unwrap()].lines().nth(line).unwrap_or("");
+        let span = token.span();
+        let word: String = text.chars().skip(span.column - 1).take(span.len).collect();
+        let value = match token {
+            Token::Instruction(_, i) => instruction_hover(&word, *i),
+            Token::Pseudo(_, p) => {
+                let (name, operands, description) = pseudo_doc(*p);
+                format!("**{}** (pseudo-instruction)\n\n`{}`\n\n{}", name, format!("{} {}", name, operands).trim_end(),
+                        description)
+            }
+            Token::Register(_, r) => {
+                let name = r.to_string();
+                let alias = if name.starts_with('X') { String::new() } else { format!(" ({})", name) };
+                format!("**X{}**{}\n\n{}", r.0, alias, register_doc(*r))
+            }
+            Token::Directive(_, d) => match DIRECTIVES.iter().find(|&&(n, _)| Directive::from_str(n) == Some(*d)) {
+                Some((name, description)) => format!("**{}**\n\n{}", name, description),
+                None => return Value::Null,
+            },
+            Token::Label(_, name) | Token::Constant(_, name) => match analysis.definition(name) {
+                Some(s) => format!("**{}**\n\n{} defined on line {}", s.name, s.kind.describe(), s.span.line),
+                None => return Value::Null,
+            },
+            _ => return Value::Null,
+        };
+        json!({ "contents": { "kind": "markdown", "value": value }, "range": range(span) })
+    }
+
+    /// Returns the name of the label under the cursor.
+    fn label_at(&self, params: &Value) -> Option<(Analysis, String)> {
+        let (analysis, line, character) = self.document(params)?;
+        let name = match analysis.token_at(line, character)? {
+            Token::Label(_, name) | Token::Constant(_, name) => name.clone(),
+            _ => return None,
+        };
+        Some((analysis, name))
+    }
+
+    fn definition(&self, params: &Value) -> Value {
+        let uri = &params["textDocument"]["uri"];
+        self.label_at(params)
+            .and_then(|(analysis, name)| analysis.definition(&name).map(|s| json!({ "uri": uri, "range": range(s.span) })))
+            .unwrap_or(Value::Null)
+    }
+
+    fn references(&self, params: &Value) -> Value {
+        let uri = &params["textDocument"]["uri"];
+        let (analysis, name) = match self.label_at(params) {
+            Some(l) => l,
+            None => return Value::Null,
+        };
+        let mut spans = Vec::new();
+        if params["context"]["includeDeclaration"].as_bool().unwrap_or(true) {
+            spans.extend(analysis.symbols.iter().filter(|s| s.name == name).map(|s| s.span));
+        }
+        spans.extend(analysis.references.iter().filter(|(n, _)| *n == name).map(|&(_, s)| s));
+        spans.sort_by_key(|s| (s.line, s.column));
+        Value::Array(spans.into_iter().map(|s| json!({ "uri": uri, "range": range(s) })).collect())
+    }
+
+    fn symbols(&self, params: &Value) -> Value {
+        let analysis = match params["textDocument"]["uri"].as_str().and_then(|u| self.documents.get(u)) {
+            Some(text) => Analysis::new(text),
+            None => return Value::Null,
+        };
+        Value::Array(analysis.symbols.iter().map(|s| json!({
+            "name": s.name,
+            "detail": s.kind.describe(),
+            "kind": s.kind.lsp(),
+            "range": range(s.span),
+            "selectionRange": range(s.span),
+        })).collect())
+    }
+}
+
+fn publish(uri: &str, diagnostics: Vec<Value>) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": { "uri": uri, "diagnostics": diagnostics },
+    })
+}
+
+/// Reads one message framed by a `Content-Length` header. Returns `None` at the end of input.
+fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(l) = line.strip_prefix("Content-Length:") {
+            length = l.trim().parse().ok();
+        }
+    }
+    let length = length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_message<W: Write>(output: &mut W, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+/// Serves the language server protocol on stdin and stdout until the client sends `exit`.
+/// Returns the exit code, which is 1 if the client exits without shutting down first.
+pub fn serve() -> i32 {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let stdout = io::stdout();
+    let mut output = stdout.lock();
+    let mut server = Server::new();
+    loop {
+        let message = match read_message(&mut input) {
+            Ok(Some(m)) => m,
+            Ok(None) => return 1,
+            Err(e) => {
+                eprintln!("lsp: {}", e);
+                continue;
+            }
+        };
+        if message["method"] == "exit" {
+            return if server.is_shut_down() { 0 } else { 1 };
+        }
+        for reply in server.handle(&message) {
+            if let Err(e) = write_message(&mut output, &reply) {
+                eprintln!("lsp: {}", e);
+                return 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SOURCE: &str = "\
+.equ N, 3
+.data
+arr: .dword 1, 2
+.text
+main:
+    ADDI X0, XZR, #N
+    LDA X1, arr
+loop:
+    CBZ X0, done
+    SUBI X0, X0, #1
+    B loop
+done:
+    HALT
+";
+
+    fn open(server: &mut Server, text: &str) -> Vec<Value> {
+        server.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": "file:///tmp/a.s", "text": text } },
+        }))
+    }
+
+    fn request(server: &mut Server, method: &str, line: usize, character: usize) -> Value {
+        let reply = server.handle(&json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": {
+                "textDocument": { "uri": "file:///tmp/a.s" },
+                "position": { "line": line, "character": character },
+                "context": { "includeDeclaration": true },
+            },
+        }));
+        reply[0]["result"].clone()
+    }
+
+    #[test]
+    fn finds_symbols_and_references() {
+        let a = Analysis::new(SOURCE);
+        let symbols: Vec<_> = a.symbols.iter().map(|s| (s.name.as_str(), s.kind, s.span.line)).collect();
+        assert_eq!(symbols, vec![("N", SymbolKind::Constant, 1), ("arr", SymbolKind::Data, 3),
+                                 ("main", SymbolKind::Code, 5), ("loop", SymbolKind::Code, 8),
+                                 ("done", SymbolKind::Code, 12)]);
+        assert_eq!(a.references, vec![("N".to_string(), Span::new(6, 20, 1)), ("arr".to_string(), Span::new(7, 13, 3)),
+                                      ("done".to_string(), Span::new(9, 13, 4)),
+                                      ("loop".to_string(), Span::new(11, 7, 4))]);
+    }
+
+    #[test]
+    fn answers_requests() {
+        let mut server = Server::new();
+        let diagnostics = open(&mut server, SOURCE);
+        assert_eq!(diagnostics[0]["params"]["diagnostics"], json!([]));
+
+        assert_eq!(request(&mut server, "textDocument/definition", 10, 8)["range"]["start"],
+                   json!({ "line": 7, "character": 0 }));
+        let references = request(&mut server, "textDocument/references", 4, 1);
+        assert_eq!(references.as_array().unwrap().len(), 1);
+        let references = request(&mut server, "textDocument/references", 8, 14);
+        assert_eq!(references.as_array().unwrap().len(), 2);
+
+        let hover = request(&mut server, "textDocument/hover", 5, 5);
+        let hover = hover["contents"]["value"].as_str().unwrap();
+        assert!(hover.starts_with("**ADDI** (I-format)\n\n`ADDI Rd, Rn, #imm`"), "{}", hover);
+        assert!(hover.contains("| 1001000100 | xxxxxxxxxxxx | xxxxx | xxxxx |"), "{}", hover);
+        assert!(request(&mut server, "textDocument/hover", 5, 14)["contents"]["value"].as_str().unwrap()
+            .contains("Zero register"));
+
+        let completion = request(&mut server, "textDocument/completion", 0, 0);
+        let labels: Vec<&str> = completion.as_array().unwrap().iter().map(|i| i["label"].as_str().unwrap()).collect();
+        for l in &["ADDI", "B.EQ", "MOV", ".dword", "X9", "SP", "XZR", "loop", "N"] {
+            assert!(labels.contains(l), "{}", l);
+        }
+        assert!(!labels.contains(&"FADDS"));
+
+        let diagnostics = open(&mut server, "    ADD X0, X1\n    B nowhere\n");
+        let messages: Vec<_> = diagnostics[0]["params"]["diagnostics"].as_array().unwrap().iter()
+            .map(|d| (d["range"]["start"]["line"].as_u64().unwrap(), d["severity"].as_u64().unwrap()))
+            .collect();
+        assert_eq!(messages, vec![(0, 1), (1, 1)]);
+    }
+}
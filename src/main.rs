@@ -1,121 +1,670 @@
 extern crate byteorder;
 extern crate clap;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate toml;
 
 mod assemble;
 mod disassemble;
+mod dump;
+mod error;
+mod executable;
+mod formats;
+mod formatter;
+mod grade;
+mod harness;
+mod lint;
+mod listing;
+mod lsp;
 mod bytecode;
+mod cfg;
+mod coverage;
+mod multicycle;
+mod object;
+mod preprocess;
+mod profiler;
 mod register;
 mod tokenizer;
 mod vm;
 
-use bytecode::Opcode;
+use assemble::Program;
+use coverage::Coverage;
+use dump::{DumpFormat, DUMP_FORMATS};
+use error::AssembleError;
+use formats::Format;
+use multicycle::MultiCycle;
+use profiler::Profiler;
 use register::Register;
 use vm::VM;
 
-use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
-use clap::{App, Arg, SubCommand};
+use clap::{App, Arg, ArgMatches, SubCommand};
 
-use std::fs::File;
-use std::io::{self, Cursor, Read, Write};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::panic;
+use std::path::Path;
+use std::process;
+use std::thread;
+
+/// The values accepted by `--format`.
+const FORMATS: [&str; 7] = ["exe", "raw", "ihex", "readmemh", "readmemb", "logisim", "listing"];
 
 fn main() {
     let matches = App::new("legv8debug")
         .subcommand(SubCommand::with_name("assemble")
             .arg(Arg::with_name("little-endian")
                 .short("le"))
+            .arg(Arg::with_name("object")
+                .long("object")
+                .short("c"))
+            .arg(Arg::with_name("listing")
+                .long("listing"))
+            .arg(Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&FORMATS))
+            .arg(Arg::with_name("comments")
+                .long("comments"))
             .arg(Arg::with_name("LEGv8 Assembly file")
                 .required(true)
                 .index(1)))
         .subcommand(SubCommand::with_name("disassemble")
             .arg(Arg::with_name("little-endian")
                 .short("le"))
+            .arg(Arg::with_name("pseudo")
+                .long("pseudo"))
+            .arg(Arg::with_name("addresses")
+                .long("addresses"))
+            .arg(Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&FORMATS))
             .arg(Arg::with_name("LEGv8 Binary file")
                 .required(true)
                 .index(1)))
+        .subcommand(SubCommand::with_name("cfg")
+            .arg(Arg::with_name("little-endian")
+                .short("le"))
+            .arg(Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&FORMATS))
+            .arg(Arg::with_name("LEGv8 file")
+                .required(true)
+                .index(1)))
+        .subcommand(SubCommand::with_name("link")
+            .arg(Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .takes_value(true))
+            .arg(Arg::with_name("LEGv8 Object files")
+                .required(true)
+                .multiple(true)
+                .index(1)))
         .subcommand(SubCommand::with_name("run")
+            .arg(Arg::with_name("multi-cycle")
+                .long("multi-cycle")
+                .short("m"))
+            .arg(Arg::with_name("profile")
+                .long("profile")
+                .short("p"))
+            .arg(Arg::with_name("profile-json")
+                .long("profile-json"))
+            .arg(Arg::with_name("coverage")
+                .long("coverage"))
+            .arg(Arg::with_name("dump")
+                .long("dump")
+                .takes_value(true)
+                .possible_values(&DUMP_FORMATS)
+                .help("How to show the machine's state, full by default"))
+            .arg(Arg::with_name("reg")
+                .long("reg")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Sets a register before the program starts, e.g. X0=5"))
+            .arg(Arg::with_name("mem")
+                .long("mem")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Sets doublewords of memory, e.g. 0x100=1,2,3"))
+            .arg(Arg::with_name("mem-file")
+                .long("mem-file")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Copies a file into memory, e.g. 0x100:input.bin"))
+            .arg(Arg::with_name("state")
+                .long("state")
+                .takes_value(true)
+                .help("Sets registers, flags and memory from a TOML or JSON file"))
+            .arg(Arg::with_name("little-endian")
+                .short("le"))
+            .arg(Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&FORMATS))
             .arg(Arg::with_name("LEGv8 Assembly file")
                 .required(true)
                 .index(1)))
         .subcommand(SubCommand::with_name("debug")
-            //.arg(Arg::with_name("binary")
-            //    .short("b"))
+            .arg(Arg::with_name("multi-cycle")
+                .long("multi-cycle")
+                .short("m"))
+            .arg(Arg::with_name("dump")
+                .long("dump")
+                .takes_value(true)
+                .possible_values(&DUMP_FORMATS)
+                .help("How to show the machine's state, full by default"))
+            .arg(Arg::with_name("reg")
+                .long("reg")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Sets a register before the program starts, e.g. X0=5"))
+            .arg(Arg::with_name("mem")
+                .long("mem")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Sets doublewords of memory, e.g. 0x100=1,2,3"))
+            .arg(Arg::with_name("mem-file")
+                .long("mem-file")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Copies a file into memory, e.g. 0x100:input.bin"))
+            .arg(Arg::with_name("state")
+                .long("state")
+                .takes_value(true)
+                .help("Sets registers, flags and memory from a TOML or JSON file"))
+            .arg(Arg::with_name("little-endian")
+                .short("le"))
+            .arg(Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&FORMATS))
+            .arg(Arg::with_name("LEGv8 Assembly file")
+                .required(true)
+                .index(1)))
+        .subcommand(SubCommand::with_name("test")
+            .arg(Arg::with_name("max-steps")
+                .long("max-steps")
+                .takes_value(true))
+            .arg(Arg::with_name("Directory")
+                .required(true)
+                .index(1)))
+        .subcommand(SubCommand::with_name("grade")
+            .arg(Arg::with_name("max-steps")
+                .long("max-steps")
+                .takes_value(true))
+            .arg(Arg::with_name("time-limit")
+                .long("time-limit")
+                .takes_value(true)
+                .help("Milliseconds each case may run for"))
+            .arg(Arg::with_name("jobs")
+                .long("jobs")
+                .short("j")
+                .takes_value(true)
+                .help("Submissions to grade at once when grading a directory"))
+            .arg(Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .takes_value(true)
+                .help("The report of a directory, as JSON if it ends in .json or CSV otherwise"))
+            .arg(Arg::with_name("Submission")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("Rubric")
+                .required(true)
+                .index(2)))
+        .subcommand(SubCommand::with_name("lsp"))
+        .subcommand(SubCommand::with_name("fmt")
+            .arg(Arg::with_name("check")
+                .long("check")
+                .help("Lists the files that aren't formatted instead of rewriting them"))
+            .arg(Arg::with_name("aliases")
+                .long("aliases")
+                .help("Writes registers as IP0, IP1, SP, FP and LR"))
+            .arg(Arg::with_name("LEGv8 Assembly files")
+                .required(true)
+                .multiple(true)
+                .index(1)))
+        .subcommand(SubCommand::with_name("lint")
             .arg(Arg::with_name("LEGv8 Assembly file")
                 .required(true)
                 .index(1)))
         .get_matches();
 
     if let Some(matches) = matches.subcommand_matches("assemble") {
+        let format = matches.value_of("format").and_then(Format::from_str).unwrap_or(Format::Raw);
         assemble(matches.value_of("LEGv8 Assembly file").unwrap(),
-                 matches.is_present("little-endian"));
+                 matches.is_present("little-endian"), matches.is_present("object"),
+                 matches.is_present("listing"), format, matches.is_present("comments"));
     } else if let Some(matches) = matches.subcommand_matches("disassemble") {
         disassemble(matches.value_of("LEGv8 Binary file").unwrap(),
-                    matches.is_present("little-endian"));
+                    matches.is_present("little-endian"), matches.is_present("pseudo"),
+                    matches.is_present("addresses"), matches.value_of("format").and_then(Format::from_str));
+    } else if let Some(matches) = matches.subcommand_matches("cfg") {
+        let filename = matches.value_of("LEGv8 file").unwrap();
+        let (_, program) = load_program(filename, matches.value_of("format").and_then(Format::from_str),
+                                        matches.is_present("little-endian"));
+        cfg(filename, &program);
+    } else if let Some(matches) = matches.subcommand_matches("link") {
+        link(matches.values_of("LEGv8 Object files").unwrap().collect(),
+             matches.value_of("output").unwrap_or("a.out"));
     } else if let Some(matches) = matches.subcommand_matches("run") {
-        run(matches.value_of("LEGv8 Assembly file").unwrap());
+        let profile = if matches.is_present("profile-json") {
+            Some(ProfileFormat::Json)
+        } else if matches.is_present("profile") {
+            Some(ProfileFormat::Text)
+        } else {
+            None
+        };
+        let (source, program) = load_program(matches.value_of("LEGv8 Assembly file").unwrap(),
+                                             matches.value_of("format").and_then(Format::from_str),
+                                             matches.is_present("little-endian"));
+        let state = initial_state(matches);
+        let options = RunOptions {
+            multi_cycle: matches.is_present("multi-cycle"),
+            profile,
+            coverage: matches.is_present("coverage"),
+            dump: dump_format(matches),
+        };
+        run(matches.value_of("LEGv8 Assembly file").unwrap(), source, program, &state, options);
     } else if let Some(matches) = matches.subcommand_matches("debug") {
-        debug(matches.value_of("LEGv8 Assembly file").unwrap());
+        let (source, program) = load_program(matches.value_of("LEGv8 Assembly file").unwrap(),
+                                             matches.value_of("format").and_then(Format::from_str),
+                                             matches.is_present("little-endian"));
+        let state = initial_state(matches);
+        debug(source, program, &state, dump_format(matches), matches.is_present("multi-cycle"));
+    } else if let Some(matches) = matches.subcommand_matches("test") {
+        let max_steps = parse_limit(matches.value_of("max-steps"), "--max-steps", harness::DEFAULT_MAX_STEPS);
+        let dir = matches.value_of("Directory").unwrap();
+        match harness::test_all(Path::new(dir), max_steps) {
+            Ok(true) => (),
+            Ok(false) => process::exit(1),
+            Err(e) => {
+                eprintln!("Unable to read {}: {}", dir, e);
+                process::exit(1);
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("grade") {
+        let limits = grade::Limits {
+            max_steps: parse_limit(matches.value_of("max-steps"), "--max-steps", harness::DEFAULT_MAX_STEPS),
+            time_limit_ms: parse_limit(matches.value_of("time-limit"), "--time-limit", grade::DEFAULT_TIME_LIMIT),
+        };
+        let submission = matches.value_of("Submission").unwrap();
+        let rubric = matches.value_of("Rubric").unwrap();
+        if Path::new(submission).is_dir() {
+            let jobs = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+            let jobs = parse_limit(matches.value_of("jobs"), "--jobs", jobs);
+            grade_all(submission, rubric, limits, jobs, matches.value_of("output").unwrap_or("grades.csv"));
+        } else {
+            grade(submission, rubric, limits);
+        }
+    } else if matches.subcommand_matches("lsp").is_some() {
+        process::exit(lsp::serve());
+    } else if let Some(matches) = matches.subcommand_matches("fmt") {
+        let options = formatter::Options { aliases: matches.is_present("aliases") };
+        if !fmt(matches.values_of("LEGv8 Assembly files").unwrap().collect(), options, matches.is_present("check")) {
+            process::exit(1);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("lint") {
+        if !lint(matches.value_of("LEGv8 Assembly file").unwrap()) {
+            process::exit(1);
+        }
     }
 }
 
-fn assemble(filename: &str, le: bool) {
-    let mut f = File::open(filename).unwrap();
-    let mut buf = String::new();
-    f.read_to_string(&mut buf).unwrap();
-
-    let tokens = tokenizer::Tokenizer::tokenize(&buf);
-    let (code, _) = assemble::assemble(tokens);
-    let mut c = Vec::new();
-    for op in code {
-        if le {
-            c.write_u32::<LittleEndian>(op.0).unwrap();
-        } else {
-            c.write_u32::<BigEndian>(op.0).unwrap();
+/// Prints each warning about `filename` with its source line. Returns false if there were any.
+fn lint(filename: &str) -> bool {
+    let source = match fs::read_to_string(filename) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Unable to read {}: {}", filename, e);
+            return false;
         }
+    };
+    let dir = Path::new(filename).parent().unwrap_or_else(|| Path::new("."));
+    let warnings = match lint::lint_source(&source, dir) {
+        Ok(w) => w,
+        Err(errors) => {
+            error::report(filename, &source, errors);
+            return false;
+        }
+    };
+    for w in &warnings {
+        println!("{}:{}: warning: {} [{}]", filename, w.line, w.message, w.kind);
+        println!("{:>5} | {}", w.line, source.lines().nth(w.line.saturating_sub(1)).unwrap_or("").trim_end());
+    }
+    if !warnings.is_empty() {
+        println!("{}", cfg::plural(warnings.len(), "warning"));
     }
+    warnings.is_empty()
+}
 
-    let mut f = File::create(format!("{}.machine", filename)).unwrap();
-    f.write_all(&c).unwrap();
+/// Formats each file in place, or with `check` reports the first line of each that would change.
+/// Returns false if a file couldn't be read or written, or isn't formatted when checking.
+fn fmt(filenames: Vec<&str>, options: formatter::Options, check: bool) -> bool {
+    let mut ok = true;
+    for filename in filenames {
+        let source = match fs::read_to_string(filename) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Unable to read {}: {}", filename, e);
+                ok = false;
+                continue;
+            }
+        };
+        let formatted = formatter::format(&source, options);
+        if formatted == source {
+            continue;
+        }
+        if check {
+            let line = source.lines().zip(formatted.lines()).take_while(|(a, b)| a == b).count() + 1;
+            println!("{}:{}: not formatted", filename, line);
+            ok = false;
+        } else if let Err(e) = fs::write(filename, formatted) {
+            eprintln!("Unable to write {}: {}", filename, e);
+            ok = false;
+        }
+    }
+    ok
 }
 
-fn disassemble(filename: &str, le: bool) {
-    let mut f = File::open(filename).unwrap();
-    let mut buf: Vec<u8> = Vec::new();
-    f.read_to_end(&mut buf).unwrap();
-    assert!(buf.len() % 4 == 0);
-    let len = buf.len();
+/// Collects the registers and memory to set before the program starts. Those given with `--reg`,
+/// `--mem` and `--mem-file` are set after the ones in the `--state` file.
+fn initial_state(matches: &ArgMatches) -> harness::State {
+    let state = match matches.value_of("state") {
+        Some(path) => harness::read_file(Path::new(path)).map_err(|e| format!("unable to read {}: {}", path, e)),
+        None => Ok(harness::State::default()),
+    };
+    let state = state.and_then(|mut state| {
+        for arg in matches.values_of("reg").into_iter().flatten() {
+            state.add_register(arg)?;
+        }
+        for arg in matches.values_of("mem").into_iter().flatten() {
+            state.add_memory(arg)?;
+        }
+        for arg in matches.values_of("mem-file").into_iter().flatten() {
+            state.add_memory_file(arg)?;
+        }
+        Ok(state)
+    });
+    state.unwrap_or_else(|e| {
+        eprintln!("Invalid initial state: {}", e);
+        process::exit(1);
+    })
+}
 
-    let mut rdr = Cursor::new(buf);
+fn dump_format(matches: &ArgMatches) -> DumpFormat {
+    matches.value_of("dump").and_then(DumpFormat::from_str).unwrap_or(DumpFormat::Full)
+}
 
-    let mut i = 0;
-    let mut code = Vec::new();
-    while i < len {
-        if le {
-            code.push(Opcode(rdr.read_u32::<LittleEndian>().unwrap()));
-        } else {
-            code.push(Opcode(rdr.read_u32::<BigEndian>().unwrap()));
+/// Creates a VM with `program` loaded and `state` set, exiting if `state` doesn't fit the
+/// program.
+fn load_vm(program: &Program, state: &harness::State) -> VM {
+    let mut vm = VM::new();
+    if let Err(e) = vm.load_data(&program.data, program.data_labels.clone()) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+    if let Err(e) = harness::set_state(&mut vm, program, state) {
+        eprintln!("Invalid initial state: {}", e);
+        process::exit(1);
+    }
+    vm.load_code(program.code.clone(), program.entry);
+    vm.load_line_map(program.line_map.clone());
+    vm
+}
+
+/// Parses the value of a limit given on the command line, exiting if it isn't a number.
+fn parse_limit<T: std::str::FromStr>(value: Option<&str>, flag: &str, default: T) -> T {
+    match value.map(str::parse) {
+        None => default,
+        Some(Ok(n)) => n,
+        Some(Err(_)) => {
+            eprintln!("{} must be a positive integer", flag);
+            process::exit(1);
         }
-        i += 4;
     }
+}
 
-    let asm = disassemble::disassemble(code);
-    for a in asm {
-        println!("{}", a);
+fn read_rubric(rubric: &str) -> grade::Rubric {
+    match harness::read_file(Path::new(rubric)) {
+        Ok(rubric) => rubric,
+        Err(e) => {
+            eprintln!("Unable to read {}: {}", rubric, e);
+            process::exit(1);
+        }
     }
 }
 
-fn debug(filename: &str) {
-    let mut f = File::open(filename).unwrap();
+/// Grades `submission` on the cases in `rubric`, printing the report and writing it as JSON to
+/// `{submission}.grade.json`.
+fn grade(submission: &str, rubric: &str, limits: grade::Limits) {
+    let rubric = read_rubric(rubric);
+    // Panics are reported as the result of the case that caused them.
+    panic::set_hook(Box::new(|_| ()));
+    let report = grade::grade_file(Path::new(submission), &rubric, limits);
+    let _ = panic::take_hook();
+
+    print!("{}", report.text());
+    let mut f = File::create(format!("{}.grade.json", submission)).unwrap();
+    f.write_all(serde_json::to_string_pretty(&report).unwrap().as_bytes()).unwrap();
+}
+
+/// Grades every submission in `dir` with `jobs` threads, printing each student's score and
+/// writing a row per student to `output`.
+fn grade_all(dir: &str, rubric: &str, limits: grade::Limits, jobs: usize, output: &str) {
+    let rubric = read_rubric(rubric);
+    panic::set_hook(Box::new(|_| ()));
+    let rows = grade::grade_all(Path::new(dir), &rubric, limits, jobs);
+    let _ = panic::take_hook();
+    let rows = match rows {
+        Ok(rows) => rows,
+        Err(e) => {
+            eprintln!("Unable to read {}: {}", dir, e);
+            process::exit(1);
+        }
+    };
+
+    for row in &rows {
+        let r = &row.report;
+        let note = if r.errors.is_empty() { String::new() } else { format!(" ({})", r.errors[0]) };
+        println!("{}: {}/{}{}", row.student, r.score, r.total, note);
+    }
+    let report = if output.ends_with(".json") {
+        serde_json::to_string_pretty(&rows).unwrap()
+    } else {
+        grade::csv(&rows, &rubric)
+    };
+    let mut f = File::create(output).unwrap();
+    f.write_all(report.as_bytes()).unwrap();
+}
+
+/// Reads and assembles `filename`, returning the source along with the assembled program. If the
+/// program contains errors they are all reported and the process exits.
+fn read_program(filename: &str) -> (String, Program) {
+    let (buf, program) = read_source(filename);
+    if !program.externs.is_empty() {
+        let errors = program.externs.iter()
+            .map(|(l, span)| AssembleError::new(*span, format!(
+                "Label `{}` is external, assemble with --object and link the program", l)))
+            .collect();
+        error::report(filename, &buf, errors);
+        process::exit(1);
+    }
+    (buf, program)
+}
+
+/// Loads the program to run from `filename`. Unless `format` is given the file may be assembly
+/// source, an executable, a linked object file or any of the formats written by `assemble`, which
+/// is recognised from its contents. Files ending in `.machine` are always read as raw machine code
+/// in the byte order given by `le`. The source is returned along with the program when it is
+/// available.
+fn load_program(filename: &str, format: Option<Format>, le: bool) -> (Option<String>, Program) {
+    let mut buf = Vec::new();
+    if let Err(e) = File::open(filename).and_then(|mut f| f.read_to_end(&mut buf)) {
+        eprintln!("Unable to read {}: {}", filename, e);
+        process::exit(1);
+    }
+
+    let program = match format {
+        Some(format) => formats::read(format, &buf, le),
+        None if object::is_object(&buf) => object::read(&buf).and_then(|p| {
+            match p.relocations.iter().find(|r| !p.labels.contains_key(&r.symbol) &&
+                                                 !p.data_labels.contains_key(&r.symbol)) {
+                Some(r) => Err(format!("label `{}` is external, link the object file first", r.symbol)),
+                None => Ok(p),
+            }
+        }),
+        None if filename.ends_with(".machine") => formats::read(Format::Raw, &buf, le),
+        None => match Format::detect(&buf) {
+            // Anything unrecognised that is text is taken to be assembly.
+            Format::Raw if std::str::from_utf8(&buf).is_ok() => {
+                let (source, program) = read_program(filename);
+                return (Some(source), program);
+            }
+            format => formats::read(format, &buf, le),
+        },
+    };
+
+    match program {
+        Ok(program) => (None, program),
+        Err(e) => {
+            eprintln!("Unable to read {}: {}", filename, e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Like `read_program` but allows references to external labels, for assembling object files.
+fn read_source(filename: &str) -> (String, Program) {
     let mut buf = String::new();
-    f.read_to_string(&mut buf).unwrap();
+    if let Err(e) = File::open(filename).and_then(|mut f| f.read_to_string(&mut buf)) {
+        eprintln!("Unable to read {}: {}", filename, e);
+        process::exit(1);
+    }
+
+    let dir = Path::new(filename).parent().unwrap_or_else(|| Path::new("."));
+    match assemble::assemble_source(&buf, dir) {
+        Ok(program) => (buf, program),
+        Err(errors) => {
+            error::report(filename, &buf, errors);
+            process::exit(1);
+        }
+    }
+}
 
-    let tokens = tokenizer::Tokenizer::tokenize(&buf);
-    let (code, line_map) = assemble::assemble(tokens);
+fn assemble(filename: &str, le: bool, object: bool, listing: bool, format: Format, comments: bool) {
+    let (buf, program) = if object { read_source(filename) } else { read_program(filename) };
+    if listing {
+        let mut f = File::create(format!("{}.lst", filename)).unwrap();
+        f.write_all(listing::listing(&buf, &program).as_bytes()).unwrap();
+    }
+    if object {
+        let mut f = File::create(format!("{}.o", filename)).unwrap();
+        f.write_all(&object::write(&program)).unwrap();
+        return;
+    }
 
-    let mut vm = VM::new();
-    vm.load_code(code);
-    vm.load_line_map(line_map);
+    let source = if comments { Some(&buf[..]) } else { None };
+    let mut f = File::create(format!("{}.{}", filename, format.extension())).unwrap();
+    f.write_all(&formats::write(format, &program, le, source)).unwrap();
+}
+
+/// Prints a summary of the control-flow graph of `program` and writes it to `{file}.dot`.
+fn cfg(filename: &str, program: &Program) {
+    let cfg = cfg::Cfg::new(program);
+    print!("{}", cfg.summary());
+    let mut f = File::create(format!("{}.dot", filename)).unwrap();
+    f.write_all(cfg.dot().as_bytes()).unwrap();
+}
+
+fn link(filenames: Vec<&str>, output: &str) {
+    let mut objects = Vec::new();
+    for filename in filenames {
+        let mut buf = Vec::new();
+        if let Err(e) = File::open(filename).and_then(|mut f| f.read_to_end(&mut buf)) {
+            eprintln!("Unable to read {}: {}", filename, e);
+            process::exit(1);
+        }
+        match object::read(&buf) {
+            Ok(o) => objects.push((filename.to_string(), o)),
+            Err(e) => {
+                eprintln!("Unable to read {}: {}", filename, e);
+                process::exit(1);
+            }
+        }
+    }
+
+    match object::link(&objects) {
+        Ok(program) => {
+            let mut f = File::create(output).unwrap();
+            f.write_all(&executable::write(&program, false)).unwrap();
+        }
+        Err(errors) => {
+            for e in &errors {
+                eprintln!("error: {}", e);
+            }
+            process::exit(1);
+        }
+    }
+}
+
+fn disassemble(filename: &str, le: bool, pseudo: bool, addresses: bool, format: Option<Format>) {
+    let mut f = File::open(filename).unwrap();
+    let mut buf: Vec<u8> = Vec::new();
+    f.read_to_end(&mut buf).unwrap();
+    let program = if format.is_none() && object::is_object(&buf) {
+        object::read(&buf)
+    } else {
+        formats::read(format.unwrap_or_else(|| Format::detect(&buf)), &buf, le)
+    };
+
+    match program {
+        Ok(program) => for a in disassemble::disassemble(&program, pseudo, addresses) {
+            println!("{}", a);
+        },
+        Err(e) => {
+            eprintln!("Unable to read {}: {}", filename, e);
+            process::exit(1);
+        }
+    }
+}
+
+fn debug(source: Option<String>, program: Program, state: &harness::State, format: DumpFormat, multi_cycle: bool) {
+    let source: Option<Vec<String>> = source.map(|s| s.lines().map(str::to_string).collect());
+    if program.line_map.is_empty() {
+        println!("There is no line information, set breakpoints with `b *ADDRESS` or `b LABEL`");
+    }
+    if source.is_none() {
+        println!("No source is available, `l` shows the disassembly");
+    }
+    let labels = program.labels.clone();
+    let names: HashMap<usize, String> = listing::label_names(&labels).into_iter()
+        .map(|(i, l)| (i, l.clone()))
+        .collect();
+
+    let mut vm = load_vm(&program, state);
+    vm.set_dump_format(format);
+    let mut mc = if multi_cycle { Some(MultiCycle::new()) } else { None };
+    // The state when the debugger last stopped and the time before, for `d diff`.
+    let mut current = vm.snapshot();
+    let mut previous = current.clone();
 
     loop {
+        let now = vm.snapshot();
+        if now != current {
+            previous = std::mem::replace(&mut current, now);
+        }
         print!("> ");
         io::stdout().flush().unwrap();
         let mut input = String::new();
@@ -138,11 +687,36 @@ fn debug(filename: &str) {
                     continue;
                 };
                 for _ in 0..i {
-                    vm.run();
+                    match mc {
+                        Some(ref mut mc) => mc.run(&mut vm),
+                        None => {
+                            vm.run();
+                        }
+                    }
                 }
             }
-            "d" => vm.dump(),
-            "s" => {
+            "d" => {
+                let input = input[1..].trim();
+                let format = if input.is_empty() {
+                    format
+                } else if let Some(f) = DumpFormat::from_str(input) {
+                    f
+                } else {
+                    println!("Expected one of {}", DUMP_FORMATS.join(", "));
+                    continue;
+                };
+                vm.dump_as(format, &previous);
+                if let (Some(ref mc), DumpFormat::Full) = (&mc, format) {
+                    mc.dump();
+                }
+            }
+            "c" => {
+                let mc = if let Some(ref mut mc) = mc {
+                    mc
+                } else {
+                    println!("Clock cycles can only be stepped in multi-cycle mode");
+                    continue;
+                };
                 let input = &input[1..].trim();
                 let i = if input.is_empty() {
                     1
@@ -154,19 +728,51 @@ fn debug(filename: &str) {
                 };
 
                 for _ in 0..i {
-                    vm.step();
+                    mc.cycle(&mut vm);
                 }
             }
-            "b" => {
+            "s" => {
                 let input = &input[1..].trim();
-                let i = if let Ok(i) = input.parse() {
+                let i = if input.is_empty() {
+                    1
+                } else if let Ok(i) = input.parse() {
                     i
                 } else {
                     println!("Expected a positive integer");
                     continue;
                 };
-                vm.add_breakpoint(i);
+
+                for _ in 0..i {
+                    match mc {
+                        Some(ref mut mc) => mc.step(&mut vm),
+                        None => vm.step(),
+                    }
+                }
             }
+            "b" => {
+                let input = &input[1..].trim();
+                if let Some(addr) = input.strip_prefix('*') {
+                    match parse_address(addr.trim()) {
+                        Some(a) if a.is_multiple_of(4) => vm.add_address_breakpoint(a / 4),
+                        _ => println!("Expected an instruction address, e.g. *0x10"),
+                    }
+                } else if let Some(&pc) = labels.get(*input) {
+                    vm.add_address_breakpoint(pc);
+                } else if let Ok(i) = input.parse() {
+                    if vm.line_map().is_empty() {
+                        println!("There is no line information, use `b *ADDRESS` or `b LABEL`");
+                        continue;
+                    }
+                    vm.add_breakpoint(i);
+                } else {
+                    println!("Expected a line number, *ADDRESS or label");
+                    continue;
+                }
+            }
+            "l" => match source {
+                Some(ref lines) if !vm.line_map().is_empty() => list_source(&vm, lines),
+                _ => list_disassembly(&vm, &names),
+            },
             "p" => {
                 let input = &input[1..].trim();
                 if let Some(r) = Register::from_str(input) {
@@ -183,17 +789,117 @@ fn debug(filename: &str) {
     }
 }
 
-fn run(filename: &str) {
-    let mut f = File::open(filename).unwrap();
-    let mut buf = String::new();
-    f.read_to_string(&mut buf).unwrap();
+/// Parses a byte address in hex with a `0x` prefix or in decimal.
+fn parse_address(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
 
-    let tokens = tokenizer::Tokenizer::tokenize(&buf);
-    let (code, line_map) = assemble::assemble(tokens);
+/// The number of lines `l` shows either side of the current one.
+const LIST_CONTEXT: usize = 3;
 
-    let mut vm = VM::new();
-    vm.load_code(code);
-    vm.load_line_map(line_map);
-    vm.run();
-    vm.dump();
+/// Prints the source around the line being executed.
+fn list_source(vm: &VM, lines: &[String]) {
+    let current = match assemble::pc_to_line(vm.line_map(), vm.pc()) {
+        Some(l) if !vm.is_finished() => l,
+        _ => {
+            println!("Reached end of program");
+            return;
+        }
+    };
+    let first = current.saturating_sub(LIST_CONTEXT).max(1);
+    for l in first..=(current + LIST_CONTEXT).min(lines.len()) {
+        let marker = if l == current { "=>" } else { "  " };
+        println!("{} {:>4}  {}", marker, l, lines[l-1]);
+    }
+}
+
+/// Prints the instructions around the one being executed along with their addresses.
+fn list_disassembly(vm: &VM, names: &HashMap<usize, String>) {
+    let pc = vm.pc();
+    for i in pc.saturating_sub(LIST_CONTEXT)..=pc + LIST_CONTEXT {
+        let op = match vm.instruction(i) {
+            Some(op) => op,
+            None => break,
+        };
+        if let Some(l) = names.get(&i) {
+            println!("            {}:", l);
+        }
+        let marker = if i == pc { "=>" } else { "  " };
+        match op.branch_addr().map(|o| (i as u32).wrapping_add(o) as usize) {
+            Some(target) => {
+                let label = names.get(&target).cloned().unwrap_or_else(|| format!("{:#x}", target * 4));
+                println!("{} {:08x}  {}", marker, i * 4, op.print_branch_label(&label));
+            }
+            None => println!("{} {:08x}  {}", marker, i * 4, op),
+        }
+    }
+    if vm.is_finished() {
+        println!("Reached end of program");
+    }
+}
+
+enum ProfileFormat {
+    Text,
+    Json,
+}
+
+/// How `run` executes the program and what it reports afterwards.
+struct RunOptions {
+    multi_cycle: bool,
+    profile: Option<ProfileFormat>,
+    coverage: bool,
+    dump: DumpFormat,
+}
+
+fn run(filename: &str, source: Option<String>, program: Program, state: &harness::State, options: RunOptions) {
+    let RunOptions { multi_cycle, profile, coverage, dump: format } = options;
+
+    let mut vm = load_vm(&program, state);
+    vm.set_dump_format(format);
+    // Only the final dump is printed in the other formats, so that it can be read by a program.
+    if format != DumpFormat::Full {
+        vm.set_trace(false);
+    }
+    if profile.is_some() {
+        vm.load_profiler(Profiler::new(program.code.len(), program.line_map.clone(), &program.labels));
+    }
+    if coverage {
+        vm.load_coverage(Coverage::new(program.code.clone(), program.line_map.clone()));
+    }
+    let start = vm.snapshot();
+    if multi_cycle {
+        let mut mc = MultiCycle::new();
+        mc.run(&mut vm);
+        vm.dump_as(format, &start);
+        if format == DumpFormat::Full {
+            mc.dump();
+        }
+    } else {
+        vm.run();
+        vm.dump_as(format, &start);
+    }
+    if format != DumpFormat::Full {
+        eprint!("{}", vm.output());
+        if let Some(f) = vm.fault() {
+            eprintln!("{}", f);
+        }
+    }
+
+    match (profile, vm.profiler()) {
+        (Some(ProfileFormat::Text), Some(p)) => p.report(source.as_deref()),
+        (Some(ProfileFormat::Json), Some(p)) => {
+            let mut f = File::create(format!("{}.profile.json", filename)).unwrap();
+            f.write_all(p.report_json().as_bytes()).unwrap();
+        }
+        _ => (),
+    }
+
+    if let Some(c) = vm.coverage() {
+        c.report(source.as_deref());
+        let mut f = File::create(format!("{}.lcov", filename)).unwrap();
+        f.write_all(c.lcov(filename).as_bytes()).unwrap();
+    }
 }
@@ -0,0 +1,293 @@
+use bytecode::Opcode;
+use register::Register;
+use vm::VM;
+
+use std::fmt;
+
+/// The states of the multi-cycle control FSM. Every instruction starts in `Fetch` and `Decode`,
+/// then follows a path that depends on its class:
+///
+/// - R and I format: Execute, WriteBack (4 cycles)
+/// - LDUR: Execute, Memory, WriteBack (5 cycles)
+/// - STUR: Execute, Memory (4 cycles)
+/// - branches: Execute (3 cycles)
+/// - PRNT, PRNL, DUMP and HALT complete in Decode (2 cycles)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum State {
+    Fetch,
+    Decode,
+    Execute,
+    Memory,
+    WriteBack,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            State::Fetch => f.pad("IF"),
+            State::Decode => f.pad("ID"),
+            State::Execute => f.pad("EX"),
+            State::Memory => f.pad("MEM"),
+            State::WriteBack => f.pad("WB"),
+        }
+    }
+}
+
+/// Drives a `VM` one clock cycle at a time following the textbook multi-cycle datapath. The
+/// architectural effects of an instruction are committed through `VM::step` in its final cycle,
+/// so both models always agree on the program's results.
+pub struct MultiCycle {
+    state: State,
+    ir: u32,
+    mdr: u64,
+    a: u64,
+    b: u64,
+    alu_out: u64,
+    cycles: usize,
+    instructions: usize,
+}
+
+impl MultiCycle {
+    pub fn new() -> Self {
+        MultiCycle {
+            state: State::Fetch,
+            ir: 0,
+            mdr: 0,
+            a: 0,
+            b: 0,
+            alu_out: 0,
+            cycles: 0,
+            instructions: 0,
+        }
+    }
+
+    /// Advances the datapath by a single clock cycle. Returns true if an instruction completed
+    /// during this cycle.
+    pub fn cycle(&mut self, vm: &mut VM) -> bool {
+        use bytecode::Instruction::*;
+        if vm.is_finished() {
+            if vm.is_tracing() {
+                println!("Reached end of program");
+            }
+            return false;
+        }
+
+        let state = self.state;
+        if state == State::Fetch {
+            self.ir = vm.current_instruction().unwrap().0;
+        }
+        let op = Opcode(self.ir);
+        let instr = match op.decode() {
+            Some(i) => i,
+            // Let the single-cycle emulator report the fault and stop, which ends the instruction.
+            None => {
+                vm.step();
+                self.state = State::Fetch;
+                return true;
+            }
+        };
+
+        let done = match state {
+            State::Fetch => {
+                self.state = State::Decode;
+                false
+            }
+            State::Decode => {
+                let (a, b) = Self::operands(op);
+                self.a = vm.get_register(a);
+                self.b = vm.get_register(b);
+                // Speculatively compute the branch target like the textbook datapath does.
+                self.alu_out = Self::branch_target(vm.pc(), op);
+                match instr {
+                    Prnt | Prnl | Dump | Halt => true,
+                    _ => {
+                        self.state = State::Execute;
+                        false
+                    }
+                }
+            }
+            State::Execute => match instr {
+                B | Bl | Br | Cbz | Cbnz | Beq | Bne | Bhs | Blo | Bmi | Bpl | Bvs | Bvc | Bhi |
+                Bls | Bgt | Bge | Blt | Ble => true,
+                Ldur | Stur => {
+                    self.alu_out = self.a.wrapping_add(op.ldur_addr() as u64);
+                    self.state = State::Memory;
+                    false
+                }
+                _ => {
+                    self.alu_out = self.alu(op);
+                    self.state = State::WriteBack;
+                    false
+                }
+            },
+            State::Memory => match instr {
+                Ldur => {
+                    let (rn, _) = Self::operands(op);
+                    self.mdr = vm.peek_memory(rn, op.ldur_addr() as i64).unwrap_or(0);
+                    self.state = State::WriteBack;
+                    false
+                }
+                _ => true,
+            },
+            State::WriteBack => true,
+        };
+
+        self.cycles += 1;
+        if vm.is_tracing() {
+            self.print_state(state);
+        }
+        if done {
+            vm.step();
+            self.instructions += 1;
+            self.state = State::Fetch;
+        }
+        done
+    }
+
+    /// Runs cycles until the instruction currently in flight completes.
+    pub fn step(&mut self, vm: &mut VM) {
+        if vm.is_finished() {
+            if vm.is_tracing() {
+                println!("Reached end of program");
+            }
+            return;
+        }
+
+        while !self.cycle(vm) {}
+    }
+
+    /// Runs until a breakpoint is hit or the program terminates.
+    pub fn run(&mut self, vm: &mut VM) {
+        while !vm.is_finished() {
+            if self.state == State::Fetch && vm.check_breakpoint() {
+                return;
+            }
+            self.cycle(vm);
+        }
+        if vm.is_tracing() {
+            println!("Reached end of program");
+        }
+    }
+
+    fn print_state(&self, state: State) {
+        println!("    [cycle {}] {:<3} IR={:08x} A={:#x} B={:#x} ALUOut={:#x} MDR={:#x}",
+                 self.cycles, state, self.ir, self.a, self.b, self.alu_out, self.mdr);
+    }
+
+    /// Prints the FSM state, the internal datapath registers and the cycle statistics.
+    pub fn dump(&self) {
+        println!("\nMulti-cycle datapath:");
+        println!("  Next state: {}", self.state);
+        println!("          IR: {:08x} ({})", self.ir, Opcode(self.ir));
+        println!("         MDR: {:#018x}", self.mdr);
+        println!("           A: {:#018x}", self.a);
+        println!("           B: {:#018x}", self.b);
+        println!("      ALUOut: {:#018x}", self.alu_out);
+        println!("Clock cycles: {}", self.cycles);
+        println!("Instructions: {}", self.instructions);
+        if self.instructions > 0 {
+            println!("         CPI: {:.2} (single-cycle CPI: 1.00)",
+                     self.cycles as f64 / self.instructions as f64);
+        }
+    }
+
+    /// Returns the registers read into A and B during decode.
+    fn operands(op: Opcode) -> (Register, Register) {
+        use bytecode::Instruction::*;
+        match op.instruction() {
+            Ldur | Stur | Ldurb | Ldurh | Ldursw | Ldxr | Sturb | Sturh | Sturw | Stxr =>
+                (op.ldur_rn(), op.ldur_rt()),
+            Addi | Addis | Andi | Andis | Eori | Orri | Subi | Subis => (op.addi_rn(), Register(31)),
+            Lsl | Lsr => (op.lsl_rn(), Register(31)),
+            Cbz | Cbnz => (Register(31), op.cbz_rt()),
+            Br => (op.br_rt(), Register(31)),
+            Prnt => (op.prnt_rd(), Register(31)),
+            B | Bl | Beq | Bne | Bhs | Blo | Bmi | Bpl | Bvs | Bvc | Bhi | Bls | Bgt | Bge | Blt |
+                Ble | Prnl | Dump | Halt | Movz => (Register(31), Register(31)),
+            Movk => (op.movk_rd(), Register(31)),
+            _ => (op.add_rn(), op.add_rm()),
+        }
+    }
+
+    fn branch_target(pc: usize, op: Opcode) -> u64 {
+        use bytecode::Instruction::*;
+        let offset = match op.instruction() {
+            B => op.b_addr(),
+            Bl => op.bl_addr(),
+            Cbz | Cbnz => op.cbz_addr(),
+            Beq | Bne | Bhs | Blo | Bmi | Bpl | Bvs | Bvc | Bhi | Bls | Bgt | Bge | Blt | Ble =>
+                op.beq_addr(),
+            _ => 0,
+        };
+        (pc as u32).wrapping_add(offset) as u64
+    }
+
+    fn alu(&self, op: Opcode) -> u64 {
+        use bytecode::Instruction::*;
+        let a = self.a;
+        let b = self.b;
+        match op.instruction() {
+            Add | Adds => a.wrapping_add(b),
+            Sub | Subs => a.wrapping_sub(b),
+            And | Ands => a & b,
+            Orr => a | b,
+            Eor => a ^ b,
+            Mul => a.wrapping_mul(b),
+            Addi | Addis => a.wrapping_add(op.addi_imm() as u64),
+            Subi | Subis => a.wrapping_sub(op.subi_imm() as u64),
+            Andi | Andis => a & op.andi_imm() as u64,
+            Orri => a | op.orri_imm() as u64,
+            Eori => a ^ op.eori_imm() as u64,
+            Lsl => a << op.lsl_shamt(),
+            Lsr => a >> op.lsr_shamt(),
+            Movz => (op.movz_imm() as u64) << op.movz_shift(),
+            Movk => (a & !(0xffff << op.movk_shift())) | ((op.movk_imm() as u64) << op.movk_shift()),
+            _ => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assemble::assemble;
+    use tokenizer::Tokenizer;
+
+    #[test]
+    fn counts_cycles_per_instruction_class() {
+        let (tokens, _) = Tokenizer::tokenize("ADDI X0, XZR, #8\nADD X1, X0, X0\nSTUR X1, [X0, #0]\n\
+                                               LDUR X2, [X0, #0]\nCBZ XZR, next\nnext: B end\nend: PRNL\n");
+        let program = assemble(tokens).unwrap();
+        let mut vm = VM::new();
+        vm.set_trace(false);
+        vm.load_code(program.code, 0);
+
+        let mut mc = MultiCycle::new();
+        let mut cycles = Vec::new();
+        while !vm.is_finished() {
+            let before = mc.cycles;
+            mc.step(&mut vm);
+            cycles.push(mc.cycles - before);
+        }
+        // I, R, STUR, LDUR, CB, B and PRNL.
+        assert_eq!(cycles, vec![4, 4, 4, 5, 3, 3, 2]);
+        assert_eq!((mc.cycles, mc.instructions), (25, 7));
+        assert_eq!(vm.get_register(Register(2)), 16);
+    }
+
+    #[test]
+    fn steps_onto_invalid_words() {
+        let (tokens, _) = Tokenizer::tokenize("ADDI X0, XZR, #1\n.word 0\nADDI X0, X0, #1\n");
+        let program = assemble(tokens).unwrap();
+        let mut vm = VM::new();
+        vm.set_trace(false);
+        vm.load_code(program.code, 0);
+
+        let mut mc = MultiCycle::new();
+        mc.step(&mut vm);
+        mc.step(&mut vm);
+        assert!(vm.is_finished());
+        assert!(vm.fault().is_some());
+        assert_eq!(vm.get_register(Register(0)), 1);
+    }
+}
@@ -0,0 +1,316 @@
+use assemble::{Fixup, Program, Relocation, ENTRY_LABEL};
+use bytecode::{Format, Opcode};
+use executable::read_count;
+use vm::HEAP_SIZE;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use std::collections::HashMap;
+use std::io::{self, Cursor, Read};
+
+/// The first bytes of every object file.
+pub const MAGIC: &[u8; 8] = b"LEGv8OBJ";
+const VERSION: u32 = 1;
+
+/// Returns true if `buf` holds an object file rather than raw machine code.
+pub fn is_object(buf: &[u8]) -> bool {
+    buf.starts_with(MAGIC)
+}
+
+/// Serializes an assembled program, including its symbols and relocations, so that it can be
+/// linked with others later. All values are little-endian.
+pub fn write(program: &Program) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.write_u32::<LittleEndian>(VERSION).unwrap();
+
+    out.write_u32::<LittleEndian>(program.code.len() as u32).unwrap();
+    for op in &program.code {
+        out.write_u32::<LittleEndian>(op.0).unwrap();
+    }
+    out.write_u32::<LittleEndian>(program.data.len() as u32).unwrap();
+    out.extend_from_slice(&program.data);
+    out.write_u32::<LittleEndian>(program.line_map.len() as u32).unwrap();
+    for &i in &program.line_map {
+        out.write_u32::<LittleEndian>(i as u32).unwrap();
+    }
+
+    // Sorted so that assembling the same source always produces the same file.
+    let mut symbols: Vec<_> = program.labels.iter().map(|(l, &i)| (l, 0, i as u64))
+        .chain(program.data_labels.iter().map(|(l, &a)| (l, 1, a)))
+        .collect();
+    symbols.sort();
+    out.write_u32::<LittleEndian>(symbols.len() as u32).unwrap();
+    for (l, section, value) in symbols {
+        out.write_u8(section).unwrap();
+        out.write_u8(program.globals.contains(l) as u8).unwrap();
+        out.write_u64::<LittleEndian>(value).unwrap();
+        write_str(&mut out, l);
+    }
+
+    out.write_u32::<LittleEndian>(program.relocations.len() as u32).unwrap();
+    for r in &program.relocations {
+        let (kind, size) = match r.fixup {
+            Fixup::Branch => (0, 0),
+            Fixup::Imm => (1, 0),
+            Fixup::Data(size) => (2, size as u8),
+        };
+        out.write_u8(kind).unwrap();
+        out.write_u8(size).unwrap();
+        out.write_u32::<LittleEndian>(r.offset as u32).unwrap();
+        write_str(&mut out, &r.symbol);
+    }
+    out
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    out.write_u32::<LittleEndian>(s.len() as u32).unwrap();
+    out.extend_from_slice(s.as_bytes());
+}
+
+/// Reads an object file written by `write`.
+pub fn read(buf: &[u8]) -> Result<Program, String> {
+    if !is_object(buf) {
+        return Err("not an object file".to_string());
+    }
+    let mut rdr = Cursor::new(&buf[MAGIC.len()..]);
+    read_program(&mut rdr).map_err(|e| format!("corrupt object file: {}", e))
+}
+
+fn read_program(rdr: &mut Cursor<&[u8]>) -> io::Result<Program> {
+    let version = rdr.read_u32::<LittleEndian>()?;
+    if version != VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported version {}", version)));
+    }
+
+    let mut code = Vec::new();
+    for _ in 0..read_count::<LittleEndian>(rdr, 4)? {
+        code.push(Opcode(rdr.read_u32::<LittleEndian>()?));
+    }
+    let mut data = vec![0; read_count::<LittleEndian>(rdr, 1)?];
+    if data.len() > HEAP_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+            "the data section is {} bytes but memory is only {} bytes", data.len(), HEAP_SIZE)));
+    }
+    rdr.read_exact(&mut data)?;
+    let mut line_map = Vec::new();
+    for _ in 0..read_count::<LittleEndian>(rdr, 4)? {
+        line_map.push(rdr.read_u32::<LittleEndian>()? as usize);
+    }
+
+    let mut labels = HashMap::new();
+    let mut data_labels = HashMap::new();
+    let mut globals = Vec::new();
+    // Each symbol is at least a section, a flag, a value and the length of its name.
+    for _ in 0..read_count::<LittleEndian>(rdr, 14)? {
+        let section = rdr.read_u8()?;
+        let global = rdr.read_u8()? != 0;
+        let value = rdr.read_u64::<LittleEndian>()?;
+        let name = read_str(rdr)?;
+        if global {
+            globals.push(name.clone());
+        }
+        if section == 0 {
+            labels.insert(name, value as usize);
+        } else {
+            data_labels.insert(name, value);
+        }
+    }
+
+    let mut relocations = Vec::new();
+    for _ in 0..read_count::<LittleEndian>(rdr, 10)? {
+        let kind = rdr.read_u8()?;
+        let size = rdr.read_u8()?;
+        let offset = rdr.read_u32::<LittleEndian>()? as usize;
+        let symbol = read_str(rdr)?;
+        let fixup = match kind {
+            0 => Fixup::Branch,
+            1 => Fixup::Imm,
+            2 => Fixup::Data(size as usize),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown relocation kind {}", kind))),
+        };
+        let in_bounds = match fixup {
+            Fixup::Branch | Fixup::Imm => offset < code.len(),
+            Fixup::Data(size) => size <= 8 && offset + size <= data.len(),
+        };
+        if !in_bounds {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("relocation of `{}` out of bounds", symbol)));
+        }
+        // Linking patches the field of the relocation, which must be there.
+        let patchable = match fixup {
+            Fixup::Branch => code[offset].branch_addr().is_some(),
+            Fixup::Imm => code[offset].decode().is_some_and(|i| i.format() == Format::I),
+            Fixup::Data(_) => true,
+        };
+        if !patchable {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "relocation of `{}` does not refer to {} instruction", symbol,
+                if fixup == Fixup::Branch { "a branch" } else { "an I-format" })));
+        }
+        relocations.push(Relocation { offset, fixup, symbol });
+    }
+
+    let entry = labels.get(ENTRY_LABEL).cloned().unwrap_or(0);
+    Ok(Program { code, entry, line_map, labels, data, data_labels, globals, relocations, externs: Vec::new() })
+}
+
+fn read_str(rdr: &mut Cursor<&[u8]>) -> io::Result<String> {
+    let mut buf = vec![0; read_count::<LittleEndian>(rdr, 1)?];
+    rdr.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[derive(Copy, Clone)]
+enum Symbol {
+    Code(usize),
+    Data(u64),
+}
+
+/// Combines `objects` into a single program. The code and data of each object follow those of the
+/// previous one, so execution starts with the first object. Labels are looked up in the object
+/// that refers to them first and then among the global labels of all objects. The lines of each
+/// object continue the numbering of the one before, after its last line of code.
+pub fn link(objects: &[(String, Program)]) -> Result<Program, Vec<String>> {
+    let mut code = Vec::new();
+    let mut data = Vec::new();
+    let mut errors = Vec::new();
+    let mut bases = Vec::new();
+    let mut line_map = Vec::new();
+    let mut globals: HashMap<&String, (Symbol, &String)> = HashMap::new();
+
+    for (name, object) in objects {
+        // Keep doublewords in each object aligned.
+        data.resize(data.len().div_ceil(8) * 8, 0);
+        let (text_base, data_base) = (code.len(), data.len() as u64);
+        bases.push((text_base, data_base));
+        code.extend_from_slice(&object.code);
+        data.extend_from_slice(&object.data);
+        line_map.extend(object.line_map.iter().map(|&i| text_base + i));
+
+        for g in &object.globals {
+            let symbol = match (object.labels.get(g), object.data_labels.get(g)) {
+                (Some(&i), _) => Symbol::Code(text_base + i),
+                (_, Some(&a)) => Symbol::Data(data_base + a),
+                _ => continue,
+            };
+            if let Some((_, other)) = globals.get(g) {
+                errors.push(format!("Label `{}` is defined in both {} and {}", g, other, name));
+            } else {
+                globals.insert(g, (symbol, name));
+            }
+        }
+    }
+
+    for ((name, object), &(text_base, data_base)) in objects.iter().zip(&bases) {
+        for r in &object.relocations {
+            let symbol = match (object.labels.get(&r.symbol), object.data_labels.get(&r.symbol)) {
+                (Some(&i), _) => Symbol::Code(text_base + i),
+                (_, Some(&a)) => Symbol::Data(data_base + a),
+                _ => match globals.get(&r.symbol) {
+                    Some(&(s, _)) => s,
+                    None => {
+                        errors.push(format!("Undefined label `{}` referenced in {}", r.symbol, name));
+                        continue;
+                    }
+                },
+            };
+            let value = match symbol {
+                Symbol::Code(i) => i as u64,
+                Symbol::Data(a) => a,
+            };
+
+            match r.fixup {
+                Fixup::Branch => {
+                    let pos = text_base + r.offset;
+                    match symbol {
+                        Symbol::Code(i) => code[pos] = code[pos].set_branch_addr(i.wrapping_sub(pos) as u32),
+                        Symbol::Data(_) => errors.push(format!(
+                            "Cannot branch to data label `{}` in {}", r.symbol, name)),
+                    }
+                }
+                Fixup::Imm if value >= 4096 => errors.push(format!(
+                    "The address of `{}` is {} which does not fit in a 12-bit immediate in {}",
+                    r.symbol, value, name)),
+                Fixup::Imm => {
+                    let pos = text_base + r.offset;
+                    code[pos] = code[pos].set_i_imm(value as u16);
+                }
+                Fixup::Data(size) => {
+                    let pos = data_base as usize + r.offset;
+                    data[pos..pos+size].copy_from_slice(&value.to_le_bytes()[..size]);
+                }
+            }
+        }
+    }
+
+    if data.len() > HEAP_SIZE {
+        errors.push(format!("The data section is {} bytes but memory is only {} bytes", data.len(), HEAP_SIZE));
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let mut labels = HashMap::new();
+    let mut data_labels = HashMap::new();
+    for (&g, &(s, _)) in &globals {
+        if let Symbol::Code(i) = s {
+            labels.insert(g.clone(), i);
+        } else if let Symbol::Data(a) = s {
+            data_labels.insert(g.clone(), a);
+        }
+    }
+    let mut names: Vec<_> = globals.keys().map(|&g| g.clone()).collect();
+    names.sort();
+    Ok(Program {
+        entry: labels.get(ENTRY_LABEL).cloned().unwrap_or(0),
+        code,
+        line_map,
+        labels,
+        data,
+        data_labels,
+        globals: names,
+        relocations: Vec::new(),
+        externs: Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use Register;
+    use assemble::{assemble, pc_to_line};
+    use tokenizer::Tokenizer;
+
+    fn object(src: &str) -> Program {
+        let (tokens, _) = Tokenizer::tokenize(src);
+        read(&write(&assemble(tokens).unwrap())).unwrap()
+    }
+
+    #[test]
+    fn links_objects() {
+        let main = object(".extern f, table\n.data\nx: .dword 1\n.text\nLDA X0, table\nBL f\n");
+        let lib = object(".global f, table\n.data\ntable: .dword table\n.text\nf: BR LR\n");
+        let program = link(&[("main".to_string(), main), ("lib".to_string(), lib)]).unwrap();
+        assert_eq!(program.code[0], Opcode::Addi(Register(31), Register(0), 8));
+        assert_eq!(program.code[1], Opcode::Bl(1));
+        assert_eq!(&program.data[8..], &[8, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(program.labels["f"], 2);
+        let lines: Vec<_> = (0..3).map(|pc| pc_to_line(&program.line_map, pc)).collect();
+        assert_eq!(lines, vec![Some(5), Some(6), Some(11)]);
+    }
+
+    #[test]
+    fn rejects_relocations_of_other_instructions() {
+        let mut buf = write(&assemble(Tokenizer::tokenize(".extern f\nBL f\n").0).unwrap());
+        // Replace the BL with an ADD, which has no offset to patch.
+        let code = MAGIC.len() + 8;
+        buf[code..code + 4].copy_from_slice(&Opcode::Add(Register(1), Register(2), Register(3)).0.to_le_bytes());
+        assert_eq!(read(&buf).unwrap_err(), "corrupt object file: relocation of `f` does not refer to a branch instruction");
+
+        let mut buf = write(&assemble(Tokenizer::tokenize(".extern f\nBL f\n").0).unwrap());
+        let kind = buf.len() - 4 - 1 - 4 - 1 - 1;
+        buf[kind] = 3;
+        assert_eq!(read(&buf).unwrap_err(), "corrupt object file: unknown relocation kind 3");
+    }
+}
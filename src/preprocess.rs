@@ -0,0 +1,559 @@
+use error::{AssembleError, Span};
+use tokenizer::{Directive, Token, Tokenizer};
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Macros may invoke other macros and files may include other files, but not so deeply that it
+/// must be a runaway recursion.
+const MAX_DEPTH: usize = 64;
+/// The number of macro invocations and includes expanded in total. A macro that invokes itself
+/// twice stays within `MAX_DEPTH` but would otherwise expand 2^64 times.
+const MAX_EXPANSIONS: usize = 100_000;
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Vec<Token>>,
+}
+
+struct Preprocessor {
+    /// The directory that `.include` paths in the file being preprocessed are relative to.
+    dir: PathBuf,
+    /// The included files, numbered by `Span::file` from 1.
+    files: Vec<PathBuf>,
+    constants: HashMap<String, i64>,
+    macros: HashMap<String, Macro>,
+    /// The number of expansions so far, counted against `MAX_EXPANSIONS`.
+    expansions: usize,
+    /// Set once a limit is reached, after which nothing more is expanded.
+    stopped: bool,
+    errors: Vec<AssembleError>,
+}
+
+/// Handles `.equ` constants and `.macro`/`.endm` definitions, returning the tokens with every
+/// constant replaced by its value and every macro invocation replaced by the macro's body. The
+/// tokens of an expansion are placed on the line of the invocation so that diagnostics and the
+/// line map refer to it. `.include` directives are replaced by the tokens of the file, which keep
+/// their own lines and are numbered by the file they came from. Paths are relative to the
+/// including file, starting at `dir`. The included files are returned in the order they were read.
+pub fn preprocess(tokens: Vec<Token>, dir: &Path) -> (Vec<Token>, Vec<PathBuf>, Vec<AssembleError>) {
+    let mut p = Preprocessor {
+        dir: dir.to_path_buf(),
+        files: Vec::new(),
+        constants: HashMap::new(),
+        macros: HashMap::new(),
+        expansions: 0,
+        stopped: false,
+        errors: Vec::new(),
+    };
+    let tokens = p.expand(split_lines(tokens), 0);
+    (tokens, p.files, p.errors)
+}
+
+fn split_lines(tokens: Vec<Token>) -> Vec<Vec<Token>> {
+    let mut lines: Vec<Vec<Token>> = Vec::new();
+    for t in tokens {
+        match lines.last_mut() {
+            Some(l) if l[0].span().same_line(t.span()) => l.push(t),
+            _ => lines.push(vec![t]),
+        }
+    }
+    lines
+}
+
+/// Moves the tokens of an expansion onto the line of `span`. Tokens that are already on that line
+/// came from the arguments of a macro and keep their position, everything else points at `span`.
+fn place(tokens: Vec<Token>, span: Span, out: &mut Vec<Token>) {
+    for mut t in tokens {
+        if !t.span().same_line(span) {
+            t.set_span(span);
+        }
+        out.push(t);
+    }
+}
+
+/// Returns true if the label at `i` in `line` refers to something rather than defining it. Labels
+/// are only defined at the start of a line.
+fn is_reference(line: &[Token], i: usize) -> bool {
+    line[..i].iter().any(|t| !matches!(t, Token::Label(..)))
+}
+
+/// Returns true if the token at `i` in `line` can be part of an expression.
+fn in_expression(line: &[Token], i: usize) -> bool {
+    match line[i] {
+        Token::Immediate(..) | Token::Constant(..) | Token::Operator(..) => true,
+        Token::Label(..) => is_reference(line, i),
+        _ => false,
+    }
+}
+
+/// Returns the error for `t` where a constant, macro or parameter name was expected. Names the
+/// assembler already knows are reserved, as they would not be read back as the name.
+fn expected_name(t: &Token, what: &str) -> AssembleError {
+    let reserved = match t {
+        Token::Register(..) => "Register",
+        Token::Instruction(..) => "Instruction",
+        Token::Pseudo(..) => "Pseudo-instruction",
+        Token::Directive(..) => "Directive",
+        _ => return AssembleError::new(t.span(), format!("Expected {} name", what)),
+    };
+    AssembleError::new(t.span(), format!("{} names are reserved and cannot name a {}", reserved, what))
+}
+
+impl Preprocessor {
+    fn expand(&mut self, lines: Vec<Vec<Token>>, depth: usize) -> Vec<Token> {
+        let mut out = Vec::new();
+        let mut lines = lines.into_iter();
+        while let Some(line) = lines.next() {
+            match line[0] {
+                Token::Directive(span, Directive::Equ) => {
+                    self.handle_equ(&line, span);
+                    continue;
+                }
+                Token::Directive(span, Directive::Macro) => {
+                    self.handle_macro(&line, span, &mut lines);
+                    continue;
+                }
+                Token::Directive(span, Directive::Endm) => {
+                    self.errors.push(AssembleError::new(span, "`.endm` without `.macro`"));
+                    continue;
+                }
+                Token::Directive(span, Directive::Include) => {
+                    let allowed = self.allow(span, depth, || format!(
+                        "Files are included more than {} deep", MAX_DEPTH));
+                    if let Some(tokens) = allowed.then(|| self.include(&line, span)).flatten() {
+                        // The `.include` is kept for the line map, followed by the file.
+                        out.extend(line);
+                        out.extend(self.expand(split_lines(tokens), depth + 1));
+                    }
+                    continue;
+                }
+                _ => (),
+            }
+
+            let mut line = self.evaluate(line);
+
+            // A macro is invoked by the last label before the first other token, as any labels
+            // before it are definitions.
+            let invocation = line.iter().position(|t| !matches!(t, Token::Label(..)))
+                .unwrap_or(line.len());
+            let name = match invocation.checked_sub(1).map(|i| &line[i]) {
+                Some(Token::Label(_, l)) if self.macros.contains_key(l) => l.clone(),
+                _ => {
+                    out.extend(line);
+                    continue;
+                }
+            };
+            let invocation = invocation - 1;
+            let span = line[invocation].span();
+            let args = line.split_off(invocation + 1);
+            line.pop();
+            out.extend(line);
+            if !self.allow(span, depth, || format!("Macro `{}` is nested more than {} times", name, MAX_DEPTH)) {
+                continue;
+            }
+            if let Some(body) = self.invoke(&name, args, span) {
+                let tokens = self.expand(body, depth + 1);
+                place(tokens, span, &mut out);
+            }
+        }
+        out
+    }
+
+    /// Returns true if an expansion at `span` nested `depth` deep is within the limits. The first
+    /// expansion over either limit is reported, with `too_deep` giving the message for `MAX_DEPTH`,
+    /// and nothing is expanded after it as the rest would only repeat the error.
+    fn allow<F: FnOnce() -> String>(&mut self, span: Span, depth: usize, too_deep: F) -> bool {
+        if self.stopped {
+            return false;
+        }
+        self.expansions += 1;
+        let message = if depth >= MAX_DEPTH {
+            too_deep()
+        } else if self.expansions > MAX_EXPANSIONS {
+            format!("Macros and includes are expanded more than {} times", MAX_EXPANSIONS)
+        } else {
+            return true;
+        };
+        self.errors.push(AssembleError::new(span, message));
+        self.stopped = true;
+        false
+    }
+
+    /// Reads and tokenizes the file named by `.include "path"`, relative to the file containing the
+    /// `.include`. The tokens and errors of the file are given the file's number.
+    fn include(&mut self, line: &[Token], span: Span) -> Option<Vec<Token>> {
+        let dir = match span.file {
+            0 => self.dir.as_path(),
+            f => self.files[f - 1].parent().unwrap_or_else(|| Path::new(".")),
+        };
+        let (path, span) = match line.get(1) {
+            Some(Token::Str(s, p)) if line.len() == 2 => (dir.join(p), *s),
+            Some(t) => {
+                self.errors.push(AssembleError::new(t.span(), "Expected file name"));
+                return None;
+            }
+            None => {
+                self.errors.push(AssembleError::new(span, "Expected file name"));
+                return None;
+            }
+        };
+
+        let mut buf = String::new();
+        if let Err(e) = File::open(&path).and_then(|mut f| f.read_to_string(&mut buf)) {
+            self.errors.push(AssembleError::new(span, format!("Unable to read {}: {}", path.display(), e)));
+            return None;
+        }
+        self.files.push(path);
+        let file = self.files.len();
+        let (mut tokens, errors) = Tokenizer::tokenize(&buf);
+        for mut e in errors {
+            e.span.file = file;
+            self.errors.push(e);
+        }
+        for t in tokens.iter_mut() {
+            let mut span = t.span();
+            span.file = file;
+            t.set_span(span);
+        }
+        Some(tokens)
+    }
+
+    /// Returns the body of the macro `name` with its parameters replaced by `args`.
+    fn invoke(&mut self, name: &str, args: Vec<Token>, span: Span) -> Option<Vec<Vec<Token>>> {
+        let mut values = vec![Vec::new()];
+        for t in args {
+            match t {
+                Token::Comma(_) => values.push(Vec::new()),
+                t => values.last_mut().unwrap().push(t),
+            }
+        }
+        if values.len() == 1 && values[0].is_empty() {
+            values.clear();
+        }
+
+        let m = &self.macros[name];
+        if values.len() != m.params.len() {
+            self.errors.push(AssembleError::new(span, format!(
+                "Macro `{}` takes {} argument{} but {} were given", name, m.params.len(),
+                if m.params.len() == 1 { "" } else { "s" }, values.len())));
+            return None;
+        }
+
+        let mut body = Vec::new();
+        for line in &m.body {
+            let mut out = Vec::new();
+            for t in line {
+                match t {
+                    Token::Label(s, l) | Token::Constant(s, l) if l.starts_with('\\') => {
+                        match m.params.iter().position(|p| *p == l[1..]) {
+                            Some(i) => out.extend(values[i].iter().cloned()),
+                            None => {
+                                self.errors.push(AssembleError::new(*s, format!(
+                                    "Macro `{}` has no parameter `{}`", name, &l[1..])));
+                                return None;
+                            }
+                        }
+                    }
+                    t => out.push(t.clone()),
+                }
+            }
+            body.push(out);
+        }
+        Some(body)
+    }
+
+    /// Replaces each expression in the operands of `line` by an immediate with its value. A label
+    /// on its own is an address unless it names a constant, but in an expression it must be one.
+    fn evaluate(&mut self, line: Vec<Token>) -> Vec<Token> {
+        let mut out = Vec::with_capacity(line.len());
+        let mut i = 0;
+        while i < line.len() {
+            let end = (i..line.len()).find(|&j| !in_expression(&line, j)).unwrap_or(line.len());
+            let expression = &line[i..end];
+            match expression {
+                [] => {
+                    out.push(line[i].clone());
+                    i += 1;
+                    continue;
+                }
+                [Token::Immediate(..)] => out.push(line[i].clone()),
+                [Token::Label(_, l)] if !self.constants.contains_key(l) => out.push(line[i].clone()),
+                _ => {
+                    let (first, last) = (expression[0].span(), expression[expression.len() - 1].span());
+                    // Parts of an expression from a macro body and from its arguments are on
+                    // different lines until the expansion is placed.
+                    let span = if first.same_line(last) && last.column >= first.column {
+                        Span { len: last.column + last.len - first.column, ..first }
+                    } else {
+                        first
+                    };
+                    let v = Expression::evaluate(expression, &self.constants, first).unwrap_or_else(|e| {
+                        self.errors.push(e);
+                        0
+                    });
+                    out.push(Token::Immediate(span, v));
+                }
+            }
+            i = end;
+        }
+        out
+    }
+
+    /// Handles `.equ NAME, expr`.
+    fn handle_equ(&mut self, line: &[Token], span: Span) {
+        let name = match line.get(1) {
+            Some(Token::Label(_, l)) => l.clone(),
+            Some(t) => return self.errors.push(expected_name(t, "constant")),
+            None => return self.errors.push(AssembleError::new(span, "Expected constant name")),
+        };
+        match line.get(2) {
+            Some(Token::Comma(_)) => (),
+            Some(t) => return self.errors.push(AssembleError::new(t.span(), "Expected comma")),
+            None => return self.errors.push(AssembleError::new(line[1].span(), "Expected comma")),
+        }
+
+        match Expression::evaluate(&line[3..], &self.constants, line[2].span()) {
+            Ok(v) => {
+                self.constants.insert(name, v);
+            }
+            Err(e) => self.errors.push(e),
+        }
+    }
+
+    /// Handles `.macro name param, ...` and reads the lines up to the matching `.endm`.
+    fn handle_macro<I: Iterator<Item = Vec<Token>>>(&mut self, line: &[Token], span: Span, lines: &mut I) {
+        let mut body = Vec::new();
+        let mut closed = false;
+        for l in lines.by_ref() {
+            match l[0] {
+                Token::Directive(_, Directive::Endm) => {
+                    closed = true;
+                    break;
+                }
+                Token::Directive(s, Directive::Macro) => {
+                    self.errors.push(AssembleError::new(s, "Macros cannot be defined inside a macro"));
+                }
+                _ => body.push(l),
+            }
+        }
+        if !closed {
+            return self.errors.push(AssembleError::new(span, "`.macro` without `.endm`"));
+        }
+
+        let name = match line.get(1) {
+            Some(Token::Label(_, l)) => l.clone(),
+            Some(t) => return self.errors.push(expected_name(t, "macro")),
+            None => return self.errors.push(AssembleError::new(span, "Expected macro name")),
+        };
+        let mut params = Vec::new();
+        for t in &line[2..] {
+            match t {
+                Token::Label(_, p) if !p.starts_with('\\') => params.push(p.clone()),
+                Token::Comma(_) => (),
+                t => return self.errors.push(expected_name(t, "parameter")),
+            }
+        }
+        self.macros.insert(name, Macro { params, body });
+    }
+}
+
+/// Evaluates an expression made of integers, constants, `+ - * /` and parentheses.
+struct Expression<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+    constants: &'a HashMap<String, i64>,
+    last: Span,
+}
+
+impl<'a> Expression<'a> {
+    /// Returns the value of `tokens`, which must all be part of the expression. `last` is the span
+    /// of the token before them, for reporting an empty expression.
+    fn evaluate(tokens: &[Token], constants: &HashMap<String, i64>, last: Span) -> Result<i64, AssembleError> {
+        let mut expr = Expression { tokens, pos: 0, constants, last };
+        let v = expr.parse()?;
+        match expr.tokens.get(expr.pos) {
+            Some(t) => Err(AssembleError::new(t.span(), "Expected operator")),
+            None => Ok(v),
+        }
+    }
+
+    fn next(&mut self) -> Option<&'a Token> {
+        let t = self.tokens.get(self.pos);
+        if let Some(t) = t {
+            self.pos += 1;
+            self.last = t.span();
+        }
+        t
+    }
+
+    fn error(&self, message: &str) -> AssembleError {
+        match self.tokens.get(self.pos) {
+            Some(t) => AssembleError::new(t.span(), message),
+            None => AssembleError::new(self.last.after(), message),
+        }
+    }
+
+    fn parse(&mut self) -> Result<i64, AssembleError> {
+        let mut v = self.term()?;
+        loop {
+            match self.tokens.get(self.pos) {
+                Some(Token::Operator(_, '+')) => {
+                    self.next();
+                    v = v.wrapping_add(self.term()?);
+                }
+                Some(Token::Operator(_, '-')) => {
+                    self.next();
+                    v = v.wrapping_sub(self.term()?);
+                }
+                _ => return Ok(v),
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<i64, AssembleError> {
+        let mut v = self.factor()?;
+        loop {
+            match self.tokens.get(self.pos) {
+                Some(Token::Operator(_, '*')) => {
+                    self.next();
+                    v = v.wrapping_mul(self.factor()?);
+                }
+                Some(Token::Operator(s, '/')) => {
+                    let s = *s;
+                    self.next();
+                    let d = self.factor()?;
+                    if d == 0 {
+                        return Err(AssembleError::new(s, "Division by zero"));
+                    }
+                    v = v.wrapping_div(d);
+                }
+                _ => return Ok(v),
+            }
+        }
+    }
+
+    fn factor(&mut self) -> Result<i64, AssembleError> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Immediate(_, v)) => {
+                self.next();
+                Ok(*v)
+            }
+            Some(Token::Label(s, l)) | Some(Token::Constant(s, l)) => match self.constants.get(l) {
+                Some(v) => {
+                    self.next();
+                    Ok(*v)
+                }
+                None => Err(AssembleError::new(*s, format!("Constant `{}` not defined", l))),
+            },
+            Some(Token::Operator(_, '-')) => {
+                self.next();
+                Ok(self.factor()?.wrapping_neg())
+            }
+            Some(Token::Operator(_, '(')) => {
+                self.next();
+                let v = self.parse()?;
+                match self.tokens.get(self.pos) {
+                    Some(Token::Operator(_, ')')) => {
+                        self.next();
+                        Ok(v)
+                    }
+                    _ => Err(self.error("Expected `)`")),
+                }
+            }
+            _ => Err(self.error("Expected value")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assemble::{assemble_source, pc_to_line};
+    use tokenizer::Tokenizer;
+
+    use std::{env, fs, process};
+
+    #[test]
+    fn expands_on_invocation_line() {
+        let src = ".equ SIZE, (1 + 2) * 8\n.macro push reg\nSUBI SP, SP, #SIZE\nSTUR \\reg, [SP, #0]\n.endm\npush X1\n";
+        let (tokens, errors) = Tokenizer::tokenize(src);
+        assert!(errors.is_empty());
+        let (tokens, _, errors) = preprocess(tokens, Path::new("."));
+        assert!(errors.is_empty());
+        assert!(tokens.iter().all(|t| t.line() == 6));
+        assert!(tokens.iter().any(|t| matches!(t, Token::Immediate(_, 24))));
+        assert!(tokens.iter().any(|t| matches!(t, Token::Register(s, r) if **r == 1 && s.column == 6)));
+    }
+
+    #[test]
+    fn evaluates_operand_expressions() {
+        let preprocessed = |src: &str| {
+            let (tokens, errors) = Tokenizer::tokenize(src);
+            assert!(errors.is_empty());
+            let (tokens, _, errors) = preprocess(tokens, Path::new("."));
+            let values = tokens.iter().filter_map(|t| match t {
+                Token::Immediate(_, v) => Some(*v),
+                _ => None,
+            }).collect::<Vec<_>>();
+            (values, errors.into_iter().map(|e| e.message).collect::<Vec<_>>())
+        };
+        let (values, errors) = preprocessed(".equ A, 5\n.equ C, A-1\nADDI X0, X0, #A-1\n\
+            SUBI X0, X0, #(A + 1) * C\nADDI X0, X0, #-8\n.dword A-1, C -1, -A\n\
+            .macro m v\nADDI X0, X0, #\\v\n.endm\nm -1\n");
+        assert!(errors.is_empty());
+        assert_eq!(values, vec![4, 24, -8, 4, 3, -5, -1]);
+
+        let (_, errors) = preprocessed("ADDI X0, X0, #undefined\n.dword 2 * label\n.equ B, 1\n.macro X0\n.endm\n");
+        assert_eq!(errors, vec![
+            "Constant `undefined` not defined",
+            "Constant `label` not defined",
+            "Instruction names are reserved and cannot name a constant",
+            "Register names are reserved and cannot name a macro",
+        ]);
+    }
+
+    #[test]
+    fn limits_total_expansions() {
+        let messages = |src: &str| {
+            let (tokens, _) = Tokenizer::tokenize(src);
+            let (_, _, errors) = preprocess(tokens, Path::new("."));
+            errors.into_iter().map(|e| e.message).collect::<Vec<_>>()
+        };
+        assert_eq!(messages(".macro m\nm\n.endm\nm\n"), vec!["Macro `m` is nested more than 64 times"]);
+        // Each expansion invokes `m` twice, which would take 2^64 expansions to reach the depth limit.
+        assert_eq!(messages(".macro m\nm\nm\n.endm\nm\n"),
+                   vec!["Macro `m` is nested more than 64 times"]);
+        // Six levels of macros that each invoke the next ten times stay shallow but expand a million
+        // times.
+        let mut src = ".macro m0\nNOP\n.endm\n".to_string();
+        for i in 1..=6 {
+            src += &format!(".macro m{}\n{}.endm\n", i, format!("m{}\n", i - 1).repeat(10));
+        }
+        assert_eq!(messages(&(src + "m6\n")), vec!["Macros and includes are expanded more than 100000 times"]);
+    }
+
+    #[test]
+    fn includes_keep_their_own_lines() {
+        let dir = env::temp_dir().join(format!("legv8-include-{}", process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/outer.s"), ".global f\nf:\n    ADDI X0, X0, #1\n    .include \"inner.s\"\n").unwrap();
+        fs::write(dir.join("sub/inner.s"), "g:\n    ADDI X1, X1, #2\n    BR LR\n").unwrap();
+
+        let program = assemble_source("    BL f\n    .include \"sub/outer.s\"\n    B g\n", &dir).unwrap();
+        assert_eq!(program.code.len(), 5);
+        assert_eq!(program.globals, vec!["f"]);
+        assert_eq!(program.labels["g"], 2);
+        let lines: Vec<_> = (0..5).map(|pc| pc_to_line(&program.line_map, pc)).collect();
+        assert_eq!(lines, vec![Some(1), Some(2), Some(2), Some(2), Some(3)]);
+
+        fs::write(dir.join("sub/inner.s"), "g:\n    ADDI X1, X1\n").unwrap();
+        let errors = assemble_source("    .include \"sub/outer.s\"\n", &dir).unwrap_err();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].file, dir.join("sub/inner.s").display().to_string());
+        assert_eq!((errors[0].span.line, errors[0].span.column), (2, 16));
+    }
+}
@@ -0,0 +1,263 @@
+use assemble::pc_to_line;
+use bytecode::{Instruction, Opcode};
+use listing::label_names;
+
+use std::collections::HashMap;
+
+/// Counts how often each instruction executes and attributes those counts to procedures by
+/// following the BL/BR call structure.
+pub struct Profiler {
+    counts: Vec<usize>,
+    line_map: Vec<usize>,
+    names: HashMap<usize, String>,
+    /// Entry points of the procedures that are currently active, innermost last.
+    stack: Vec<usize>,
+    calls: HashMap<usize, usize>,
+    inclusive: HashMap<usize, usize>,
+    exclusive: HashMap<usize, usize>,
+    total: usize,
+}
+
+#[derive(Serialize)]
+struct Procedure {
+    name: String,
+    calls: usize,
+    inclusive: usize,
+    exclusive: usize,
+}
+
+#[derive(Serialize)]
+struct LineCount {
+    line: usize,
+    count: usize,
+}
+
+#[derive(Serialize)]
+struct PcCount {
+    pc: usize,
+    count: usize,
+}
+
+#[derive(Serialize)]
+struct Json {
+    instructions: usize,
+    /// Sorted from hottest to coldest.
+    lines: Vec<LineCount>,
+    pcs: Vec<PcCount>,
+    procedures: Vec<Procedure>,
+}
+
+impl Profiler {
+    pub fn new(code_len: usize, line_map: Vec<usize>, labels: &HashMap<String, usize>) -> Self {
+        let names = label_names(labels).into_iter().map(|(i, l)| (i, l.clone())).collect();
+
+        let mut calls = HashMap::new();
+        calls.insert(0, 1);
+        Profiler {
+            counts: vec![0; code_len],
+            line_map,
+            names,
+            stack: vec![0],
+            calls,
+            inclusive: HashMap::new(),
+            exclusive: HashMap::new(),
+            total: 0,
+        }
+    }
+
+    /// Records the execution of the instruction at `pc`. Must be called before it executes.
+    pub fn record(&mut self, pc: usize) {
+        if let Some(c) = self.counts.get_mut(pc) {
+            *c += 1;
+        }
+        self.total += 1;
+
+        let top = *self.stack.last().unwrap();
+        *self.exclusive.entry(top).or_insert(0) += 1;
+        // Recursive procedures appear on the stack several times but are only credited once.
+        let mut seen = Vec::new();
+        for &p in &self.stack {
+            if !seen.contains(&p) {
+                seen.push(p);
+                *self.inclusive.entry(p).or_insert(0) += 1;
+            }
+        }
+    }
+
+    /// Updates the call stack after `op` has executed and moved the PC to `pc`.
+    pub fn update(&mut self, op: Opcode, pc: usize) {
+        match op.decode() {
+            Some(Instruction::Bl) => {
+                self.stack.push(pc);
+                *self.calls.entry(pc).or_insert(0) += 1;
+            }
+            Some(Instruction::Br) if self.stack.len() > 1 => {
+                self.stack.pop();
+            }
+            _ => (),
+        }
+    }
+
+    fn name(&self, pc: usize) -> String {
+        match self.names.get(&pc) {
+            Some(n) => n.clone(),
+            None if pc == 0 => "<entry>".to_string(),
+            None => format!("<pc {}>", pc),
+        }
+    }
+
+    fn line(&self, pc: usize) -> Option<usize> {
+        pc_to_line(&self.line_map, pc)
+    }
+
+    fn line_counts(&self) -> Vec<(usize, usize)> {
+        let mut lines = Vec::new();
+        for (pc, &c) in self.counts.iter().enumerate() {
+            if c == 0 {
+                continue;
+            }
+            if let Some(l) = self.line(pc) {
+                lines.push((l, c));
+            }
+        }
+        lines.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        lines
+    }
+
+    /// Returns the instructions that executed with their counts, hottest first.
+    fn pc_counts(&self) -> Vec<(usize, usize)> {
+        let mut pcs: Vec<_> = self.counts.iter().cloned().enumerate().filter(|&(_, c)| c > 0).collect();
+        pcs.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        pcs
+    }
+
+    fn procedures(&self) -> Vec<Procedure> {
+        let mut procs: Vec<_> = self.inclusive.iter().map(|(&pc, &inclusive)| Procedure {
+            name: self.name(pc),
+            calls: self.calls.get(&pc).cloned().unwrap_or(0),
+            inclusive,
+            exclusive: self.exclusive.get(&pc).cloned().unwrap_or(0),
+        }).collect();
+        procs.sort_by(|a, b| b.inclusive.cmp(&a.inclusive).then(a.name.cmp(&b.name)));
+        procs
+    }
+
+    fn percent(&self, c: usize) -> f64 {
+        if self.total == 0 {
+            0.0
+        } else {
+            c as f64 * 100.0 / self.total as f64
+        }
+    }
+
+    /// Prints the hot-spot report followed by the source annotated with execution counts. Without
+    /// the source, as when running an executable, hot spots are listed by address instead.
+    pub fn report(&self, source: Option<&str>) {
+        let lines: Vec<&str> = source.map_or_else(Vec::new, |s| s.lines().collect());
+
+        println!("\nProfile:");
+        println!("Instructions executed: {}", self.total);
+
+        println!("\nHot spots:");
+        if source.is_some() {
+            println!("{:>10} {:>7}  line", "count", "%");
+            for (l, c) in self.line_counts() {
+                println!("{:>10} {:>6.2}%  {:>4}: {}", c, self.percent(c), l,
+                         lines.get(l-1).map(|s| s.trim()).unwrap_or(""));
+            }
+        } else {
+            println!("{:>10} {:>7}  address", "count", "%");
+            for (pc, c) in self.pc_counts() {
+                println!("{:>10} {:>6.2}%  {:08x}", c, self.percent(c), pc * 4);
+            }
+        }
+
+        println!("\nProcedures:");
+        println!("{:>10} {:>10} {:>7} {:>10} {:>7}  name", "calls", "inclusive", "%", "exclusive", "%");
+        for p in self.procedures() {
+            println!("{:>10} {:>10} {:>6.2}% {:>10} {:>6.2}%  {}", p.calls, p.inclusive,
+                     self.percent(p.inclusive), p.exclusive, self.percent(p.exclusive), p.name);
+        }
+
+        if source.is_none() {
+            println!("\nNo source available to annotate.");
+            return;
+        }
+        let mut per_line = vec![0; lines.len()];
+        for (pc, &c) in self.counts.iter().enumerate() {
+            if let Some(l) = self.line(pc) {
+                if l <= per_line.len() {
+                    per_line[l-1] += c;
+                }
+            }
+        }
+
+        println!("\nAnnotated source:");
+        for (i, line) in lines.iter().enumerate() {
+            if per_line[i] > 0 {
+                println!("{:>10} | {}", per_line[i], line);
+            } else {
+                println!("{:>10} | {}", "", line);
+            }
+        }
+    }
+
+    /// Returns the profile as a JSON object.
+    pub fn report_json(&self) -> String {
+        let json = Json {
+            instructions: self.total,
+            lines: self.line_counts().into_iter().map(|(line, count)| LineCount { line, count }).collect(),
+            pcs: self.counts.iter().enumerate()
+                .filter(|&(_, &count)| count > 0)
+                .map(|(pc, &count)| PcCount { pc, count })
+                .collect(),
+            procedures: self.procedures(),
+        };
+        serde_json::to_string(&json).unwrap() + "\n"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assemble::assemble;
+    use tokenizer::Tokenizer;
+    use vm::VM;
+
+    #[test]
+    fn credits_recursive_calls_once() {
+        let (tokens, _) = Tokenizer::tokenize("main: ADDI X0, XZR, #2\nBL f\nHALT\n\
+                                               f: CBZ X0, done\nSUBI X0, X0, #1\nSUBI SP, SP, #8\n\
+                                               STUR LR, [SP, #0]\nBL f\nLDUR LR, [SP, #0]\nADDI SP, SP, #8\n\
+                                               done: BR LR\n");
+        let program = assemble(tokens).unwrap();
+        let mut vm = VM::new();
+        vm.set_trace(false);
+        vm.load_profiler(Profiler::new(program.code.len(), program.line_map.clone(), &program.labels));
+        vm.load_code(program.code, 0);
+        vm.run();
+
+        let p = vm.profiler().unwrap();
+        assert_eq!(p.total, 21);
+        let procs: Vec<_> = p.procedures().into_iter().map(|p| (p.name, p.calls, p.inclusive, p.exclusive)).collect();
+        // f runs three times, two of them inside itself, but its instructions are only counted once.
+        assert_eq!(procs, vec![("main".to_string(), 1, 21, 3), ("f".to_string(), 3, 18, 18)]);
+        assert_eq!(p.line_counts()[..2], [(4, 3), (11, 3)]);
+    }
+
+    #[test]
+    fn report_json_is_valid() {
+        let mut labels = HashMap::new();
+        labels.insert("main".to_string(), 0);
+        let mut p = Profiler::new(2, vec![0, 1], &labels);
+        p.record(0);
+        p.update(Opcode::Prnl(), 1);
+        let json: serde_json::Value = serde_json::from_str(&p.report_json()).unwrap();
+        assert_eq!(json, json!({
+            "instructions": 1,
+            "lines": [{ "line": 1, "count": 1 }],
+            "pcs": [{ "pc": 0, "count": 1 }],
+            "procedures": [{ "name": "main", "calls": 1, "inclusive": 1, "exclusive": 1 }],
+        }));
+    }
+}
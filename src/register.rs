@@ -1,7 +1,7 @@
 use std::fmt;
 use std::ops::Deref;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct Register(pub u8);
 
 impl Deref for Register {
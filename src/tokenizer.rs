@@ -1,150 +1,411 @@
 use bytecode::Instruction;
+use error::{AssembleError, Span};
 use register::Register;
 
+use std::iter::Peekable;
 use std::str::Chars;
 
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Directive {
+    Data,
+    Text,
+    Dword,
+    Word,
+    Byte,
+    Asciz,
+    Space,
+    Align,
+    Equ,
+    Macro,
+    Endm,
+    Include,
+    Global,
+    Extern,
+}
+
+impl Directive {
+    pub fn from_str(s: &str) -> Option<Self> {
+        use self::Directive::*;
+        Some(match s {
+            ".data" => Data,
+            ".text" => Text,
+            ".dword" => Dword,
+            ".word" => Word,
+            ".byte" => Byte,
+            ".asciz" => Asciz,
+            ".space" => Space,
+            ".align" => Align,
+            ".equ" => Equ,
+            ".macro" => Macro,
+            ".endm" => Endm,
+            ".include" => Include,
+            ".global" => Global,
+            ".extern" => Extern,
+            _ => return None,
+        })
+    }
+}
+
+/// Instructions that have no encoding of their own and are expanded by the assembler into one or
+/// more real instructions.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Pseudo {
+    Mov,
+    Cmp,
+    Cmpi,
+    Lda,
+    Movi,
+    Neg,
+    Nop,
+    Ret,
+}
+
+impl Pseudo {
+    pub fn from_str(s: &str) -> Option<Self> {
+        use self::Pseudo::*;
+        Some(match s {
+            "MOV" => Mov,
+            "CMP" => Cmp,
+            "CMPI" => Cmpi,
+            "LDA" => Lda,
+            "MOVI" => Movi,
+            "NEG" => Neg,
+            "NOP" => Nop,
+            "RET" => Ret,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone)]
 pub enum Token {
-    Comma(usize),
-    LBrace(usize),
-    RBrace(usize),
-    Immediate(usize, u16),
-    Label(usize, String),
-    Register(usize, Register),
-    Instruction(usize, Instruction),
+    Comma(Span),
+    LBrace(Span),
+    RBrace(Span),
+    Immediate(Span, i64),
+    Label(Span, String),
+    /// `#NAME`, which refers to a constant, or to a parameter inside a macro. The span includes
+    /// the `#`.
+    Constant(Span, String),
+    Register(Span, Register),
+    Instruction(Span, Instruction),
+    Pseudo(Span, Pseudo),
+    Directive(Span, Directive),
+    Str(Span, String),
+    /// One of `+ - * / ( )`, used in expressions.
+    Operator(Span, char),
 }
 
 impl Token {
-    pub fn line(&self) -> usize {
+    pub fn span(&self) -> Span {
         match self {
-            Token::Comma(l) => *l,
-            Token::LBrace(l) => *l,
-            Token::RBrace(l) => *l,
-            Token::Immediate(l, _) => *l,
-            Token::Label(l, _) => *l,
-            Token::Register(l, _) => *l,
-            Token::Instruction(l, _) => *l,
+            Token::Comma(s) => *s,
+            Token::LBrace(s) => *s,
+            Token::RBrace(s) => *s,
+            Token::Immediate(s, _) => *s,
+            Token::Label(s, _) => *s,
+            Token::Constant(s, _) => *s,
+            Token::Register(s, _) => *s,
+            Token::Instruction(s, _) => *s,
+            Token::Pseudo(s, _) => *s,
+            Token::Directive(s, _) => *s,
+            Token::Str(s, _) => *s,
+            Token::Operator(s, _) => *s,
         }
     }
+
+    pub fn set_span(&mut self, span: Span) {
+        *match self {
+            Token::Comma(s) => s,
+            Token::LBrace(s) => s,
+            Token::RBrace(s) => s,
+            Token::Immediate(s, _) => s,
+            Token::Label(s, _) => s,
+            Token::Constant(s, _) => s,
+            Token::Register(s, _) => s,
+            Token::Instruction(s, _) => s,
+            Token::Pseudo(s, _) => s,
+            Token::Directive(s, _) => s,
+            Token::Str(s, _) => s,
+            Token::Operator(s, _) => s,
+        } = span;
+    }
+
+    pub fn line(&self) -> usize {
+        self.span().line
+    }
 }
 
 pub struct Tokenizer<'a> {
     line: usize,
-    input: Chars<'a>,
+    column: usize,
+    input: Peekable<Chars<'a>>,
     tokens: Vec<Token>,
+    errors: Vec<AssembleError>,
 }
 
 impl<'a> Tokenizer<'a> {
-    pub fn tokenize(input: &'a str) -> Vec<Token> {
-        let input = input.chars();
+    /// Splits `input` into tokens. Lines containing errors are left out of the returned tokens
+    /// entirely so that the assembler does not report follow-on errors for them.
+    pub fn tokenize(input: &'a str) -> (Vec<Token>, Vec<AssembleError>) {
         let mut tokenizer = Tokenizer {
             line: 1,
-            input: input,
+            column: 1,
+            input: input.chars().peekable(),
             tokens: Vec::new(),
+            errors: Vec::new(),
         };
         tokenizer._tokenize();
-        tokenizer.tokens
+        (tokenizer.tokens, tokenizer.errors)
     }
 
     fn next(&mut self) -> Option<char> {
-        self.input.next()
+        let c = self.input.next();
+        if c == Some('\n') {
+            self.line += 1;
+            self.column = 1;
+        } else if c.is_some() {
+            self.column += 1;
+        }
+        c
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.input.peek().cloned()
+    }
+
+    fn skip_line(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == '\n' {
+                break;
+            }
+            self.next();
+        }
+    }
+
+    fn error<S: Into<String>>(&mut self, span: Span, message: S) {
+        self.errors.push(AssembleError::new(span, message));
+        while self.tokens.last().is_some_and(|t| t.line() == span.line) {
+            self.tokens.pop();
+        }
+        self.skip_line();
     }
 
     fn _tokenize(&mut self) {
-        while let Some(c) = self.next() {
+        loop {
+            let (line, column) = (self.line, self.column);
+            let c = if let Some(c) = self.next() {
+                c
+            } else {
+                break;
+            };
+            let span = Span::new(line, column, 1);
+
             match c {
-                '/' => if self.next() == Some('/') {
-                    while let Some(c) = self.next() {
-                        if c == '\n' {
-                            self.line += 1;
-                            break;
-                        }
-                    }
+                '/' => if self.peek() == Some('/') {
+                    self.skip_line();
                 } else {
-                    panic!("Unexpected `/` on line {}", self.line);
+                    self.tokens.push(Token::Operator(span, c));
                 },
-                'a' ..= 'z' | 'A' ..= 'Z' | '_' | '.' => self.handle_symbol(c),
-                '[' => self.tokens.push(Token::LBrace(self.line)),
-                ']' => self.tokens.push(Token::RBrace(self.line)),
-                ',' => self.tokens.push(Token::Comma(self.line)),
-                '#' => self.handle_immediate(),
-                '\n' => self.line += 1,
+                // Macro parameters are referred to as `\name`.
+                'a' ..= 'z' | 'A' ..= 'Z' | '_' | '.' | '\\' => self.handle_symbol(c, span),
+                '[' => self.tokens.push(Token::LBrace(span)),
+                ']' => self.tokens.push(Token::RBrace(span)),
+                ',' => self.tokens.push(Token::Comma(span)),
+                '#' => self.handle_immediate(span),
+                // Immediates may also be written without a leading `#`, which is how the values
+                // of data directives are usually written. After a value, `-` is a subtraction.
+                '-' if self.after_value(line) || !self.peek().is_some_and(|c| c.is_ascii_digit() || c == '\'') => {
+                    self.tokens.push(Token::Operator(span, c));
+                }
+                '0' ..= '9' | '-' | '\'' => self.handle_immediate_from(c, span),
+                '+' | '*' | '(' | ')' => self.tokens.push(Token::Operator(span, c)),
+                '"' => self.handle_string(span),
                 _ if c.is_whitespace() => (),
-                _ => panic!("Unexpected token on line {}", self.line),
+                _ => self.error(span, format!("Unexpected character `{}`", c)),
             }
         }
     }
 
-    fn handle_symbol(&mut self, c: char) {
+    /// Returns true if the last token on `line` is a value, so that a `-` after it subtracts. A
+    /// label only counts as an operand: at the start of the line it defines a label or invokes a
+    /// macro, whose first argument may be negative.
+    fn after_value(&self, line: usize) -> bool {
+        let mut tokens = self.tokens.iter().rev().take_while(|t| t.line() == line);
+        match tokens.next() {
+            Some(Token::Immediate(..)) | Some(Token::Constant(..)) | Some(Token::Operator(_, ')')) => true,
+            Some(Token::Label(..)) => tokens.any(|t| !matches!(t, Token::Label(..))),
+            _ => false,
+        }
+    }
+
+    fn read_symbol(&mut self, c: char) -> String {
         let mut buf = String::new();
         buf.push(c);
-        while let Some(c) = self.next() {
+        while let Some(c) = self.peek() {
             match c {
-                'a' ..= 'z' | 'A' ..= 'Z' | '0' ..= '9' | '_' | '.' => buf.push(c),
-                ':' => return self.tokens.push(Token::Label(self.line, buf)),
-                '\n' => {
-                    if let Some(r) = Register::from_str(&buf) {
-                        self.tokens.push(Token::Register(self.line, r));
-                    } else if let Some(i) = Instruction::from_str(&buf) {
-                        self.tokens.push(Token::Instruction(self.line, i));
-                    } else {
-                        self.tokens.push(Token::Label(self.line, buf));
-                    }
-                    self.line += 1;
-                    return;
-                },
-                ',' => {
-                    if let Some(r) = Register::from_str(&buf) {
-                        self.tokens.push(Token::Register(self.line, r));
-                    } else if let Some(i) = Instruction::from_str(&buf) {
-                        self.tokens.push(Token::Instruction(self.line, i));
-                    } else {
-                        self.tokens.push(Token::Label(self.line, buf));
-                    }
-                    self.tokens.push(Token::Comma(self.line));
-                    return;
+                'a' ..= 'z' | 'A' ..= 'Z' | '0' ..= '9' | '_' | '.' => {
+                    buf.push(c);
+                    self.next();
                 }
-                _ if c.is_whitespace() => break,
-                _ => panic!("Unexpected token on line {}", self.line),
+                _ => break,
             }
         }
+        buf
+    }
 
-        if let Some(r) = Register::from_str(&buf) {
-            self.tokens.push(Token::Register(self.line, r));
+    fn handle_symbol(&mut self, c: char, mut span: Span) {
+        let buf = self.read_symbol(c);
+        span.len = buf.len();
+
+        if self.peek() == Some(':') {
+            self.next();
+            self.tokens.push(Token::Label(span, buf));
+        } else if let Some(r) = Register::from_str(&buf) {
+            self.tokens.push(Token::Register(span, r));
         } else if let Some(i) = Instruction::from_str(&buf) {
-            self.tokens.push(Token::Instruction(self.line, i));
+            self.tokens.push(Token::Instruction(span, i));
+        } else if let Some(p) = Pseudo::from_str(&buf) {
+            self.tokens.push(Token::Pseudo(span, p));
+        } else if let Some(d) = Directive::from_str(&buf) {
+            self.tokens.push(Token::Directive(span, d));
         } else {
-            self.tokens.push(Token::Label(self.line, buf));
+            self.tokens.push(Token::Label(span, buf));
         }
     }
 
-    fn handle_immediate(&mut self) {
+    fn handle_immediate(&mut self, mut span: Span) {
+        match self.peek() {
+            // A symbolic immediate such as `#SIZE`, which is replaced by the value of the constant
+            // before assembling.
+            Some(c) if c.is_alphabetic() || c == '_' || c == '\\' => {
+                self.next();
+                let buf = self.read_symbol(c);
+                span.len = self.column - span.column;
+                self.tokens.push(Token::Constant(span, buf));
+            }
+            // An expression such as `#(SIZE + 1) * 8`.
+            Some('(') => (),
+            Some(c) if c == '\'' || c == '-' || c.is_alphanumeric() => {
+                self.next();
+                self.handle_immediate_from(c, span);
+            }
+            _ => self.error(span, "Expected immediate value after `#`"),
+        }
+    }
+
+    /// Handles an immediate whose first character `c` has already been consumed. `span` starts at
+    /// the `#` if there was one.
+    fn handle_immediate_from(&mut self, c: char, mut span: Span) {
+        if c == '\'' {
+            return self.handle_char(span);
+        }
+
         let mut buf = String::new();
-        while let Some(c) = self.next() {
-            match  c {
-                '0' ..= '9' => buf.push(c),
-                '\n' => if let Ok(i) = buf.parse() {
-                    self.tokens.push(Token::Immediate(self.line, i));
-                    self.line += 1;
-                    return;
-                } else {
-                    panic!("Invalid format for immediate value on line {}", self.line);
-                },
-                ']' => if let Ok(i) = buf.parse() {
-                    self.tokens.push(Token::Immediate(self.line, i));
-                    self.tokens.push(Token::RBrace(self.line));
-                    return;
-                } else {
-                    panic!("Invalid format for immediate value on line {}", self.line);
-                },
-                _ if c.is_whitespace() => break,
-                _ => panic!("Unexpected token on line {}", self.line),
+        buf.push(c);
+        while let Some(c) = self.peek() {
+            if c.is_alphanumeric() || c == '_' {
+                buf.push(c);
+                self.next();
+            } else {
+                break;
             }
         }
+        span.len = self.column - span.column;
 
-        if let Ok(i) = buf.parse() {
-            self.tokens.push(Token::Immediate(self.line, i));
+        if let Some(i) = parse_immediate(&buf) {
+            self.tokens.push(Token::Immediate(span, i));
         } else {
-            panic!("Invalid format for immediate value on line {}", self.line);
+            self.error(span, format!("Invalid immediate value `{}`", buf));
+        }
+    }
+
+    /// Handles a character literal such as `#'A'` or `#'\n'` after the opening quote.
+    fn handle_char(&mut self, mut span: Span) {
+        let c = match self.next() {
+            Some('\\') => self.escape(),
+            Some('\n') | Some('\'') | None => None,
+            c => c,
+        };
+        if self.peek() == Some('\'') {
+            self.next();
+            if let Some(c) = c {
+                span.len = self.column - span.column;
+                return self.tokens.push(Token::Immediate(span, c as i64));
+            }
+        }
+        span.len = self.column - span.column;
+        self.error(span, "Invalid character literal");
+    }
+
+    /// Handles a string literal after the opening `"`.
+    fn handle_string(&mut self, mut span: Span) {
+        let mut buf = String::new();
+        loop {
+            match self.next() {
+                Some('"') => break,
+                Some('\\') => match self.escape() {
+                    Some(c) => buf.push(c),
+                    None => {
+                        span.len = self.column - span.column;
+                        return self.error(span, "Invalid escape sequence in string");
+                    }
+                },
+                Some('\n') | None => {
+                    span.len = self.column - span.column;
+                    return self.error(span, "Unterminated string");
+                }
+                Some(c) => buf.push(c),
+            }
+        }
+        span.len = self.column - span.column;
+        self.tokens.push(Token::Str(span, buf));
+    }
+
+    /// Reads the character following a `\\`.
+    fn escape(&mut self) -> Option<char> {
+        match self.next() {
+            Some('n') => Some('\n'),
+            Some('t') => Some('\t'),
+            Some('r') => Some('\r'),
+            Some('0') => Some('\0'),
+            Some('\\') => Some('\\'),
+            Some('\'') => Some('\''),
+            Some('"') => Some('"'),
+            _ => None,
+        }
+    }
+}
+
+/// Parses an immediate value written in decimal, hexadecimal (`0x`), binary (`0b`) or octal (`0o`)
+/// with an optional leading `-`.
+fn parse_immediate(s: &str) -> Option<i64> {
+    let (negative, s) = if let Some(s) = s.strip_prefix('-') {
+        (true, s)
+    } else {
+        (false, s)
+    };
+    let s = s.replace('_', "");
+    let lower = s.to_lowercase();
+
+    let v = if let Some(d) = lower.strip_prefix("0x") {
+        u64::from_str_radix(d, 16).ok()?
+    } else if let Some(d) = lower.strip_prefix("0b") {
+        u64::from_str_radix(d, 2).ok()?
+    } else if let Some(d) = lower.strip_prefix("0o") {
+        u64::from_str_radix(d, 8).ok()?
+    } else {
+        lower.parse::<u64>().ok()?
+    };
+
+    if negative {
+        if v > i64::MAX as u64 + 1 {
+            return None;
         }
+        Some((v as i64).wrapping_neg())
+    } else if v > i64::MAX as u64 {
+        None
+    } else {
+        Some(v as i64)
     }
 }
@@ -1,6 +1,64 @@
 use bytecode::Opcode;
+use coverage::Coverage;
+use dump::{DumpFormat, Snapshot};
+use profiler::Profiler;
 use register::Register;
 
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Instant;
+
+/// The size of main memory in bytes.
+pub const HEAP_SIZE: usize = 4096;
+
+/// The reason execution stopped before the end of the program.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Fault {
+    /// The word at the byte address isn't an instruction.
+    InvalidInstruction(u32, usize),
+    /// The instruction can be decoded but the emulator doesn't implement it.
+    Unsupported(Opcode),
+    /// A load or store to a byte address that isn't a multiple of 8.
+    Misaligned(usize),
+    /// A load or store outside of the stack or main memory.
+    OutOfBounds(usize),
+}
+
+/// Why `VM::run` returned.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stop {
+    /// The program ran to its end or faulted.
+    Finished,
+    Breakpoint,
+    /// The program executed the number of instructions set by `set_limits`.
+    StepLimit,
+    /// The program ran past the deadline set by `set_limits`.
+    Timeout,
+}
+
+impl Fault {
+    /// Returns a name for the kind of fault, for reports.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Fault::InvalidInstruction(..) => "invalid_instruction",
+            Fault::Unsupported(_) => "unsupported",
+            Fault::Misaligned(_) => "misaligned",
+            Fault::OutOfBounds(_) => "out_of_bounds",
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Fault::InvalidInstruction(word, addr) => write!(f, "Invalid instruction {:#010x} at {:#x}", word, addr),
+            Fault::Unsupported(op) => write!(f, "{} is not supported by the emulator", op),
+            Fault::Misaligned(addr) => write!(f, "Addresses must be divisible by 8: {:#x}", addr),
+            Fault::OutOfBounds(addr) => write!(f, "Address {:#x} out of bounds", addr),
+        }
+    }
+}
+
 pub struct VM {
     registers: [u64; 32],
     flags: u64,
@@ -8,12 +66,24 @@ pub struct VM {
     code: Vec<Opcode>,
     stack: Vec<u64>,
     heap: Vec<u64>,
+    data_labels: HashMap<String, u64>,
     line_map: Vec<usize>,
     breakpoints: Vec<usize>,
     hit_br: bool,
     steps: usize,
+    /// Where `run` stops if the program hasn't finished, see `set_limits`.
+    max_steps: Option<usize>,
+    deadline: Option<Instant>,
     loads: usize,
     stores: usize,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    trace: bool,
+    /// How `DUMP` shows the machine, and what it showed last for `DumpFormat::Diff`.
+    dump_format: DumpFormat,
+    last_dump: Option<Snapshot>,
+    fault: Option<Fault>,
+    output: String,
 }
 
 impl VM {
@@ -24,13 +94,23 @@ impl VM {
             pc: 0,
             code: Vec::new(),
             stack: vec![0; 64],
-            heap: vec![0; 4096/8],
+            heap: vec![0; HEAP_SIZE/8],
+            data_labels: HashMap::new(),
             line_map: Vec::new(),
             breakpoints: Vec::new(),
             hit_br: false,
             steps: 0,
+            max_steps: None,
+            deadline: None,
             loads: 0,
             stores: 0,
+            profiler: None,
+            coverage: None,
+            trace: true,
+            dump_format: DumpFormat::Full,
+            last_dump: None,
+            fault: None,
+            output: String::new(),
         };
 
         // Initialise SP and FP to end of stack
@@ -39,15 +119,99 @@ impl VM {
         vm
     }
 
-    pub fn load_code(&mut self, code: Vec<Opcode>) {
+    /// Loads `code`, with execution starting at the instruction index `entry`.
+    pub fn load_code(&mut self, code: Vec<Opcode>, entry: usize) {
         self.code = code;
+        self.pc = entry;
     }
 
     pub fn load_line_map(&mut self, line_map: Vec<usize>) {
         self.line_map = line_map;
     }
 
-    fn get_register(&self, r: Register) -> u64 {
+    /// Copies `data` into main memory starting at address 0. The labels are shown next to their
+    /// addresses when memory is dumped.
+    pub fn load_data(&mut self, data: &[u8], labels: HashMap<String, u64>) -> Result<(), String> {
+        if data.len() > HEAP_SIZE {
+            return Err(format!("The data section is {} bytes but memory is only {} bytes", data.len(), HEAP_SIZE));
+        }
+        for (i, chunk) in data.chunks(8).enumerate() {
+            let mut bytes = [0; 8];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            self.heap[i] = u64::from_le_bytes(bytes);
+        }
+        self.data_labels = labels;
+        Ok(())
+    }
+
+    pub fn load_profiler(&mut self, profiler: Profiler) {
+        self.profiler = Some(profiler);
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    pub fn load_coverage(&mut self, coverage: Coverage) {
+        self.coverage = Some(coverage);
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /// Turns printing of each instruction as it executes on or off. Output from `PRNT` and `PRNL`
+    /// is still collected when tracing is off.
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    pub fn is_tracing(&self) -> bool {
+        self.trace
+    }
+
+    /// Makes `run` stop once `max_steps` instructions have executed in total or `deadline` has
+    /// passed, whichever comes first.
+    pub fn set_limits(&mut self, max_steps: Option<usize>, deadline: Option<Instant>) {
+        self.max_steps = max_steps;
+        self.deadline = deadline;
+    }
+
+    /// Sets how the `DUMP` instruction shows the machine. The first `DUMP` in the diff format
+    /// shows what changed since this call.
+    pub fn set_dump_format(&mut self, format: DumpFormat) {
+        self.dump_format = format;
+        self.last_dump = Some(self.snapshot());
+    }
+
+    fn log(&self, message: fmt::Arguments) {
+        if self.trace {
+            println!("{}", message);
+        }
+    }
+
+    /// Returns the fault that stopped the program, if any.
+    pub fn fault(&self) -> Option<&Fault> {
+        self.fault.as_ref()
+    }
+
+    /// Returns everything the program has printed with `PRNT` and `PRNL`.
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
+    /// Returns the number of instructions executed.
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    fn stop(&mut self, fault: Fault) {
+        self.log(format_args!("{}", fault));
+        self.fault = Some(fault);
+        self.pc = self.code.len();
+    }
+
+    pub fn get_register(&self, r: Register) -> u64 {
         if *r == 31 {
             0
         } else {
@@ -59,6 +223,36 @@ impl VM {
         self.registers[*r as usize] = v;
     }
 
+    pub fn set_register(&mut self, r: Register, v: u64) {
+        self.assign_register(r, v);
+    }
+
+    pub fn set_flags(&mut self, v: u64) {
+        self.flags = v;
+    }
+
+    /// Reads the doubleword at byte address `addr` in main memory.
+    pub fn read_memory(&self, addr: u64) -> Option<u64> {
+        if !addr.is_multiple_of(8) {
+            return None;
+        }
+        self.heap.get((addr / 8) as usize).cloned()
+    }
+
+    /// Writes the doubleword at byte address `addr` in main memory.
+    pub fn write_memory(&mut self, addr: u64, v: u64) -> Result<(), Fault> {
+        if !addr.is_multiple_of(8) {
+            return Err(Fault::Misaligned(addr as usize));
+        }
+        match self.heap.get_mut((addr / 8) as usize) {
+            Some(word) => {
+                *word = v;
+                Ok(())
+            }
+            None => Err(Fault::OutOfBounds(addr as usize)),
+        }
+    }
+
     pub fn print_register(&self, r: Register) {
         print!("{} 0x", Register::to_str(*r as usize));
         let x = self.get_register(r).to_le_bytes();
@@ -68,6 +262,31 @@ impl VM {
         println!(" ({})", self.get_register(r));
     }
 
+    pub fn snapshot(&self) -> Snapshot {
+        let mut registers = self.registers;
+        registers[31] = 0;
+        Snapshot {
+            registers,
+            flags: self.flags,
+            pc: self.pc,
+            stack: self.stack.clone(),
+            heap: self.heap.clone(),
+            steps: self.steps,
+            loads: self.loads,
+            stores: self.stores,
+        }
+    }
+
+    /// Shows the state of the machine in `format`. Diffs are against `previous`.
+    pub fn dump_as(&self, format: DumpFormat, previous: &Snapshot) {
+        match format {
+            DumpFormat::Full => self.dump(),
+            DumpFormat::Json => print!("{}", self.snapshot().json(&self.data_labels)),
+            DumpFormat::Compact => print!("{}", self.snapshot().compact(&self.data_labels)),
+            DumpFormat::Diff => print!("{}", self.snapshot().diff(previous)),
+        }
+    }
+
     pub fn dump(&self) {
         println!("Registers:");
         for i in 0..32 {
@@ -106,7 +325,15 @@ impl VM {
             print!(" |");
             Self::print_little_endian_ascii(self.heap[2*i]);
             Self::print_little_endian_ascii(self.heap[2*i + 1]);
-            println!("|");
+            print!("|");
+            let mut labels: Vec<_> = self.data_labels.iter()
+                .filter(|(_, &a)| a / 16 == i as u64)
+                .collect();
+            labels.sort_by_key(|&(l, &a)| (a, l));
+            for (l, a) in labels {
+                print!(" {}@{:x}", l, a);
+            }
+            println!();
         }
         println!("{:08x}", self.heap.len()*8);
 
@@ -126,7 +353,7 @@ impl VM {
     fn print_little_endian_ascii(x: u64) {
         let x = x.to_le_bytes();
         for &i in &x {
-            if i >= 32 && i <= 126 {
+            if (32..=126).contains(&i) {
                 print!("{}", i as char);
             } else {
                 print!(".");
@@ -143,38 +370,110 @@ impl VM {
         self.breakpoints.push(self.line_map[line-1]);
     }
 
-    pub fn run(&mut self) {
+    /// Adds a breakpoint at the instruction index `pc`, for programs without line information.
+    pub fn add_address_breakpoint(&mut self, pc: usize) {
+        if pc >= self.code.len() {
+            println!("There are only {} instructions in this program", self.code.len());
+            return;
+        }
+
+        self.breakpoints.push(pc);
+    }
+
+    pub fn line_map(&self) -> &[usize] {
+        &self.line_map
+    }
+
+    /// Runs until the program finishes, reaches a breakpoint or reaches a limit set by
+    /// `set_limits`.
+    pub fn run(&mut self) -> Stop {
         while self.pc < self.code.len() {
-            if !self.hit_br && self.breakpoints.contains(&self.pc) {
-                println!("Reached breakpoint");
-                self.hit_br = true;
-                return;
-            } else if self.hit_br {
-                self.hit_br = false;
+            if self.check_breakpoint() {
+                return Stop::Breakpoint;
+            }
+            if self.max_steps.is_some_and(|m| self.steps >= m) {
+                return Stop::StepLimit;
+            }
+            // Reading the clock is slow compared to a step, so it is only checked now and then.
+            if self.steps.is_multiple_of(1024) && self.deadline.is_some_and(|d| Instant::now() >= d) {
+                return Stop::Timeout;
             }
             self.step();
         }
-        println!("Reached end of program");
+        self.log(format_args!("Reached end of program"));
+        Stop::Finished
+    }
+
+    /// Returns true if execution should stop at the current instruction. A breakpoint only stops
+    /// execution once, so that calling `run` again continues past it.
+    pub fn check_breakpoint(&mut self) -> bool {
+        if !self.hit_br && self.breakpoints.contains(&self.pc) {
+            self.log(format_args!("Reached breakpoint"));
+            self.hit_br = true;
+            return true;
+        } else if self.hit_br {
+            self.hit_br = false;
+        }
+        false
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.pc >= self.code.len()
+    }
+
+    pub fn instruction(&self, pc: usize) -> Option<Opcode> {
+        self.code.get(pc).cloned()
+    }
+
+    pub fn current_instruction(&self) -> Option<Opcode> {
+        self.code.get(self.pc).cloned()
+    }
+
+    /// Reads the doubleword at `offset` bytes from the address in `rn` without counting it as a
+    /// load. Returns `None` if the address is misaligned or out of bounds.
+    pub fn peek_memory(&self, rn: Register, offset: i64) -> Option<u64> {
+        let addr = self.get_register(rn).wrapping_add(offset as u64) as usize;
+        if !addr.is_multiple_of(8) {
+            return None;
+        }
+        let addr = addr / 8;
+        if *rn == 28 {
+            self.stack.get(addr).cloned()
+        } else {
+            self.heap.get(addr).cloned()
+        }
     }
 
     pub fn step(&mut self) {
         use bytecode::Instruction::*;
         if self.pc >= self.code.len() {
-            println!("Reached end of program");
+            self.log(format_args!("Reached end of program"));
             return;
         }
 
         if !self.hit_br && self.breakpoints.contains(&self.pc) {
-            println!("Reached breakpoint");
+            self.log(format_args!("Reached breakpoint"));
             self.hit_br = true;
         } else if self.hit_br {
             self.hit_br = false;
         }
 
-        println!("    {}", self.code[self.pc]);
+        self.log(format_args!("    {}", self.code[self.pc]));
 
         let op = self.code[self.pc];
-        match op.instruction() {
+        let pc = self.pc;
+        let instruction = match op.decode() {
+            Some(i) => i,
+            None => return self.stop(Fault::InvalidInstruction(op.0, pc * 4)),
+        };
+        if let Some(ref mut p) = self.profiler {
+            p.record(pc);
+        }
+        match instruction {
             Addis => self.addis(op),
             Addi => self.addi(op),
             Adds => self.adds(op),
@@ -206,11 +505,31 @@ impl VM {
             Mul => self.mul(op),
             Lsl => self.lsl(op),
             Lsr => self.lsr(op),
-            Prnt | Prnl | Dump => self.pc += 1,
+            Movz => self.movz(op),
+            Movk => self.movk(op),
+            Prnt => self.prnt(op),
+            Prnl => {
+                self.print("\n");
+                self.pc += 1;
+            }
+            Dump => {
+                if self.trace {
+                    let now = self.snapshot();
+                    self.dump_as(self.dump_format, self.last_dump.as_ref().unwrap_or(&now));
+                    self.last_dump = Some(now);
+                }
+                self.pc += 1;
+            }
             Halt => self.pc = self.code.len(),
-            _ => unimplemented!(),
+            _ => return self.stop(Fault::Unsupported(op)),
         }
 
+        if let Some(ref mut p) = self.profiler {
+            p.update(op, self.pc);
+        }
+        if let Some(ref mut c) = self.coverage {
+            c.record(pc, self.pc);
+        }
         self.steps += 1;
     }
 
@@ -218,7 +537,7 @@ impl VM {
         let rd = op.add_rd();
         let rn = op.add_rn();
         let rm = op.add_rm();
-        let v = self.get_register(rn) + self.get_register(rm);
+        let v = self.get_register(rn).wrapping_add(self.get_register(rm));
         self.assign_register(rd, v);
         self.pc += 1;
     }
@@ -227,7 +546,7 @@ impl VM {
         let rd = op.add_rd();
         let rn = op.add_rn();
         let rm = op.add_rm();
-        let v = self.get_register(rn) + self.get_register(rm);
+        let v = self.get_register(rn).wrapping_add(self.get_register(rm));
         self.assign_register(rd, v);
         self.flags = v;
         self.pc += 1;
@@ -237,7 +556,7 @@ impl VM {
         let rd = op.addi_rd();
         let rn = op.addi_rn();
         let imm = op.addi_imm();
-        let v = self.get_register(rn) + imm as u64;
+        let v = self.get_register(rn).wrapping_add(imm as u64);
         self.assign_register(rd, v);
         self.pc += 1;
     }
@@ -246,7 +565,7 @@ impl VM {
         let rd = op.addi_rd();
         let rn = op.addi_rn();
         let imm = op.addi_imm();
-        let v = self.get_register(rn) + imm as u64;
+        let v = self.get_register(rn).wrapping_add(imm as u64);
         self.assign_register(rd, v);
         self.flags = v;
         self.pc += 1;
@@ -256,7 +575,7 @@ impl VM {
         let rd = op.sub_rd();
         let rn = op.sub_rn();
         let rm = op.sub_rm();
-        let v = self.get_register(rn) - self.get_register(rm);
+        let v = self.get_register(rn).wrapping_sub(self.get_register(rm));
         self.assign_register(rd, v);
         self.pc += 1;
     }
@@ -265,7 +584,7 @@ impl VM {
         let rd = op.subi_rd();
         let rn = op.subi_rn();
         let imm = op.subi_imm();
-        let v = self.get_register(rn) - imm as u64;
+        let v = self.get_register(rn).wrapping_sub(imm as u64);
         self.assign_register(rd, v);
         self.pc += 1;
     }
@@ -274,7 +593,7 @@ impl VM {
         let rd = op.subis_rd();
         let rn = op.subis_rn();
         let imm = op.subis_imm();
-        let v = self.get_register(rn) - imm as u64;
+        let v = self.get_register(rn).wrapping_sub(imm as u64);
         self.assign_register(rd, v);
         self.flags = v;
         self.pc += 1;
@@ -284,7 +603,7 @@ impl VM {
         let rd = op.subs_rd();
         let rn = op.subs_rn();
         let rm = op.subs_rm();
-        let v = self.get_register(rn) - self.get_register(rm);
+        let v = self.get_register(rn).wrapping_sub(self.get_register(rm));
         self.assign_register(rd, v);
         self.flags = v;
         self.pc += 1;
@@ -368,7 +687,7 @@ impl VM {
         let rd = op.mul_rd();
         let rn = op.mul_rn();
         let rm = op.mul_rm();
-        let v = self.get_register(rn) * self.get_register(rm);
+        let v = self.get_register(rn).wrapping_mul(self.get_register(rm));
         self.assign_register(rd, v);
         self.pc += 1;
     }
@@ -376,8 +695,8 @@ impl VM {
     fn cbz(&mut self, op: Opcode) {
         let rt = op.cbz_rt();
         if self.get_register(rt) == 0 {
-            println!("    Branch taken");
-            self.pc = (self.pc as u32 + op.cbz_addr()) as usize;
+            self.log(format_args!("    Branch taken"));
+            self.pc = (self.pc as u32).wrapping_add(op.cbz_addr()) as usize;
         } else {
             self.pc += 1;
         }
@@ -386,8 +705,8 @@ impl VM {
     fn cbnz(&mut self, op: Opcode) {
         let rt = op.cbnz_rt();
         if self.get_register(rt) != 0 {
-            println!("    Branch taken");
-            self.pc = (self.pc as u32 + op.cbnz_addr()) as usize;
+            self.log(format_args!("    Branch taken"));
+            self.pc = (self.pc as u32).wrapping_add(op.cbnz_addr()) as usize;
         } else {
             self.pc += 1;
         }
@@ -395,13 +714,13 @@ impl VM {
 
     fn b(&mut self, op: Opcode) {
         let addr = op.b_addr();
-        self.pc = (self.pc as u32 + addr) as usize;
+        self.pc = (self.pc as u32).wrapping_add(addr) as usize;
     }
 
     fn beq(&mut self, op: Opcode) {
         if self.flags == 0 {
-            println!("    Branch taken");
-            self.pc = (self.pc as u32 + op.beq_addr()) as usize;
+            self.log(format_args!("    Branch taken"));
+            self.pc = (self.pc as u32).wrapping_add(op.beq_addr()) as usize;
         } else {
             self.pc += 1;
         }
@@ -409,8 +728,8 @@ impl VM {
 
     fn bgt(&mut self, op: Opcode) {
         if (self.flags as i64) > 0 {
-            println!("    Branch taken");
-            self.pc = (self.pc as u32 + op.bgt_addr()) as usize;
+            self.log(format_args!("    Branch taken"));
+            self.pc = (self.pc as u32).wrapping_add(op.bgt_addr()) as usize;
         } else {
             self.pc += 1;
         }
@@ -418,8 +737,8 @@ impl VM {
 
     fn bge(&mut self, op: Opcode) {
         if (self.flags as i64) >= 0 {
-            println!("    Branch taken");
-            self.pc = (self.pc as u32 + op.bge_addr()) as usize;
+            self.log(format_args!("    Branch taken"));
+            self.pc = (self.pc as u32).wrapping_add(op.bge_addr()) as usize;
         } else {
             self.pc += 1;
         }
@@ -427,8 +746,8 @@ impl VM {
 
     fn blt(&mut self, op: Opcode) {
         if (self.flags as i64) < 0 {
-            println!("    Branch taken");
-            self.pc = (self.pc as u32 + op.blt_addr()) as usize;
+            self.log(format_args!("    Branch taken"));
+            self.pc = (self.pc as u32).wrapping_add(op.blt_addr()) as usize;
         } else {
             self.pc += 1;
         }
@@ -436,8 +755,8 @@ impl VM {
 
     fn ble(&mut self, op: Opcode) {
         if (self.flags as i64) <= 0 {
-            println!("    Branch taken");
-            self.pc = (self.pc as u32 + op.ble_addr()) as usize;
+            self.log(format_args!("    Branch taken"));
+            self.pc = (self.pc as u32).wrapping_add(op.ble_addr()) as usize;
         } else {
             self.pc += 1;
         }
@@ -450,43 +769,31 @@ impl VM {
 
     fn bl(&mut self, op: Opcode) {
         self.assign_register(Register(30), (self.pc+1) as u64);
-        self.pc = (self.pc as u32 + op.bl_addr()) as usize;
+        self.pc = (self.pc as u32).wrapping_add(op.bl_addr()) as usize;
     }
 
     fn stur(&mut self, op: Opcode) {
         let rn = op.stur_rn();
         let rt = op.stur_rt();
 
-        let addr = (op.stur_addr() as u64 + self.get_register(rn)) as usize;
-        if addr % 8 != 0 {
-            println!("Addresses must be divisible by 8: {:#x}", addr);
-            self.pc = self.code.len();
-            return;
+        let addr = self.get_register(rn).wrapping_add(op.stur_addr() as u64) as usize;
+        if !addr.is_multiple_of(8) {
+            return self.stop(Fault::Misaligned(addr));
         }
         let addr = addr / 8;
 
-        if (addr as isize) < 0 {
-            println!("Address {:#x} out of bounds", addr);
-            self.pc = self.code.len();
-            return;
-        }
-
         let v = self.get_register(rt);
 
         // handle SP specially
         if *rn == 28 {
             if addr >= self.stack.len() {
-                println!("Address {:#x} out of bounds", addr);
-                self.pc = self.code.len();
-                return;
+                return self.stop(Fault::OutOfBounds(addr * 8));
             }
 
             self.stack[addr] = v;
         } else {
             if addr >= self.heap.len() {
-                println!("Address {:#x} out of bounds", addr);
-                self.pc = self.code.len();
-                return;
+                return self.stop(Fault::OutOfBounds(addr * 8));
             }
 
             self.heap[addr] = v;
@@ -499,34 +806,22 @@ impl VM {
         let rn = op.stur_rn();
         let rt = op.stur_rt();
 
-        let addr = (op.stur_addr() as u64 + self.get_register(rn)) as usize;
-        if addr % 8 != 0 {
-            println!("Addresses must be divisible by 8: {:#x}", addr);
-            self.pc = self.code.len();
-            return;
+        let addr = self.get_register(rn).wrapping_add(op.stur_addr() as u64) as usize;
+        if !addr.is_multiple_of(8) {
+            return self.stop(Fault::Misaligned(addr));
         }
         let addr = addr / 8;
 
-        if (addr as isize) < 0 {
-            println!("Address {:#x} out of bounds", addr);
-            self.pc = self.code.len();
-            return;
-        }
-
         // Handle SP specially
         let v = if *rn == 28 {
             if addr >= self.stack.len() {
-                println!("Address {:#x} out of bounds", addr);
-                self.pc = self.code.len();
-                return;
+                return self.stop(Fault::OutOfBounds(addr * 8));
             }
 
             self.stack[addr]
         } else {
             if addr >= self.heap.len() {
-                println!("Address {:#x} out of bounds", addr);
-                self.pc = self.code.len();
-                return;
+                return self.stop(Fault::OutOfBounds(addr * 8));
             }
 
             self.heap[addr]
@@ -536,6 +831,21 @@ impl VM {
         self.pc += 1;
     }
 
+    /// Records `text` as output of the program, printing it straight away when tracing.
+    fn print(&mut self, text: &str) {
+        if self.trace {
+            print!("{}", text);
+        }
+        self.output.push_str(text);
+    }
+
+    fn prnt(&mut self, op: Opcode) {
+        let rd = op.prnt_rd();
+        let v = self.get_register(rd);
+        self.print(&format!("{}: {:#018x} ({})\n", rd, v, v as i64));
+        self.pc += 1;
+    }
+
     fn lsl(&mut self, op: Opcode) {
         let rd = op.lsl_rd();
         let rn = op.lsl_rn();
@@ -554,4 +864,19 @@ impl VM {
         self.assign_register(rd, v);
         self.pc += 1;
     }
+
+    fn movz(&mut self, op: Opcode) {
+        let rd = op.movz_rd();
+        let v = (op.movz_imm() as u64) << op.movz_shift();
+        self.assign_register(rd, v);
+        self.pc += 1;
+    }
+
+    fn movk(&mut self, op: Opcode) {
+        let rd = op.movk_rd();
+        let shift = op.movk_shift();
+        let v = self.get_register(rd) & !(0xffff << shift);
+        self.assign_register(rd, v | ((op.movk_imm() as u64) << shift));
+        self.pc += 1;
+    }
 }